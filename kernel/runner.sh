@@ -0,0 +1,11 @@
+#!/bin/sh
+# The runner for `cargo xrun` and `cargo xtest` (see `.cargo/config`): fill in the kernel's
+# embedded symbol table with `ksyms`, so that backtraces are symbolized, then boot it with
+# `bootimage runner`. Cargo passes the path to the kernel ELF first.
+
+set -e
+
+# cargo-xbuild's RUSTFLAGS point at the kernel's sysroot, which ksyms (a host program) can't use.
+env -u RUSTFLAGS cargo run --quiet --manifest-path "$(dirname "$0")/../ksyms/Cargo.toml" -- "$1"
+
+exec bootimage runner "$@"
@@ -0,0 +1,516 @@
+//! Parsing of the ACPI tables.
+//!
+//! We find the RSDP in the BIOS areas, follow it to the RSDT (or XSDT), and parse the tables we
+//! care about:
+//! - MADT: the cores, I/O APICs, and ISA interrupt overrides.
+//! - FADT: the power management registers (for shutting down) and the reset register.
+//! - HPET: the address of the high precision event timer.
+//! - MCFG: where PCI configuration space is memory mapped (ECAM).
+//!
+//! Every table's checksum is validated; tables with bad checksums are ignored. Tables are read
+//! through permanent read-only mappings of physical memory.
+//!
+//! We don't have an AML interpreter. The only thing we need from the DSDT is the `\_S5` sleep
+//! type for shutting down, which we find by searching for the bytes of its definition.
+
+use alloc::vec::Vec;
+
+use core::{mem, slice, str};
+
+use spin::Once;
+
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::memory::map_physical;
+
+/// The parsed ACPI tables.
+static ACPI: Once<Acpi> = Once::new();
+
+/// The physical address of the pointer to the EBDA.
+const EBDA_PTR_PADDR: u64 = 0x40E;
+
+/// The BIOS read-only area, which may contain the RSDP.
+const BIOS_AREA: (u64, u64) = (0xE_0000, 0x10_0000);
+
+/// The common header of all system description tables.
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Everything we know from the ACPI tables.
+#[derive(Debug)]
+pub struct Acpi {
+    /// The ACPI revision from the RSDP (0 for ACPI 1.0, 2 for ACPI 2.0+).
+    pub revision: u8,
+
+    /// The OEM of the RSDP.
+    pub oem_id: [u8; 6],
+
+    /// The physical address and header of every table with a valid checksum.
+    pub tables: Vec<(u64, SdtHeader)>,
+
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+
+    /// The memory mapped PCI configuration space regions (empty if there is no MCFG).
+    pub mcfg: Vec<McfgRegion>,
+}
+
+/// Info from the Multiple APIC Description Table.
+#[derive(Debug, Default)]
+pub struct Madt {
+    /// The physical address of the local APICs.
+    pub lapic_addr: u64,
+
+    /// Is there a legacy 8259 PIC pair?
+    pub has_8259: bool,
+
+    pub cpus: Vec<MadtCpu>,
+    pub ioapics: Vec<MadtIoApic>,
+    pub overrides: Vec<MadtOverride>,
+}
+
+/// A core (processor local APIC entry).
+#[derive(Debug)]
+pub struct MadtCpu {
+    pub processor_id: u8,
+    pub apic_id: u8,
+
+    /// The core can be used. Otherwise, it is disabled and should not be started.
+    pub enabled: bool,
+}
+
+/// An I/O APIC.
+#[derive(Debug)]
+pub struct MadtIoApic {
+    pub id: u8,
+
+    /// The physical address of the registers.
+    pub addr: u64,
+
+    /// The first global system interrupt (GSI) number of this I/O APIC's inputs.
+    pub gsi_base: u32,
+}
+
+/// An interrupt source override: ISA IRQ `irq` is connected to `gsi` rather than the GSI with the
+/// same number.
+#[derive(Debug)]
+pub struct MadtOverride {
+    pub bus: u8,
+    pub irq: u8,
+    pub gsi: u32,
+
+    /// MPS INTI flags (polarity and trigger mode).
+    pub flags: u16,
+}
+
+/// Info from the Fixed ACPI Description Table.
+#[derive(Debug, Default)]
+pub struct Fadt {
+    /// The physical address of the DSDT.
+    pub dsdt: u64,
+
+    /// The SCI interrupt.
+    pub sci_int: u16,
+
+    /// The I/O ports of the PM1 control blocks (0 if not present).
+    pub pm1a_cnt_blk: u16,
+    pub pm1b_cnt_blk: u16,
+
+    /// The `SLP_TYPa` and `SLP_TYPb` values for the S5 (soft off) state, from the DSDT.
+    pub s5_slp_typ: Option<(u16, u16)>,
+
+    /// The reset register (address space ID, address) and the value to write to it, if supported.
+    pub reset: Option<(u8, u64, u8)>,
+
+    /// The FADT flags.
+    pub flags: u32,
+}
+
+/// Info from the HPET Description Table.
+#[derive(Debug)]
+pub struct Hpet {
+    /// The physical address of the HPET registers.
+    pub base: u64,
+
+    /// The HPET sequence number.
+    pub number: u8,
+
+    /// The minimum tick in periodic mode.
+    pub min_tick: u16,
+}
+
+/// A memory mapped PCI configuration space region (ECAM), from the MCFG.
+#[derive(Copy, Clone, Debug)]
+pub struct McfgRegion {
+    /// The physical address of the configuration space of bus 0 (even if `start_bus` isn't 0).
+    pub base: u64,
+
+    /// The PCI segment group.
+    pub segment: u16,
+
+    /// The buses in the region.
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl core::fmt::Debug for SdtHeader {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        // Copy the fields out of the packed struct before taking references.
+        let (length, revision) = (self.length, self.revision);
+        write!(
+            f,
+            "{} rev {} len {} oem {} {}",
+            ascii(&self.signature),
+            revision,
+            length,
+            ascii(&self.oem_id),
+            ascii(&self.oem_table_id),
+        )
+    }
+}
+
+/// Show some bytes as a string if possible.
+fn ascii(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("?")
+}
+
+/// Do the bytes add up to 0 (mod 256)?
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Map `len` bytes of physical memory at `paddr`, read-only.
+unsafe fn map(paddr: u64, len: usize) -> &'static [u8] {
+    let vaddr = map_physical(
+        paddr,
+        len as u64,
+        PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+    );
+    slice::from_raw_parts(vaddr as *const u8, len)
+}
+
+/// Read a little-endian value of type `T` at `offset` in `bytes`.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> T {
+    assert!(offset + mem::size_of::<T>() <= bytes.len());
+    unsafe { (bytes.as_ptr().add(offset) as *const T).read_unaligned() }
+}
+
+/// Map the system description table at `paddr`. Returns its header and its bytes (including the
+/// header), or `None` if it is too short to hold its header or the checksum is wrong.
+unsafe fn map_table(paddr: u64) -> Option<(SdtHeader, &'static [u8])> {
+    let header: SdtHeader = read(map(paddr, mem::size_of::<SdtHeader>()), 0);
+    if (header.length as usize) < mem::size_of::<SdtHeader>() {
+        warn!("ACPI table {:?} @ {:#x} is too short", header, paddr);
+        return None;
+    }
+
+    let bytes = map(paddr, header.length as usize);
+
+    if checksum_ok(bytes) {
+        Some((header, bytes))
+    } else {
+        warn!("bad checksum on ACPI table {:?} @ {:#x}", header, paddr);
+        None
+    }
+}
+
+/// Search `[start, end)` for the RSDP, which is on a 16-byte boundary.
+unsafe fn find_rsdp_in(start: u64, end: u64) -> Option<u64> {
+    let area = map(start, (end - start) as usize);
+
+    (0..area.len() - 20)
+        .step_by(16)
+        .find(|&off| &area[off..off + 8] == b"RSD PTR " && checksum_ok(&area[off..off + 20]))
+        .map(|off| start + off as u64)
+}
+
+/// Find the RSDP in the first KiB of the EBDA or the BIOS area.
+unsafe fn find_rsdp() -> Option<u64> {
+    let ebda = (read::<u16>(map(EBDA_PTR_PADDR, 2), 0) as u64) << 4;
+
+    let in_ebda = if ebda != 0 {
+        find_rsdp_in(ebda, ebda + 1024)
+    } else {
+        None
+    };
+
+    in_ebda.or_else(|| find_rsdp_in(BIOS_AREA.0, BIOS_AREA.1))
+}
+
+/// Parse the MADT.
+fn parse_madt(bytes: &[u8]) -> Madt {
+    let mut madt = Madt {
+        lapic_addr: read::<u32>(bytes, 36) as u64,
+        has_8259: read::<u32>(bytes, 40) & 1 != 0,
+        ..Madt::default()
+    };
+
+    let mut off = 44;
+    while off + 2 <= bytes.len() {
+        let (ty, len) = (bytes[off], bytes[off + 1] as usize);
+        if len < 2 || off + len > bytes.len() {
+            break;
+        }
+        let entry = &bytes[off..off + len];
+
+        match ty {
+            // Processor local APIC
+            0 => madt.cpus.push(MadtCpu {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: read::<u32>(entry, 4) & 1 != 0,
+            }),
+
+            // I/O APIC
+            1 => madt.ioapics.push(MadtIoApic {
+                id: entry[2],
+                addr: read::<u32>(entry, 4) as u64,
+                gsi_base: read(entry, 8),
+            }),
+
+            // Interrupt source override
+            2 => madt.overrides.push(MadtOverride {
+                bus: entry[2],
+                irq: entry[3],
+                gsi: read(entry, 4),
+                flags: read(entry, 8),
+            }),
+
+            // Local APIC address override
+            5 => madt.lapic_addr = read(entry, 4),
+
+            // Other stuff we don't care about (NMIs, x2APIC, ...)
+            _ => {}
+        }
+
+        off += len;
+    }
+
+    madt
+}
+
+/// Find the `SLP_TYPa` and `SLP_TYPb` values of `\_S5` in the DSDT.
+///
+/// The definition looks like `NameOp(0x08) "_S5_" PackageOp(0x12) PkgLength NumElements
+/// SLP_TYPa SLP_TYPb ...`, where each value is either a `BytePrefix(0x0A)` followed by a byte, or
+/// a bare `ZeroOp`/`OneOp`.
+fn find_s5(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let pos = dsdt.windows(4).position(|w| w == b"_S5_")?;
+    let mut off = pos + 4;
+
+    if *dsdt.get(off)? != 0x12 {
+        return None;
+    }
+    off += 1;
+
+    // The top 2 bits of the first byte say how many more bytes the length has.
+    off += ((*dsdt.get(off)? >> 6) & 0b11) as usize + 1;
+
+    // NumElements
+    off += 1;
+
+    let mut value = || {
+        let mut v = *dsdt.get(off)?;
+        if v == 0x0A {
+            off += 1;
+            v = *dsdt.get(off)?;
+        }
+        off += 1;
+        Some(v as u16)
+    };
+
+    Some((value()?, value()?))
+}
+
+/// Parse the FADT, and the `\_S5` object from the DSDT it points to.
+unsafe fn parse_fadt(header: &SdtHeader, bytes: &[u8]) -> Fadt {
+    let mut fadt = Fadt {
+        dsdt: read::<u32>(bytes, 40) as u64,
+        sci_int: read(bytes, 46),
+        pm1a_cnt_blk: read::<u32>(bytes, 64) as u16,
+        pm1b_cnt_blk: read::<u32>(bytes, 68) as u16,
+        flags: read(bytes, 112),
+        ..Fadt::default()
+    };
+
+    // ACPI 2.0+ fields
+    if header.revision >= 2 && bytes.len() >= 148 {
+        /// The reset register is supported.
+        const RESET_REG_SUP: u32 = 1 << 10;
+
+        if fadt.flags & RESET_REG_SUP != 0 {
+            fadt.reset = Some((bytes[116], read(bytes, 120), bytes[128]));
+        }
+
+        let x_dsdt: u64 = read(bytes, 140);
+        if x_dsdt != 0 {
+            fadt.dsdt = x_dsdt;
+        }
+    }
+
+    if fadt.dsdt != 0 {
+        if let Some((_, dsdt)) = map_table(fadt.dsdt) {
+            fadt.s5_slp_typ = find_s5(dsdt);
+        }
+    }
+
+    fadt
+}
+
+/// Parse the HPET table.
+fn parse_hpet(bytes: &[u8]) -> Hpet {
+    Hpet {
+        base: read(bytes, 44),
+        number: bytes[52],
+        min_tick: read(bytes, 53),
+    }
+}
+
+/// Parse the MCFG.
+fn parse_mcfg(bytes: &[u8]) -> Vec<McfgRegion> {
+    // The header is followed by 8 reserved bytes, then 16-byte entries.
+    (44..bytes.len())
+        .step_by(16)
+        .take_while(|off| off + 16 <= bytes.len())
+        .map(|off| McfgRegion {
+            base: read(bytes, off),
+            segment: read(bytes, off + 8),
+            start_bus: bytes[off + 10],
+            end_bus: bytes[off + 11],
+        })
+        .collect()
+}
+
+/// Find and parse the ACPI tables. This needs the heap and paging to be set up.
+pub fn init() {
+    let rsdp = if let Some(rsdp) = unsafe { find_rsdp() } {
+        rsdp
+    } else {
+        warn!("no ACPI RSDP found");
+        return;
+    };
+
+    let rsdp_bytes = unsafe { map(rsdp, 36) };
+    let revision = rsdp_bytes[15];
+
+    // ACPI 2.0+ has a 64-bit XSDT, with its own checksum over the whole RSDP.
+    let (root, entry_size) = if revision >= 2 && checksum_ok(&rsdp_bytes[..36]) {
+        (read::<u64>(rsdp_bytes, 24), 8)
+    } else {
+        (read::<u32>(rsdp_bytes, 16) as u64, 4)
+    };
+
+    let mut acpi = Acpi {
+        revision,
+        oem_id: read(rsdp_bytes, 9),
+        tables: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: Vec::new(),
+    };
+
+    let (root_header, root_bytes) = if let Some(root) = unsafe { map_table(root) } {
+        root
+    } else {
+        warn!("bad ACPI root table");
+        return;
+    };
+    acpi.tables.push((root, root_header));
+
+    let header_size = mem::size_of::<SdtHeader>();
+    for i in 0..root_bytes.len().saturating_sub(header_size) / entry_size {
+        let off = header_size + i * entry_size;
+        let paddr = if entry_size == 8 {
+            read::<u64>(root_bytes, off)
+        } else {
+            read::<u32>(root_bytes, off) as u64
+        };
+
+        let (header, bytes) = if let Some(table) = unsafe { map_table(paddr) } {
+            table
+        } else {
+            continue;
+        };
+
+        match &header.signature {
+            b"APIC" => acpi.madt = Some(parse_madt(bytes)),
+            b"FACP" => acpi.fadt = Some(unsafe { parse_fadt(&header, bytes) }),
+            b"HPET" => acpi.hpet = Some(parse_hpet(bytes)),
+            b"MCFG" => acpi.mcfg = parse_mcfg(bytes),
+            _ => {}
+        }
+
+        acpi.tables.push((paddr, header));
+    }
+
+    info!(
+        "acpi inited - rev {}, {} tables",
+        acpi.revision,
+        acpi.tables.len()
+    );
+
+    ACPI.call_once(|| acpi);
+}
+
+/// The parsed ACPI tables, if there are any.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.r#try()
+}
+
+/// Print all of the ACPI tables and what we parsed from them.
+pub fn dump() {
+    let acpi = if let Some(acpi) = get() {
+        acpi
+    } else {
+        printk!("No ACPI tables\n");
+        return;
+    };
+
+    printk!("ACPI rev {} oem {}\n", acpi.revision, ascii(&acpi.oem_id));
+
+    for (paddr, header) in acpi.tables.iter() {
+        printk!("\t{:#010x} {:?}\n", paddr, header);
+    }
+
+    if let Some(madt) = &acpi.madt {
+        printk!(
+            "MADT: lapic @ {:#x}, 8259: {}\n",
+            madt.lapic_addr,
+            madt.has_8259
+        );
+        for cpu in madt.cpus.iter() {
+            printk!("\t{:?}\n", cpu);
+        }
+        for ioapic in madt.ioapics.iter() {
+            printk!("\t{:?}\n", ioapic);
+        }
+        for over in madt.overrides.iter() {
+            printk!("\t{:?}\n", over);
+        }
+    }
+
+    if let Some(fadt) = &acpi.fadt {
+        printk!("FADT: {:?}\n", fadt);
+    }
+
+    if let Some(hpet) = &acpi.hpet {
+        printk!("HPET: {:?}\n", hpet);
+    }
+
+    for region in acpi.mcfg.iter() {
+        printk!("MCFG: {:?}\n", region);
+    }
+}
@@ -0,0 +1,252 @@
+//! Stack backtraces, symbolized with a symbol table embedded in the kernel image.
+//!
+//! The kernel is built with frame pointers (see `x86_64-unknown-elf.json`), so every frame starts
+//! with the caller's `rbp` followed by the return address. We follow that chain, only touching
+//! memory that is mapped (see `memory::probe`), so a corrupt stack ends the backtrace rather than
+//! faulting in the middle of a panic.
+//!
+//! The symbol table lives in the `.ksyms` section. It has a fixed size, so that filling it in
+//! doesn't move anything else in the image. After the kernel is linked, the `ksyms` tool (in
+//! `ksyms/` at the top of the repo) reads the ELF symbol table, and writes a sorted table of
+//! function addresses and (demangled) names into the section. If that didn't happen, backtraces
+//! just show addresses, which can still be looked up with `addr2line`.
+//!
+//! The table format is shared with `ksyms`:
+//!
+//! ```txt
+//! magic: [u8; 8] = "KSYMS\0\0\0"
+//! count: u64
+//! data: [u8; KSYMS_SIZE]
+//!     count entries of { addr: u64, name_offset: u32, name_len: u32 }, sorted by addr
+//!     followed by the names (UTF-8), with offsets relative to the start of `data`
+//! ```
+
+use core::{mem::size_of, str};
+
+use spin::Mutex;
+
+use crate::{
+    memory::{probe, Probe},
+    smp::{cpu_id, MAX_CPUS},
+};
+
+/// The size of the symbol table data. If the kernel outgrows this, `ksyms` will say so.
+const KSYMS_SIZE: usize = 512 * 1024;
+
+/// The most frames to print, in case the frame pointers form a cycle.
+const MAX_FRAMES: usize = 64;
+
+/// The code interrupted by an exception that an exception handler on each core is turning into a
+/// panic, as `(rip, rbp)` (see `set_exception`). Indexed by `cpu_id`.
+static EXCEPTIONS: Mutex<[Option<(u64, u64)>; MAX_CPUS]> = Mutex::new([None; MAX_CPUS]);
+
+/// The symbol table, as laid out in the `.ksyms` section.
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 8],
+    count: u64,
+    data: [u8; KSYMS_SIZE],
+}
+
+/// An entry of the symbol table.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Symbol {
+    addr: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+/// The symbol table, filled in by `ksyms` after linking.
+///
+/// This is `mut` so that the compiler doesn't assume it still contains what we initialize it to.
+/// It is never actually written at run time. The magic is non-zero so that the section is part of
+/// the image rather than `.bss`.
+#[link_section = ".ksyms"]
+#[no_mangle]
+#[used]
+static mut KSYMS: SymbolTable = SymbolTable {
+    magic: *b"KSYMS\0\0\0",
+    count: 0,
+    data: [0; KSYMS_SIZE],
+};
+
+/// The entries of the symbol table, and the data they index into.
+fn symbols() -> (&'static [Symbol], &'static [u8]) {
+    let table = unsafe { &KSYMS };
+
+    // Don't trust a count that doesn't fit. That would be a bug in `ksyms`.
+    let count = table.count as usize;
+    if count > KSYMS_SIZE / size_of::<Symbol>() {
+        return (&[], &[]);
+    }
+
+    let entries =
+        unsafe { core::slice::from_raw_parts(table.data.as_ptr() as *const Symbol, count) };
+
+    (entries, &table.data)
+}
+
+/// Find the function containing `addr`. Returns its name and the offset of `addr` in it.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let (entries, data) = symbols();
+
+    // The last symbol at or before `addr`.
+    let idx = match entries.binary_search_by_key(&addr, |sym| sym.addr) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+
+    let sym = entries[idx];
+    let start = sym.name_offset as usize;
+    let name = data.get(start..start + sym.name_len as usize)?;
+
+    Some((str::from_utf8(name).ok()?, addr - sym.addr))
+}
+
+/// The current value of `rbp`. Since this is always inlined, it is the frame pointer of the caller.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("movq %rbp, $0" : "=r"(rbp) : /* no inputs */ : /* no clobbers */ : "volatile");
+    }
+    rbp
+}
+
+/// Call `f` with each return address on the stack, starting with the frame at `rbp`.
+pub fn walk<F: FnMut(u64)>(mut rbp: u64, mut f: F) {
+    for _ in 0..MAX_FRAMES {
+        // A frame is two words: the caller's rbp and the return address.
+        if rbp == 0
+            || rbp % 8 != 0
+            || probe(rbp) != Probe::Mapped
+            || probe(rbp + 8) != Probe::Mapped
+        {
+            return;
+        }
+
+        let (next, ret) = unsafe {
+            (
+                (rbp as *const u64).read_volatile(),
+                ((rbp + 8) as *const u64).read_volatile(),
+            )
+        };
+
+        if ret == 0 {
+            return;
+        }
+
+        f(ret);
+
+        // The stack grows down, so callers' frames are at higher addresses. Anything else means
+        // we've reached the bottom of the stack, or the chain is broken.
+        if next <= rbp {
+            return;
+        }
+
+        rbp = next;
+    }
+}
+
+/// Print one frame of a backtrace.
+fn print_frame(n: usize, addr: u64) {
+    // Look up `addr - 1`, since a return address may already be past the end of the function
+    // (e.g. after a call to a function that never returns).
+    match symbolize(addr - 1) {
+        Some((name, offset)) => printk!("  {:2}: {:#018x} {}+{:#x}\n", n, addr, name, offset + 1),
+        None => printk!("  {:2}: {:#018x} ??\n", n, addr),
+    }
+}
+
+/// Print a backtrace of the caller.
+#[inline(always)]
+pub fn print_backtrace() {
+    print_backtrace_from(frame_pointer());
+}
+
+/// Print a backtrace starting with the frame at `rbp`.
+pub fn print_backtrace_from(rbp: u64) {
+    printk!("Backtrace:\n");
+
+    let mut n = 0;
+    walk(rbp, |addr| {
+        print_frame(n, addr);
+        n += 1;
+    });
+}
+
+/// Called by an exception handler that is about to panic, so that the panic's backtrace is of the
+/// code interrupted by the exception rather than of the handler. `rip` is the instruction pointer
+/// from the interrupt stack frame, and `rbp` is the frame pointer of the exception handler (i.e.
+/// `frame_pointer()`), whose frame contains the interrupted code's frame pointer.
+pub fn set_exception(rip: u64, rbp: u64) {
+    // The exception may have happened while the lock was held.
+    if let Some(mut exceptions) = EXCEPTIONS.try_lock() {
+        exceptions[cpu_id()] = Some((rip, rbp));
+    }
+}
+
+/// Print the backtrace of a panic on this core: of the code interrupted by an exception if an
+/// exception handler is panicking (see `set_exception`), or else of the caller.
+#[inline(always)]
+pub fn print_panic_backtrace() {
+    // Don't wait for the lock: we might have panicked while holding it.
+    let exception = EXCEPTIONS
+        .try_lock()
+        .and_then(|mut exceptions| exceptions[cpu_id()].take());
+
+    match exception {
+        Some((rip, rbp)) => print_exception_backtrace(rip, rbp),
+        None => print_backtrace(),
+    }
+}
+
+/// Print a backtrace of the code interrupted by an exception (see `set_exception`).
+fn print_exception_backtrace(rip: u64, rbp: u64) {
+    printk!("Backtrace of the interrupted code:\n");
+
+    // `rip` hasn't been executed yet, so it doesn't need the adjustment in `print_frame`.
+    match symbolize(rip) {
+        Some((name, offset)) => printk!("  {:2}: {:#018x} {}+{:#x}\n", 0, rip, name, offset),
+        None => printk!("  {:2}: {:#018x} ??\n", 0, rip),
+    }
+
+    if probe(rbp) != Probe::Mapped {
+        return;
+    }
+
+    let mut n = 1;
+    walk(unsafe { (rbp as *const u64).read_volatile() }, |addr| {
+        print_frame(n, addr);
+        n += 1;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{frame_pointer, walk};
+
+    #[inline(never)]
+    fn inner() -> Vec<u64> {
+        let mut frames = Vec::new();
+        walk(frame_pointer(), |addr| frames.push(addr));
+        frames
+    }
+
+    #[inline(never)]
+    fn outer() -> (Vec<u64>, u64) {
+        (inner(), outer as usize as u64)
+    }
+
+    kernel_test!(walks_frames, 5, async {
+        let (frames, outer) = outer();
+
+        // The first frame returns into `outer`.
+        assert!(frames.len() >= 2);
+        assert!(frames[0] > outer && frames[0] < outer + 0x1000);
+    });
+}
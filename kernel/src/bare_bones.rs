@@ -10,6 +10,7 @@ use crate::debug::Debug;
 
 /// This function is used by `panic!` to display an error message.
 #[panic_handler]
+#[cfg_attr(test, allow(unreachable_code))]
 fn rust_begin_panic(pi: &PanicInfo) -> ! {
     // we should no be interrupting any more
     interrupts::disable();
@@ -32,7 +33,15 @@ fn rust_begin_panic(pi: &PanicInfo) -> ! {
         printk!("<no message>");
     }
 
-    printk!("\n===========================\n");
+    printk!("\n...........................\n");
+
+    crate::backtrace::print_panic_backtrace();
+
+    printk!("===========================\n");
+
+    // In a test build, a panic means a test failed.
+    #[cfg(test)]
+    crate::test::panicked();
 
     loop {
         hlt(); // Don't just spin... wait a bit
@@ -52,6 +52,18 @@ pub fn init() {
     *CAPABILITY_RNG.lock() = Some(box StdRng::seed_from_u64(0));
 }
 
+/// Calls `f` with the key and capability of everything in the registry, for debugging.
+///
+/// NOTE: This holds the registry lock, so nothing expensive should be done in `f`.
+pub fn for_each_capability<F>(mut f: F)
+where
+    F: FnMut(u128, &Capability),
+{
+    for (&key, cap) in CAPABILITY_REGISTRY.lock().as_ref().unwrap().iter() {
+        f(key, cap);
+    }
+}
+
 /// A capability on a single resource. Having this capability gives access to the resource.
 /// Capabilities should be registered in the `CAPABILITY_REGISTRY` before use so that the kernel
 /// can check them when needed.
@@ -63,6 +75,16 @@ pub enum Capability {
 
     /// A capability on a region of the virtual address space.
     VirtualMemoryRegion(VirtualMemoryRegion),
+
+    /// A capability to receive keyboard input (see `io::kbd::open`). Only the holder with the
+    /// input focus gets any.
+    Keyboard,
+
+    /// Ownership of the given virtual terminal (see `io::vt::open`).
+    Terminal(usize),
+
+    /// A capability to receive mouse events (see `io::mouse::open`).
+    Mouse,
 }
 
 /// Used to unwrap a capability when you know statically what type it is.
@@ -78,7 +100,7 @@ macro_rules! cap_unwrap {
 }
 
 /// A handle to a resource in the capability registry.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct ResourceHandle {
     /// An index into the capability registry.
     key: u128,
@@ -102,16 +124,6 @@ impl ResourceHandle {
     }
 }
 
-impl Clone for ResourceHandle {
-    fn clone(&self) -> Self {
-        ResourceHandle {
-            key: self.key.clone(),
-        }
-    }
-}
-
-impl Copy for ResourceHandle {}
-
 /// A capability that has not been registered yet.  An unregistered capability can be modified
 /// until it is registered.
 #[derive(Debug)]
@@ -180,3 +192,42 @@ impl CapabilityGroup {
         CapabilityGroup { caps }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::{Capability, CapabilityGroup, UnregisteredResourceHandle};
+    use crate::memory::VirtualMemoryRegion;
+
+    kernel_test!(register_and_lookup, 1, async {
+        let mut unregistered = VirtualMemoryRegion::alloc(3);
+        let start = cap_unwrap!(VirtualMemoryRegion(unregistered.as_mut_ref())).start();
+
+        let handle = unregistered.register();
+        let copy = handle;
+
+        let (a, len) = handle.with(|cap| {
+            let region = cap_unwrap!(VirtualMemoryRegion(cap));
+            (region.start(), region.len())
+        });
+        let b = copy.with(|cap| cap_unwrap!(VirtualMemoryRegion(cap)).start());
+
+        assert_eq!(a, start);
+        assert_eq!(b, start);
+        assert_eq!(len, 3 * 4096);
+    });
+
+    kernel_test!(unique_handles, 1, async {
+        let group = || {
+            UnregisteredResourceHandle::new(Capability::CapabilityGroup(CapabilityGroup::new(
+                vec![],
+            )))
+            .register()
+        };
+
+        let a = group();
+        let b = group();
+        assert_ne!(a.key, b.key);
+    });
+}
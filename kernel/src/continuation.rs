@@ -1,20 +1,48 @@
 //! A module for defining continuations and events
 
-use alloc::{boxed::Box, vec, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 
-use crate::{sched, time::SysTime};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    cap::ResourceHandle,
+    io::{block::BlockError, kbd::KeyEvent, mouse::MouseEvent},
+    sched,
+    time::SysTime,
+};
 
 /// Different kinds of events a continuation can wait for.
-#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum EventKind {
     /// Wait for "now" to occur. i.e. don't wait for anything.
     Now,
 
-    /// Wait for keyboard input.
-    Keyboard,
+    /// Wait for keyboard input for the given keyboard capability (see `io::kbd`).
+    Keyboard(ResourceHandle),
 
     /// Wait for the system "clock" to have a given reading.
     Until(SysTime),
+
+    /// Wait for input on the given serial port (1 to 4, for COM1 to COM4).
+    Serial(u8),
+
+    /// Wait for input on the virtual terminal owned through the given capability (see `io::vt`).
+    Terminal(ResourceHandle),
+
+    /// Wait for an event for the given mouse capability (see `io::mouse`).
+    Mouse(ResourceHandle),
+
+    /// Wait for the block device request with the given ID to finish (see `io::block`).
+    Block(u64),
+
+    /// Wait for the waker of the task with the given ID to be woken (see `task`).
+    Woken(u64),
+
+    /// The given error has already occured. Like `Now`, this doesn't wait for anything, but the
+    /// continuation is passed `Event::Error` instead of `Event::Now`.
+    Error(ContError),
 }
 
 /// The events corresponding to `EventKind`.
@@ -23,11 +51,80 @@ pub enum Event {
     /// Wow! It's now!
     Now,
 
-    /// The given character has been typed
+    /// The given character has been typed (a byte of UTF-8)
     Keyboard(u8),
 
+    /// A key has been pressed or released (for a keyboard in raw mode)
+    Key(KeyEvent),
+
     /// A timer has expired
     Timer,
+
+    /// The given byte has been received on a serial port
+    Serial(u8),
+
+    /// A byte of a line typed on a virtual terminal (a line is only delivered once it is finished)
+    Terminal(u8),
+
+    /// Ctrl-C was typed on a virtual terminal
+    Interrupt,
+
+    /// The mouse moved, or a button or the wheel was used
+    Mouse(MouseEvent),
+
+    /// A block device request has finished, successfully or not
+    Block(Result<(), BlockError>),
+
+    /// An error occured in a previous continuation.
+    Error(ContError),
+}
+
+/// The errors a continuation can fail with.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[allow(dead_code)]
+pub enum ContError {
+    /// Ran out of physical or virtual memory.
+    OutOfMemory,
+
+    /// A capability was missing or was for the wrong kind of resource.
+    InvalidCapability,
+
+    /// Gave up waiting for an event.
+    Timeout,
+
+    /// A user-mode task did something bad (e.g. an invalid syscall).
+    UserFault,
+
+    /// Anything else. The string is a short description for debugging.
+    Other(&'static str),
+}
+
+/// Scheduling classes. When picking the next continuation, the scheduler always chooses a ready
+/// continuation from the first non-empty class in this order.
+///
+/// A continuation inherits the class of the continuation that created it, unless it is given one
+/// explicitly with `Continuation::with_priority`.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[allow(dead_code)]
+pub enum Priority {
+    /// Real-time work, scheduled earliest-deadline-first. The deadline of a continuation waiting
+    /// for `EventKind::Until(t)` is `t`. Continuations waiting for anything else run after all
+    /// ready deadlines, in FIFO order.
+    RealTime,
+
+    /// Short follow-up work, e.g. for interrupts.
+    High,
+
+    /// Everything else.
+    Normal,
+
+    /// Only run when nothing else is ready.
+    Background,
+}
+
+impl Priority {
+    /// The number of scheduling classes.
+    pub const COUNT: usize = 4;
 }
 
 /// The possible results of running a continuation.
@@ -36,16 +133,156 @@ pub enum ContResult {
     /// The continuation suceeded and the next continuation and its precondition are given.
     Success(Vec<(EventKind, Continuation)>),
 
-    /// The Continuation failed and here is the continuation to handle the error.
-    Error(Continuation),
+    /// The Continuation failed with the given error. If a handler continuation is given, it is
+    /// run with `Event::Error`. Otherwise, the error is passed up to the nearest supervisor.
+    Error(ContError, Option<Continuation>),
 
     /// The continuation suceeded and there is nothing left to be done.
     Done,
 }
 
+/// A supervisor handles errors that are not handled by the continuations it supervises.
+///
+/// Supervisors form a tree: a supervisor created within a supervised continuation has that
+/// continuation's supervisor as its parent. If a supervisor fails to handle an error itself (by
+/// returning `ContResult::Error(_, None)`), the error goes to its parent, and so on. An error
+/// that reaches the top of the tree without being handled causes a panic.
+pub struct Supervisor {
+    /// Called with each unhandled error.
+    handler: Mutex<Box<dyn FnMut(ContError) -> ContResult + Send>>,
+
+    /// The supervisor to escalate to. This is filled in the first time the supervised
+    /// continuation is enqueued by its parent.
+    parent: Mutex<Option<Arc<Supervisor>>>,
+}
+
+impl Supervisor {
+    /// Set the parent of this supervisor if it doesn't have one yet.
+    fn adopt(self: &Arc<Self>, parent: &Option<Arc<Supervisor>>) {
+        let mut mine = self.parent.lock();
+        match parent {
+            Some(parent) if mine.is_none() && !Arc::ptr_eq(self, parent) => {
+                *mine = Some(parent.clone())
+            }
+            _ => {}
+        }
+    }
+
+    /// Pass `error` to the `supervisor`. This returns a continuation that runs the supervisor's
+    /// handler on behalf of the continuation that failed.
+    ///
+    /// # Panics
+    ///
+    /// If there is no supervisor.
+    fn escalate(supervisor: Option<Arc<Supervisor>>, error: ContError) -> Continuation {
+        let supervisor = supervisor
+            .unwrap_or_else(|| panic!("Unhandled error in continuation: {:?}", error));
+
+        let parent = supervisor.parent.lock().clone();
+
+        let mut cont = Continuation::new(move |ev| {
+            if let Event::Error(error) = ev {
+                (&mut *supervisor.handler.lock())(error)
+            } else {
+                unreachable!();
+            }
+        });
+        cont.supervisor = parent;
+        cont
+    }
+}
+
+/// Where a continuation sits in the continuation DAG, for the purposes of cancellation.
+///
+/// Only continuations that someone holds a `CancelToken` for are kept in the chain of parents.
+/// Otherwise, a long-running chain of continuations (e.g. a loop waiting for keyboard input)
+/// would keep all of its ancestors alive forever.
+struct Lineage {
+    /// This continuation (but not its children) was cancelled.
+    cancelled: AtomicBool,
+
+    /// This continuation and all of its descendents were cancelled.
+    tree_cancelled: AtomicBool,
+
+    /// The closest ancestor that could be cancelled. This is filled in when the parent enqueues
+    /// the continuation.
+    parent: Mutex<Option<Arc<Lineage>>>,
+}
+
+impl Lineage {
+    fn new() -> Self {
+        Lineage {
+            cancelled: AtomicBool::new(false),
+            tree_cancelled: AtomicBool::new(false),
+            parent: Mutex::new(None),
+        }
+    }
+
+    /// Has this continuation or any of its ancestors' subtrees been cancelled?
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.is_tree_cancelled()
+    }
+
+    /// Has the subtree of this continuation or any of its ancestors been cancelled?
+    fn is_tree_cancelled(&self) -> bool {
+        self.tree_cancelled.load(Ordering::Relaxed)
+            || self
+                .parent
+                .lock()
+                .as_ref()
+                .map_or(false, |parent| parent.is_tree_cancelled())
+    }
+}
+
+/// A handle that can be used to cancel a continuation that has not run yet, or to cancel all of
+/// the continuations descended from it.
+///
+/// Cancelled continuations are removed from the scheduler and dropped, so any resources they
+/// captured are released.
+#[derive(Clone)]
+pub struct CancelToken(Arc<Lineage>);
+
+#[allow(dead_code)]
+impl CancelToken {
+    /// Cancel the continuation if it has not run yet. Continuations it already created are not
+    /// affected.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+        sched::purge_cancelled();
+    }
+
+    /// Cancel the continuation and all of its descendents, including ones that have not been
+    /// created yet.
+    pub fn cancel_tree(&self) {
+        self.0.tree_cancelled.store(true, Ordering::Relaxed);
+        sched::purge_cancelled();
+    }
+
+    /// Has the continuation been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+}
+
+/// The ID of the next continuation to be created. IDs start at 1.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Represents a single Task in the system
 pub struct Continuation {
+    /// A unique ID, for debugging.
+    id: u64,
+
     routine: Option<Box<dyn FnMut(Event) -> ContResult + Send>>,
+
+    /// Handles any errors from this continuation (and the continuations it creates) that are not
+    /// handled otherwise.
+    supervisor: Option<Arc<Supervisor>>,
+
+    /// Used to cancel this continuation.
+    lineage: Arc<Lineage>,
+
+    /// The scheduling class of this continuation, if it has been set or inherited yet.
+    priority: Option<Priority>,
 }
 
 impl Continuation {
@@ -55,10 +292,77 @@ impl Continuation {
         F: 'static + Send + FnMut(Event) -> ContResult,
     {
         Continuation {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             routine: Some(Box::new(routine)),
+            supervisor: None,
+            lineage: Arc::new(Lineage::new()),
+            priority: None,
         }
     }
 
+    /// The unique ID of this continuation.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Put this continuation (and, by default, the continuations it creates) in the given
+    /// scheduling class.
+    pub fn with_priority(mut self, priority: Priority) -> Continuation {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// The scheduling class of this continuation.
+    pub fn priority(&self) -> Priority {
+        self.priority.unwrap_or(Priority::Normal)
+    }
+
+    /// Returns a token that can be used to cancel this continuation (and its descendents) later.
+    pub fn token(&self) -> CancelToken {
+        CancelToken(self.lineage.clone())
+    }
+
+    /// Has this continuation been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.lineage.is_cancelled()
+    }
+
+    /// Make `handler` the supervisor of this continuation and all of the continuations it
+    /// creates. `handler` is called for each error that they don't handle themselves.
+    #[allow(dead_code)]
+    pub fn supervised<F>(mut self, handler: F) -> Continuation
+    where
+        F: 'static + Send + FnMut(ContError) -> ContResult,
+    {
+        self.supervisor = Some(Arc::new(Supervisor {
+            handler: Mutex::new(Box::new(handler)),
+            parent: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Make `self` a child of `parent`: it gets `parent`'s supervisor, unless it has its own, in
+    /// which case its supervisor escalates to `parent`'s. Cancelling `parent`'s subtree also
+    /// cancels `self`. Unless it has its own scheduling class, `self` gets `parent`'s.
+    fn inherit(&mut self, parent: &Continuation) {
+        if self.priority.is_none() {
+            self.priority = parent.priority;
+        }
+
+        match &self.supervisor {
+            Some(sup) => sup.adopt(&parent.supervisor),
+            None => self.supervisor = parent.supervisor.clone(),
+        }
+
+        // If nobody can cancel `parent` anymore, skip it and link to its parent instead.
+        let lineage = if Arc::strong_count(&parent.lineage) > 1 {
+            Some(parent.lineage.clone())
+        } else {
+            parent.lineage.parent.lock().clone()
+        };
+        *self.lineage.parent.lock() = lineage;
+    }
+
     /// Execute this continuation. Enqueue any resulting continuation in the scheduler. Then, cede
     /// control to the scheduler.
     ///
@@ -68,14 +372,38 @@ impl Continuation {
     /// However, the caller is responsible from making sure there is no stack overflow.
     ///
     /// Usually, this will be called just from the scheduler.
-    pub fn run(mut self, event: Event) -> ! {
+    pub fn run(self, event: Event) -> ! {
+        self.step(event);
+
+        // cede control to the scheduler
+        sched::sched()
+    }
+
+    /// Execute this continuation and enqueue any resulting continuation in the scheduler, but
+    /// don't cede control to the scheduler. This is what `run` does before switching stacks, and
+    /// is used by the hosted simulator (see `sim/`), which drives the scheduler itself.
+    pub fn step(mut self, event: Event) {
         // run this continuation, and enqueue the result
         match (self.routine.take().unwrap())(event) {
             // schedule the continuation
-            ContResult::Success(cont) => sched::enqueue(cont),
+            ContResult::Success(mut cont) => {
+                for (_, child) in cont.iter_mut() {
+                    child.inherit(&self);
+                }
+                sched::enqueue(cont);
+            }
 
             // schedule the error continuation with the error event
-            ContResult::Error(cont) => sched::enqueue(vec![(EventKind::Now, cont)]),
+            ContResult::Error(error, Some(mut handler)) => {
+                handler.inherit(&self);
+                sched::enqueue(vec![(EventKind::Error(error), handler)]);
+            }
+
+            // nobody handles the error, so pass it to the supervisor
+            ContResult::Error(error, None) => {
+                let handler = Supervisor::escalate(self.supervisor.take(), error);
+                sched::enqueue(vec![(EventKind::Error(error), handler)]);
+            }
 
             // if they are done, the continuation is the idle continuation
             ContResult::Done => sched::idle(),
@@ -86,8 +414,5 @@ impl Continuation {
 
         // Drop the current continuation
         drop(self);
-
-        // cede control to the scheduler
-        sched::sched()
     }
 }
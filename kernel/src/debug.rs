@@ -1,27 +1,22 @@
-//! This module allows the user to print to QEMU's serial console.
+//! This module allows the user to print to QEMU's serial console and the VGA screen.
 //!
 //! I borrowed it from krzysz00/rust-kernel/kernel/console.rs
 
-use x86_64::instructions::port::Port;
-
 use core::fmt::{Error, Write};
 
-/// Port to output to serial console
-const PORT_IN: Port<u8> = Port::new(0x3F8 + 5);
-const PORT_OUT: Port<u8> = Port::new(0x3F8);
+use crate::io::{serial, vt};
+
+/// The serial port of the console
+pub const CONSOLE_PORT: u8 = 1;
 
 /// A struct to write data to the console port
 pub struct Debug;
 
 impl Debug {
-    /// Wait for the port, then write the given array of bytes
+    /// Write the given array of bytes (see `io::serial::write` and `io::vt::print`)
     pub fn write_bytes(&self, bytes: &[u8]) {
-        for b in bytes {
-            unsafe {
-                while PORT_IN.read() & 0x20 == 0 {}
-                PORT_OUT.write(*b);
-            }
-        }
+        serial::write(CONSOLE_PORT, bytes);
+        vt::print(bytes);
     }
 }
 
@@ -0,0 +1,789 @@
+//! A GDB remote protocol stub on COM2.
+//!
+//! QEMU's own gdbstub (`-s`) sees the machine; this one sees the kernel. Continuations are
+//! reported as threads (by `Continuation::id`), so `info threads` lists everything that is running
+//! or enqueued. To use it, connect GDB to whatever COM2 is attached to:
+//!
+//! ```txt
+//! (gdb) target remote localhost:4321
+//! ```
+//!
+//! The kernel stops (on the core that trapped) when
+//! - it hits an `int3`, either a breakpoint inserted by GDB or a call to `breakpoint`,
+//! - it finishes a single step (GDB's `stepi`), using the trap flag and the debug exception,
+//! - GDB sends Ctrl-C or a packet while the kernel is running (e.g. when it first connects). The
+//!   COM2 interrupt handler traps into the stub with `int3`, so the stop is reported there.
+//!
+//! While stopped, interrupts are off on that core, and we poll the serial port. The other cores
+//! keep running. Only the stopped core's registers are available; other "threads" can be listed
+//! but not inspected.
+//!
+//! Memory is only accessed if it is mapped (see `memory::probe`), so GDB can't cause a page fault.
+//! Pages that are allowed but not yet faulted in read as zeros.
+//!
+//! NOTE: the breakpoint and debug exceptions are handled on the current stack, not an IST stack,
+//! since the stub can trap from an interrupt handler already running on the IST stack. So they
+//! only work in kernel mode.
+
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+
+use x86_64::{
+    instructions::port::Port,
+    registers::control::{Cr0, Cr0Flags},
+    structures::idt::InterruptDescriptorTable,
+};
+
+use crate::{
+    memory::{probe, Probe},
+    sched::{self, user::TrapFrame as TrapRegs},
+};
+
+/// COM2 ports
+const DATA: Port<u8> = Port::new(0x2F8);
+const IER: Port<u8> = Port::new(0x2F8 + 1);
+const FCR: Port<u8> = Port::new(0x2F8 + 2);
+const LCR: Port<u8> = Port::new(0x2F8 + 3);
+const MCR: Port<u8> = Port::new(0x2F8 + 4);
+const LSR: Port<u8> = Port::new(0x2F8 + 5);
+
+/// Line status bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// Exception vectors that trap into the stub.
+const VECTOR_DEBUG: u64 = 1;
+const VECTOR_BREAKPOINT: u64 = 3;
+
+/// Signals reported to GDB.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The trap flag in RFLAGS.
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// The `int3` instruction.
+const INT3: u8 = 0xCC;
+
+/// GDB sends this byte to interrupt the kernel.
+const CTRL_C: u8 = 0x03;
+
+/// The largest packet we accept or send (bytes of data).
+const MAX_PACKET: usize = 4096;
+
+const HEX_DIGITS: &[u8] = b"0123456789abcdef";
+
+/// The most software breakpoints that can be inserted at once.
+const MAX_BREAKPOINTS: usize = 32;
+
+/// What the trap entry stubs save, lowest address first: the vector, then the same registers as
+/// `irq_0` saves for preemption.
+#[derive(Debug)]
+#[repr(C)]
+struct TrapFrame {
+    vector: u64,
+    regs: TrapRegs,
+}
+
+/// The state of the stub. It is locked by the core that is stopped.
+struct Stub {
+    /// Inserted software breakpoints: the address and the byte that was there before.
+    breakpoints: [Option<(u64, u8)>; MAX_BREAKPOINTS],
+
+    /// Set by `handle_irq` if GDB interrupted us.
+    interrupted: bool,
+
+    /// A byte `handle_irq` has already read that should be seen by the packet reader first.
+    pending: Option<u8>,
+
+    /// The packet being handled.
+    packet: [u8; MAX_PACKET],
+
+    /// The reply being built.
+    reply: Reply,
+}
+
+/// The stub. These buffers are static because we may be on a small interrupt stack.
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    breakpoints: [None; MAX_BREAKPOINTS],
+    interrupted: false,
+    pending: None,
+    packet: [0; MAX_PACKET],
+    reply: Reply {
+        buf: [0; MAX_PACKET],
+        len: 0,
+    },
+});
+
+/// What to do after handling a packet.
+enum Action {
+    /// Send the reply and wait for the next packet.
+    Reply,
+
+    /// Resume execution without replying.
+    Resume,
+}
+
+/// A packet being built. Anything that doesn't fit is dropped.
+struct Reply {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, b: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn hex_byte(&mut self, b: u8) {
+        self.push(HEX_DIGITS[(b >> 4) as usize]);
+        self.push(HEX_DIGITS[(b & 0xF) as usize]);
+    }
+
+    /// The low `bytes` bytes of `val` as hex, in little-endian order (i.e. like a register).
+    fn hex_le(&mut self, val: u64, bytes: usize) {
+        for i in 0..bytes {
+            self.hex_byte((val >> (i * 8)) as u8);
+        }
+    }
+
+    fn room(&self) -> usize {
+        self.buf.len() - self.len
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|b| self.push(b));
+        Ok(())
+    }
+}
+
+/// Initialize COM2 for the stub: 115200 baud, 8N1, with an interrupt when data arrives.
+pub fn init() {
+    unsafe {
+        IER.write(0x00); // no interrupts while we set things up
+        LCR.write(0x80); // set the baud rate divisor...
+        DATA.write(0x01); // ...to 1 (115200 baud)
+        IER.write(0x00);
+        LCR.write(0x03); // 8 bits, no parity, one stop bit
+        FCR.write(0xC7); // enable and clear the FIFOs
+        MCR.write(0x0B); // DTR, RTS, and OUT2 (which gates the IRQ)
+        IER.write(0x01); // interrupt when data is received
+    }
+
+    info!("gdb stub inited on COM2");
+}
+
+/// Install the breakpoint and debug exception handlers.
+pub unsafe fn init_irqs(idt: &mut InterruptDescriptorTable) {
+    extern "C" {
+        fn gdb_breakpoint_entry();
+        fn gdb_debug_entry();
+    }
+
+    // These need all of the registers, so they have their own entry stubs rather than
+    // `x86-interrupt` functions. `set_handler_fn` only uses the address.
+    idt.breakpoint.set_handler_fn(core::mem::transmute(
+        gdb_breakpoint_entry as unsafe extern "C" fn(),
+    ));
+    idt.debug.set_handler_fn(core::mem::transmute(
+        gdb_debug_entry as unsafe extern "C" fn(),
+    ));
+}
+
+/// Stop and wait for GDB.
+pub fn breakpoint() {
+    unsafe {
+        asm!("int3" : : : "memory" : "volatile");
+    }
+}
+
+/// Called by the COM2 interrupt handler. If GDB wants our attention, stop.
+pub fn handle_irq() {
+    while unsafe { LSR.read() } & LSR_DATA_READY != 0 {
+        let b = unsafe { DATA.read() };
+
+        if b == CTRL_C || b == b'$' {
+            // If another core is in the stub, it will see the packet instead.
+            match STUB.try_lock() {
+                Some(mut stub) => {
+                    stub.interrupted = true;
+                    stub.pending = if b == b'$' { Some(b) } else { None };
+                }
+                None => return,
+            }
+
+            breakpoint();
+            return;
+        }
+    }
+}
+
+// The entry stubs for the breakpoint and debug exceptions. Like `irq_0`, these save all of the
+// general-purpose registers (plus the vector) so that GDB can see and change them.
+//
+// The hardware frame is 5 words, and we push 16 more, so we need to pad the stack by one word to
+// keep it 16B-aligned for the call.
+global_asm!(
+    "
+    .section .text
+    .code64
+
+    .global gdb_breakpoint_entry
+    gdb_breakpoint_entry:
+        pushq %rax
+        mov $3, %rax
+        jmp gdb_trap_common
+
+    .global gdb_debug_entry
+    gdb_debug_entry:
+        pushq %rax
+        mov $1, %rax
+        jmp gdb_trap_common
+
+    gdb_trap_common:
+        pushq %rbx
+        pushq %rcx
+        pushq %rdx
+        pushq %rdi
+        pushq %rsi
+        pushq %rbp
+        pushq %r8
+        pushq %r9
+        pushq %r10
+        pushq %r11
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+
+        # the vector
+        pushq %rax
+
+        mov %rsp, %rdi
+        sub $8, %rsp
+        cld
+        call handle_gdb_trap
+        add $8, %rsp
+
+        # pop the vector
+        add $8, %rsp
+
+        popq %r15
+        popq %r14
+        popq %r13
+        popq %r12
+        popq %r11
+        popq %r10
+        popq %r9
+        popq %r8
+        popq %rbp
+        popq %rsi
+        popq %rdi
+        popq %rdx
+        popq %rcx
+        popq %rbx
+        popq %rax
+
+        iretq
+    "
+);
+
+/// Handle a breakpoint or debug exception. Called by the entry stubs.
+#[no_mangle]
+extern "C" fn handle_gdb_trap(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+
+    let signal = match frame.vector {
+        VECTOR_BREAKPOINT => {
+            // If this is one of our breakpoints, report (and resume at) the breakpoint itself,
+            // rather than the instruction after it.
+            let addr = frame.regs.rip.wrapping_sub(1);
+            if stub
+                .breakpoints
+                .iter()
+                .any(|bp| bp.map(|(a, _)| a) == Some(addr))
+            {
+                frame.regs.rip = addr;
+            }
+
+            if stub.interrupted {
+                stub.interrupted = false;
+                SIGINT
+            } else {
+                SIGTRAP
+            }
+        }
+
+        VECTOR_DEBUG => {
+            frame.regs.rflags &= !RFLAGS_TF;
+            SIGTRAP
+        }
+
+        _ => unreachable!(),
+    };
+
+    stub.run(frame, signal);
+}
+
+impl Stub {
+    /// Report the stop to GDB and handle packets until it tells us to resume.
+    fn run(&mut self, frame: &mut TrapFrame, signal: u8) {
+        stop_reply(&mut self.reply, signal);
+        send_packet(&self.reply);
+
+        loop {
+            let len = recv_packet(&mut self.packet, self.pending.take());
+
+            self.reply.clear();
+            let action = handle_packet(
+                &self.packet[..len],
+                &mut self.reply,
+                &mut self.breakpoints,
+                frame,
+                signal,
+            );
+
+            match action {
+                Action::Reply => send_packet(&self.reply),
+                Action::Resume => return,
+            }
+        }
+    }
+}
+
+/// Handle one packet from GDB, building the reply in `reply`.
+fn handle_packet(
+    packet: &[u8],
+    reply: &mut Reply,
+    breakpoints: &mut [Option<(u64, u8)>],
+    frame: &mut TrapFrame,
+    signal: u8,
+) -> Action {
+    let (&cmd, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Reply,
+    };
+
+    match cmd {
+        // Why did we stop?
+        b'?' => stop_reply(reply, signal),
+
+        // Read registers
+        b'g' => {
+            for &reg in registers(&frame.regs).iter() {
+                reply.hex_le(reg, 8);
+            }
+            reply.hex_le(frame.regs.rflags, 4);
+            reply.hex_le(frame.regs.cs, 4);
+            reply.hex_le(frame.regs.ss, 4);
+        }
+
+        // Write registers. We only take the general-purpose registers, rip, and rflags.
+        b'G' => match parse_registers(args) {
+            Some((regs, rflags)) => {
+                set_registers(&mut frame.regs, &regs);
+                frame.regs.rflags = rflags;
+                let _ = reply.write_str("OK");
+            }
+            None => error(reply),
+        },
+
+        // Read memory: `m addr,len`
+        b'm' => match parse_pair(args, b',') {
+            Some((addr, len)) => {
+                let len = core::cmp::min(len as usize, reply.room() / 2);
+                if !read_memory(addr, len, reply) {
+                    reply.clear();
+                    error(reply);
+                }
+            }
+            None => error(reply),
+        },
+
+        // Write memory: `M addr,len:bytes`
+        b'M' => {
+            let (header, data) = split(args, b':');
+            match (parse_pair(header, b','), data) {
+                (Some((addr, len)), Some(data)) if data.len() == len as usize * 2 => {
+                    if write_memory(addr, data) {
+                        let _ = reply.write_str("OK");
+                    } else {
+                        error(reply);
+                    }
+                }
+                _ => error(reply),
+            }
+        }
+
+        // Continue or step, optionally from a new address.
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.regs.rip = addr;
+            }
+            if cmd == b's' {
+                frame.regs.rflags |= RFLAGS_TF;
+            }
+            return Action::Resume;
+        }
+
+        // Insert or remove a software breakpoint: `Z0,addr,kind`
+        b'Z' | b'z' if args.first() == Some(&b'0') => {
+            let addr = args
+                .get(2..)
+                .and_then(|args| parse_hex(split(args, b',').0));
+
+            let ok = match addr {
+                Some(addr) if cmd == b'Z' => insert_breakpoint(breakpoints, addr),
+                Some(addr) => remove_breakpoint(breakpoints, addr),
+                None => false,
+            };
+
+            if ok {
+                let _ = reply.write_str("OK");
+            } else {
+                error(reply);
+            }
+        }
+
+        // Set the thread for later operations. We only have the registers of the stopped
+        // continuation, so we just say OK.
+        b'H' => {
+            let _ = reply.write_str("OK");
+        }
+
+        // Is the thread alive?
+        b'T' => match parse_hex(args) {
+            Some(id) if continuation_exists(id) => {
+                let _ = reply.write_str("OK");
+            }
+            _ => error(reply),
+        },
+
+        // Detach: remove all breakpoints and keep going.
+        b'D' => {
+            for bp in breakpoints.iter_mut() {
+                if let Some((addr, orig)) = bp.take() {
+                    poke(addr, orig);
+                }
+            }
+            let _ = reply.write_str("OK");
+            send_packet(reply);
+            return Action::Resume;
+        }
+
+        // Kill: there is nothing to kill, so just keep going.
+        b'k' => return Action::Resume,
+
+        b'q' => query(args, reply),
+
+        // Anything else is unsupported, which is an empty reply.
+        _ => {}
+    }
+
+    Action::Reply
+}
+
+/// Handle a `q` packet.
+fn query(args: &[u8], reply: &mut Reply) {
+    if args.starts_with(b"Supported") {
+        let _ = write!(reply, "PacketSize={:x}", MAX_PACKET);
+    } else if args == b"Attached" {
+        let _ = reply.write_str("1");
+    } else if args == b"C" {
+        if let Some(id) = sched::running() {
+            let _ = write!(reply, "QC{:x}", id);
+        }
+    } else if args == b"fThreadInfo" {
+        reply.push(b'm');
+        let mut first = true;
+        sched::for_each_continuation(|cont| {
+            // Leave room for the ID and a comma.
+            if reply.room() > 20 {
+                if !first {
+                    reply.push(b',');
+                }
+                let _ = write!(reply, "{:x}", cont.id);
+                first = false;
+            }
+        });
+        if first {
+            reply.clear();
+            reply.push(b'l');
+        }
+    } else if args == b"sThreadInfo" {
+        reply.push(b'l');
+    } else if args.starts_with(b"ThreadExtraInfo,") {
+        let id = parse_hex(&args[b"ThreadExtraInfo,".len()..]);
+        let info: &[u8] = if id.is_some() && id == sched::running() {
+            b"running"
+        } else {
+            b"enqueued"
+        };
+        info.iter().for_each(|&b| reply.hex_byte(b));
+    }
+}
+
+/// The stop reply: the signal and, if we know it, the continuation that was running.
+fn stop_reply(reply: &mut Reply, signal: u8) {
+    reply.clear();
+    let _ = match sched::running() {
+        Some(id) => write!(reply, "T{:02x}thread:{:x};", signal, id),
+        None => write!(reply, "S{:02x}", signal),
+    };
+}
+
+fn error(reply: &mut Reply) {
+    let _ = reply.write_str("E14"); // EFAULT
+}
+
+/// Is there a continuation with the given ID?
+fn continuation_exists(id: u64) -> bool {
+    let mut found = false;
+    sched::for_each_continuation(|cont| found |= cont.id == id);
+    found
+}
+
+/// The 64-bit registers in GDB's order: rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15, rip.
+fn registers(regs: &TrapRegs) -> [u64; 17] {
+    [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ]
+}
+
+/// Set the 64-bit registers from an array in GDB's order (see `registers`).
+fn set_registers(regs: &mut TrapRegs, values: &[u64; 17]) {
+    regs.rax = values[0];
+    regs.rbx = values[1];
+    regs.rcx = values[2];
+    regs.rdx = values[3];
+    regs.rsi = values[4];
+    regs.rdi = values[5];
+    regs.rbp = values[6];
+    regs.rsp = values[7];
+    regs.r8 = values[8];
+    regs.r9 = values[9];
+    regs.r10 = values[10];
+    regs.r11 = values[11];
+    regs.r12 = values[12];
+    regs.r13 = values[13];
+    regs.r14 = values[14];
+    regs.r15 = values[15];
+    regs.rip = values[16];
+}
+
+/// Parse the 64-bit registers and eflags from a `G` packet.
+fn parse_registers(data: &[u8]) -> Option<([u64; 17], u64)> {
+    let mut regs = [0; 17];
+    for (i, reg) in regs.iter_mut().enumerate() {
+        *reg = parse_hex_le(data.get(i * 16..(i + 1) * 16)?)?;
+    }
+    let rflags = parse_hex_le(data.get(17 * 16..17 * 16 + 8)?)?;
+    Some((regs, rflags))
+}
+
+/// Read `len` bytes at `addr` into `reply` as hex. Returns false if any of it is unmapped.
+fn read_memory(addr: u64, len: usize, reply: &mut Reply) -> bool {
+    let mut page = None;
+
+    for i in 0..len as u64 {
+        let addr = addr.wrapping_add(i);
+
+        // Only probe once per page.
+        let state = match page {
+            Some((base, state)) if base == addr >> 12 => state,
+            _ => {
+                let state = probe(addr);
+                page = Some((addr >> 12, state));
+                state
+            }
+        };
+
+        match state {
+            Probe::Mapped => reply.hex_byte(unsafe { (addr as *const u8).read_volatile() }),
+            Probe::NotPaged => reply.hex_byte(0),
+            Probe::Unmapped => return false,
+        }
+    }
+
+    true
+}
+
+/// Write the hex-encoded `data` at `addr`. Returns false if any of it is not mapped.
+fn write_memory(addr: u64, data: &[u8]) -> bool {
+    let len = data.len() as u64 / 2;
+
+    // Check everything first so that we don't do half a write.
+    if (0..len).any(|i| probe(addr.wrapping_add(i)) != Probe::Mapped) {
+        return false;
+    }
+
+    for i in 0..len {
+        match parse_hex(&data[i as usize * 2..i as usize * 2 + 2]) {
+            Some(b) => poke(addr.wrapping_add(i), b as u8),
+            None => return false,
+        }
+    }
+
+    true
+}
+
+/// Write a byte, even if the page is read-only (e.g. kernel text). The page must be mapped.
+fn poke(addr: u64, val: u8) {
+    unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        (addr as *mut u8).write_volatile(val);
+        Cr0::write(cr0);
+    }
+}
+
+fn insert_breakpoint(breakpoints: &mut [Option<(u64, u8)>], addr: u64) -> bool {
+    if breakpoints
+        .iter()
+        .any(|bp| bp.map(|(a, _)| a) == Some(addr))
+    {
+        return true;
+    }
+
+    if probe(addr) != Probe::Mapped {
+        return false;
+    }
+
+    match breakpoints.iter_mut().find(|bp| bp.is_none()) {
+        Some(slot) => {
+            *slot = Some((addr, unsafe { (addr as *const u8).read_volatile() }));
+            poke(addr, INT3);
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove_breakpoint(breakpoints: &mut [Option<(u64, u8)>], addr: u64) -> bool {
+    match breakpoints
+        .iter_mut()
+        .find(|bp| bp.map(|(a, _)| a) == Some(addr))
+    {
+        Some(slot) => {
+            let (_, orig) = slot.take().unwrap();
+            poke(addr, orig);
+            true
+        }
+        None => false,
+    }
+}
+
+fn serial_read() -> u8 {
+    unsafe {
+        while LSR.read() & LSR_DATA_READY == 0 {}
+        DATA.read()
+    }
+}
+
+fn serial_write(b: u8) {
+    unsafe {
+        while LSR.read() & LSR_THR_EMPTY == 0 {}
+        DATA.write(b);
+    }
+}
+
+/// Wait for a packet with a good checksum and acknowledge it. The data is put in `buf`, and its
+/// length is returned. `first` is a byte that was already read from the port, if any.
+fn recv_packet(buf: &mut [u8], mut first: Option<u8>) -> usize {
+    loop {
+        // Skip anything (acks, Ctrl-C) until the start of a packet.
+        while first.take().unwrap_or_else(serial_read) != b'$' {}
+
+        let mut len = 0;
+        let mut sum = 0u8;
+        let mut overflow = false;
+
+        loop {
+            let b = serial_read();
+            if b == b'#' {
+                break;
+            }
+
+            sum = sum.wrapping_add(b);
+            if len < buf.len() {
+                buf[len] = b;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+
+        let checksum = parse_hex(&[serial_read(), serial_read()]);
+
+        if !overflow && checksum == Some(sum as u64) {
+            serial_write(b'+');
+            return len;
+        }
+
+        serial_write(b'-');
+    }
+}
+
+/// Send the packet in `reply` until GDB acknowledges it.
+fn send_packet(reply: &Reply) {
+    let data = &reply.buf[..reply.len];
+    let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+    loop {
+        serial_write(b'$');
+        data.iter().for_each(|&b| serial_write(b));
+        serial_write(b'#');
+
+        serial_write(HEX_DIGITS[(sum >> 4) as usize]);
+        serial_write(HEX_DIGITS[(sum & 0xF) as usize]);
+
+        match serial_read() {
+            b'+' => return,
+            _ => continue,
+        }
+    }
+}
+
+/// Split `s` at the first `sep`, which is dropped.
+fn split(s: &[u8], sep: u8) -> (&[u8], Option<&[u8]>) {
+    match s.iter().position(|&b| b == sep) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    }
+}
+
+/// Parse two hex numbers separated by `sep`.
+fn parse_pair(s: &[u8], sep: u8) -> Option<(u64, u64)> {
+    let (a, b) = split(s, sep);
+    Some((parse_hex(a)?, parse_hex(b?)?))
+}
+
+/// Parse a (big-endian) hex number.
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+
+    s.iter().try_fold(0u64, |val, &b| {
+        let digit = (b as char).to_digit(16)?;
+        Some((val << 4) | digit as u64)
+    })
+}
+
+/// Parse hex bytes in little-endian order (i.e. a register value).
+fn parse_hex_le(s: &[u8]) -> Option<u64> {
+    s.chunks(2).enumerate().try_fold(0u64, |val, (i, byte)| {
+        Some(val | parse_hex(byte)? << (i * 8))
+    })
+}
@@ -0,0 +1,166 @@
+//! A module for the I/O APIC, which routes device interrupts to local APICs. This replaces the
+//! legacy 8259 PIC, which is masked.
+//!
+//! ISA IRQs are delivered on the same vectors the PIC used (`FIRST_IDT + irq`), so the handlers
+//! in `pic` work for both.
+
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::{
+    acpi,
+    memory::{map_fixed, IOAPIC_VADDR},
+};
+
+/// The default physical address of the I/O APIC, if ACPI doesn't tell us.
+const DEFAULT_IOAPIC_PADDR: u64 = 0xFEC0_0000;
+
+/// Register selector (offset from the base).
+const IOREGSEL: u64 = 0x00;
+
+/// Register window (offset from the base).
+const IOWIN: u64 = 0x10;
+
+/// Indirect register indices
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+/// Redirection entry bits
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+
+/// MPS INTI flags (in the MADT's interrupt source overrides): the polarity and trigger mode. Each
+/// is 0 if it conforms to the bus, 1 for active high or edge-triggered, and 3 for active low or
+/// level-triggered.
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_POLARITY_HIGH: u16 = 0b01;
+const INTI_POLARITY_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_TRIGGER_EDGE: u16 = 0b01 << 2;
+const INTI_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// When an interrupt input is asserted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// What an asserted interrupt input looks like.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+/// Read an I/O APIC register.
+fn read(reg: u32) -> u32 {
+    unsafe {
+        ((IOAPIC_VADDR + IOREGSEL) as *mut u32).write_volatile(reg);
+        ((IOAPIC_VADDR + IOWIN) as *const u32).read_volatile()
+    }
+}
+
+/// Write an I/O APIC register.
+fn write(reg: u32, val: u32) {
+    unsafe {
+        ((IOAPIC_VADDR + IOREGSEL) as *mut u32).write_volatile(reg);
+        ((IOAPIC_VADDR + IOWIN) as *mut u32).write_volatile(val);
+    }
+}
+
+/// The number of interrupt inputs (global system interrupts) this I/O APIC has.
+pub fn ninputs() -> u32 {
+    ((read(IOAPICVER) >> 16) & 0xFF) + 1
+}
+
+/// Write the redirection table entry for input `gsi`.
+fn set_entry(gsi: u32, entry: u64) {
+    write(IOREDTBL + gsi * 2, entry as u32);
+    write(IOREDTBL + gsi * 2 + 1, (entry >> 32) as u32);
+}
+
+/// Deliver interrupts on input `gsi`, which are signalled as given, to `vector` on the core with
+/// the given local APIC ID.
+pub fn route(gsi: u32, trigger: Trigger, polarity: Polarity, vector: u8, apic_id: u8) {
+    assert!(gsi < ninputs(), "no such I/O APIC input: {}", gsi);
+
+    // Fixed delivery mode, physical destination, unmasked.
+    let mut entry = ((apic_id as u64) << 56) | vector as u64;
+    if trigger == Trigger::Level {
+        entry |= REDIR_LEVEL;
+    }
+    if polarity == Polarity::Low {
+        entry |= REDIR_ACTIVE_LOW;
+    }
+    set_entry(gsi, entry);
+}
+
+/// Stop delivering interrupts on input `gsi`.
+#[allow(dead_code)]
+pub fn mask(gsi: u32) {
+    set_entry(gsi, REDIR_MASKED);
+}
+
+/// The I/O APIC input (GSI) that ISA IRQ `irq` is connected to, and how it is signalled,
+/// according to the interrupt source overrides in the ACPI MADT. Without an override, it is the
+/// input with the same number, edge-triggered and active high like any ISA interrupt.
+pub fn isa_irq_to_gsi(irq: u8) -> (u32, Trigger, Polarity) {
+    irq_to_gsi(irq, Trigger::Edge, Polarity::High)
+}
+
+/// Like `isa_irq_to_gsi`, but for the IRQ in a PCI device's interrupt line register. PCI
+/// interrupts are level-triggered and active low, unless the MADT says otherwise (e.g. QEMU's are
+/// active high).
+pub fn pci_irq_to_gsi(irq: u8) -> (u32, Trigger, Polarity) {
+    irq_to_gsi(irq, Trigger::Level, Polarity::Low)
+}
+
+/// The GSI of IRQ `irq` and how it is signalled, with the given defaults for whatever the MADT
+/// doesn't say.
+fn irq_to_gsi(irq: u8, trigger: Trigger, polarity: Polarity) -> (u32, Trigger, Polarity) {
+    let over = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .and_then(|madt| madt.overrides.iter().find(|o| o.bus == 0 && o.irq == irq));
+    let (gsi, flags) = over.map_or((irq as u32, 0), |o| (o.gsi, o.flags));
+
+    let trigger = match flags & INTI_TRIGGER_MASK {
+        INTI_TRIGGER_EDGE => Trigger::Edge,
+        INTI_TRIGGER_LEVEL => Trigger::Level,
+        _ => trigger,
+    };
+    let polarity = match flags & INTI_POLARITY_MASK {
+        INTI_POLARITY_HIGH => Polarity::High,
+        INTI_POLARITY_LOW => Polarity::Low,
+        _ => polarity,
+    };
+
+    (gsi, trigger, polarity)
+}
+
+/// Map the I/O APIC registers and mask all of its inputs. Inputs are unmasked by `route`.
+///
+/// NOTE: we only use the first I/O APIC, which handles the ISA IRQs on any normal PC.
+pub fn init() {
+    let paddr = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .and_then(|madt| madt.ioapics.first())
+        .map_or(DEFAULT_IOAPIC_PADDR, |ioapic| ioapic.addr);
+
+    map_fixed(
+        IOAPIC_VADDR,
+        paddr,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_EXECUTE,
+    );
+
+    for gsi in 0..ninputs() {
+        set_entry(gsi, REDIR_MASKED);
+    }
+
+    info!("ioapic inited - {:#x}, {} inputs", paddr, ninputs());
+}
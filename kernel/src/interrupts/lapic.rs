@@ -0,0 +1,168 @@
+//! A module for the local APIC. Each core has its own local APIC, which we use to identify the
+//! current core, to send inter-processor interrupts, and as the timer tick source.
+//!
+//! All local APICs live at the same physical address (each core only sees its own), which we map
+//! at `LAPIC_VADDR`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame},
+        paging::PageTableFlags,
+    },
+};
+
+use crate::memory::{map_fixed, LAPIC_VADDR};
+
+use super::{pit, IRQ_IST_FRAME_INDEX, TIMER_HZ};
+
+/// The MSR containing the physical address of the local APIC.
+const IA32_APIC_BASE: Msr = Msr::new(0x1B);
+
+/// Register offsets
+const ID: u64 = 0x20;
+const EOI: u64 = 0xB0;
+const SPURIOUS: u64 = 0xF0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL: u64 = 0x380;
+const TIMER_CURRENT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3E0;
+
+/// The vector spurious interrupts are delivered to.
+const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// ICR bits
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+const ICR_PENDING: u32 = 1 << 12;
+
+/// LVT timer bits
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Divide the bus clock by 16 for the timer.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// How long to calibrate the timer against the PIT (ms).
+const CALIBRATION_MS: usize = 10;
+
+/// The initial count for the timer to fire at `TIMER_HZ`. This is measured once by the BSP and
+/// reused by the other cores, which are assumed to have the same bus frequency.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Read a local APIC register.
+fn read(reg: u64) -> u32 {
+    unsafe { ((LAPIC_VADDR + reg) as *const u32).read_volatile() }
+}
+
+/// Write a local APIC register.
+fn write(reg: u64, val: u32) {
+    unsafe { ((LAPIC_VADDR + reg) as *mut u32).write_volatile(val) }
+}
+
+/// Install the spurious interrupt handler.
+pub unsafe fn init_irqs(idt: &mut InterruptDescriptorTable) {
+    idt[SPURIOUS_VECTOR as usize]
+        .set_handler_fn(spurious_handler)
+        .set_stack_index(IRQ_IST_FRAME_INDEX);
+}
+
+/// Map the local APIC registers and enable the BSP's local APIC. This only needs to be done once;
+/// the other cores just call `enable`.
+pub fn init() {
+    let base = unsafe { IA32_APIC_BASE.read() } & !0xFFF;
+
+    map_fixed(
+        LAPIC_VADDR,
+        base,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_EXECUTE,
+    );
+
+    enable();
+
+    info!("lapic inited - base {:#x}, id {}", base, id());
+}
+
+/// Software-enable the current core's local APIC.
+pub fn enable() {
+    write(SPURIOUS, (1 << 8) | SPURIOUS_VECTOR as u32);
+}
+
+/// The local APIC ID of the current core.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Measure how fast the local APIC timer counts using the PIT, and return the initial count for
+/// it to fire at `TIMER_HZ`.
+fn calibrate_timer() -> u32 {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_INITIAL, core::u32::MAX);
+
+    pit::sleep_polled(CALIBRATION_MS);
+
+    let elapsed = core::u32::MAX - read(TIMER_CURRENT);
+    write(TIMER_INITIAL, 0);
+
+    let per_sec = elapsed as usize * (1000 / CALIBRATION_MS);
+    (per_sec / TIMER_HZ) as u32
+}
+
+/// Start the current core's local APIC timer. It fires periodically at `TIMER_HZ` on `vector`.
+///
+/// Interrupts should be disabled.
+pub fn init_timer(vector: u8) {
+    let mut count = TIMER_INITIAL_COUNT.load(Ordering::Relaxed);
+    if count == 0 {
+        count = calibrate_timer();
+        TIMER_INITIAL_COUNT.store(count, Ordering::Relaxed);
+        info!(
+            "lapic timer inited - {} hz, initial count {}",
+            TIMER_HZ, count
+        );
+    }
+
+    write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    write(TIMER_INITIAL, count);
+}
+
+/// Signal the end of an interrupt delivered by the local APIC.
+pub fn eoi() {
+    write(EOI, 0);
+}
+
+/// Send an interprocessor interrupt with the given ICR low bits to the core with the given APIC
+/// ID, and wait for it to be delivered.
+fn send_ipi(apic_id: u8, icr: u32) {
+    write(ICR_HIGH, (apic_id as u32) << 24);
+    write(ICR_LOW, icr);
+
+    while read(ICR_LOW) & ICR_PENDING != 0 {}
+}
+
+/// Send an INIT IPI to the given core, resetting it.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT | ICR_TRIGGER_LEVEL);
+}
+
+/// Send a STARTUP IPI to the given core. It will start executing in real mode at physical address
+/// `page << 12`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+/// Spurious interrupts don't need an EOI; just ignore them.
+extern "x86-interrupt" fn spurious_handler(_: &mut InterruptStackFrame) {}
@@ -1,5 +1,7 @@
 //! This module contains everything needed for interrupts
 
+use alloc::boxed::Box;
+
 use spin::Mutex;
 
 use x86_64::{
@@ -12,11 +14,19 @@ use x86_64::{
     PrivilegeLevel, VirtAddr,
 };
 
-pub use self::pit::HZ as PIT_HZ;
+use crate::backtrace;
 
+mod ioapic;
+pub mod lapic;
 mod pic;
 mod pit;
 
+/// The frequency of the timer tick (on every core).
+pub const TIMER_HZ: usize = 1000;
+
+/// The ISA IRQs we route through the I/O APIC: keyboard, serial, mouse, and IDE.
+const ISA_IRQS: &[u8] = &[1, 3, 4, 12, 14, 15];
+
 /// Number of bytes of the IST stack frame.
 const IST_FRAME_SIZE: usize = 4096;
 
@@ -55,13 +65,10 @@ pub static SELECTORS: Mutex<Selectors> = Mutex::new(Selectors {
     tss: SegmentSelector::new(0, PrivilegeLevel::Ring0),
 });
 
-/// Initialize interrupts (and exceptions).
-pub fn init() {
+/// Create a TSS with fresh IST stacks for the current core.
+fn make_tss() -> TaskStateSegment {
     let mut tss = TaskStateSegment::new();
-    let mut gdt = GlobalDescriptorTable::new();
-    let mut idt = InterruptDescriptorTable::new();
 
-    // Create TSS (but don't load yet).
     tss.interrupt_stack_table[EMERGENCY_IST_FRAME_INDEX as usize] = {
         // We create a struct to force the alignment to 16.
         #[repr(align(16))]
@@ -72,9 +79,9 @@ pub fn init() {
         let stack = box Stack {
             _data: [0; IST_FRAME_SIZE],
         };
-        let stack_start = VirtAddr::from_ptr(&stack);
+        let stack_start = VirtAddr::from_ptr(Box::leak(stack));
         let stack_end = stack_start + IST_FRAME_SIZE;
-        printk!("double fault stack @ {:?}, {:?}\n", stack_start, stack_end);
+        debug!("double fault stack @ {:?}, {:?}", stack_start, stack_end);
         stack_end
     };
 
@@ -88,21 +95,19 @@ pub fn init() {
         let stack = box Stack {
             _data: [0; IST_FRAME_SIZE],
         };
-        let stack_start = VirtAddr::from_ptr(&stack);
+        let stack_start = VirtAddr::from_ptr(Box::leak(stack));
         let stack_end = stack_start + IST_FRAME_SIZE;
-        printk!("irq stack @ {:?}, {:?}\n", stack_start, stack_end);
+        debug!("irq stack @ {:?}, {:?}", stack_start, stack_end);
         stack_end
     };
 
-    *TSS.lock() = Some(tss);
-
-    let tss_ref = unsafe {
-        // We know that the TSS will last forever...
-        &*(TSS.lock().as_ref().unwrap() as *const TaskStateSegment)
-    };
+    tss
+}
 
-    // Initalize GDT
-    let mut selectors = SELECTORS.lock();
+/// Create a GDT for the current core with the given TSS, recording the selectors in `selectors`.
+/// Every core's GDT has the same layout, so the selectors are the same on every core.
+fn make_gdt(tss: &'static TaskStateSegment, selectors: &mut Selectors) -> GlobalDescriptorTable {
+    let mut gdt = GlobalDescriptorTable::new();
 
     // NOTE: kernel CS must be the one before kernel SS
     selectors.kernel_cs = gdt.add_entry(Descriptor::kernel_code_segment());
@@ -126,9 +131,27 @@ pub fn init() {
             .bits(),
     ));
 
-    selectors.tss = gdt.add_entry(Descriptor::tss_segment(tss_ref));
+    selectors.tss = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    gdt
+}
+
+/// Initialize interrupts (and exceptions).
+pub fn init() {
+    let mut idt = InterruptDescriptorTable::new();
+
+    // Create TSS (but don't load yet).
+    *TSS.lock() = Some(make_tss());
 
-    *GDT.lock() = Some(gdt);
+    let tss_ref = unsafe {
+        // We know that the TSS will last forever...
+        &*(TSS.lock().as_ref().unwrap() as *const TaskStateSegment)
+    };
+
+    // Initalize GDT
+    let mut selectors = SELECTORS.lock();
+
+    *GDT.lock() = Some(make_gdt(tss_ref, &mut selectors));
 
     // Load the GDT and TSS
     let gdt_ref = unsafe {
@@ -150,6 +173,8 @@ pub fn init() {
 
     unsafe {
         pic::init_irqs(&mut idt);
+        lapic::init_irqs(&mut idt);
+        crate::gdb::init_irqs(&mut idt);
 
         crate::memory::init_pf_handler(&mut idt);
 
@@ -179,11 +204,55 @@ pub fn init() {
     };
     idt_ref.load();
 
-    // Initialize the Programmable Interrupt Controler
+    // Initialize the Programmable Interrupt Controler, which masks it
     pic::init();
 
-    // Initialize the Programmable Interrupt Timer
-    pit::init();
+    // Initialize this core's local APIC and its timer
+    lapic::init();
+    lapic::init_timer(pic::TIMER_VECTOR);
+
+    // Route ISA interrupts to this core through the I/O APIC.
+    ioapic::init();
+    for &irq in ISA_IRQS {
+        let (gsi, trigger, polarity) = ioapic::isa_irq_to_gsi(irq);
+        ioapic::route(gsi, trigger, polarity, pic::FIRST_IDT + irq, lapic::id());
+    }
+
+    // And the interrupts of the PCI devices that have drivers, which are level-triggered.
+    for irq in crate::pci::irqs() {
+        let (gsi, trigger, polarity) = ioapic::pci_irq_to_gsi(irq);
+        ioapic::route(gsi, trigger, polarity, pic::FIRST_IDT + irq, lapic::id());
+    }
+}
+
+/// Initialize interrupts on an application processor: give it its own GDT, TSS, and IST stacks,
+/// and load the shared IDT. The BSP must already have called `init`.
+pub fn init_ap() {
+    let tss: &'static TaskStateSegment = Box::leak(box make_tss());
+
+    let mut selectors = Selectors {
+        kernel_cs: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+        kernel_ss: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+        user_cs: SegmentSelector::new(0, PrivilegeLevel::Ring3),
+        user_ss: SegmentSelector::new(0, PrivilegeLevel::Ring3),
+        tss: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+    };
+    let gdt: &'static GlobalDescriptorTable = Box::leak(box make_gdt(tss, &mut selectors));
+
+    gdt.load();
+    unsafe {
+        set_cs(selectors.kernel_cs);
+        load_tss(selectors.tss);
+    }
+
+    let idt_ref = unsafe {
+        // We know that the IDT will last forever...
+        &*(IDT.lock().as_ref().unwrap() as *const InterruptDescriptorTable)
+    };
+    idt_ref.load();
+
+    lapic::enable();
+    lapic::init_timer(pic::TIMER_VECTOR);
 }
 
 /// Handle invalid opcode
@@ -218,6 +287,11 @@ extern "x86-interrupt" fn handle_nmi(esf: &mut InterruptStackFrame) {
 
 /// Handle a GPF fault
 extern "x86-interrupt" fn handle_gpf(esf: &mut InterruptStackFrame, error: u64) {
+    backtrace::set_exception(
+        esf.instruction_pointer.as_u64(),
+        backtrace::frame_pointer(),
+    );
+
     panic!(
         "General Protection Fault
             error: {:#x}
@@ -232,6 +306,11 @@ extern "x86-interrupt" fn handle_gpf(esf: &mut InterruptStackFrame, error: u64)
 
 /// Handle a double fault
 extern "x86-interrupt" fn handle_double_fault(esf: &mut InterruptStackFrame, error: u64) -> ! {
+    backtrace::set_exception(
+        esf.instruction_pointer.as_u64(),
+        backtrace::frame_pointer(),
+    );
+
     panic!(
         "Double Fault
             error: {:#x}
@@ -1,13 +1,16 @@
-//! A module for programmable interrupt controller
+//! A module for the IRQ handlers and the (legacy) programmable interrupt controller.
+//!
+//! The PIC is remapped and then masked completely; interrupts come from the I/O APIC and local
+//! APIC timer instead, on the same vectors.
 
 use x86_64::{
     instructions::{interrupts, port::Port},
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
-use crate::time;
+use crate::{sched::user::TrapFrame, smp, time};
 
-use super::IRQ_IST_FRAME_INDEX;
+use super::{lapic, IRQ_IST_FRAME_INDEX};
 
 //use super::idt64;
 
@@ -25,13 +28,19 @@ const D2: Port<u8> = Port::new(0xA1);
 
 /// The first entries of the IDT are reserved for traps and exceptions. So the first
 /// _interrupt_ is at vector 0x30.
-const FIRST_IDT: u8 = 0x30;
+pub const FIRST_IDT: u8 = 0x30;
+
+/// The vector of the timer interrupt (on every core).
+pub const TIMER_VECTOR: u8 = FIRST_IDT;
 
 /// Initialize some interrupt handlers
 pub unsafe fn init_irqs(idt: &mut InterruptDescriptorTable) {
     // Set up basic interrupts
+    //
+    // The timer interrupt may preempt user mode, so it needs all of the registers. It has its own
+    // entry stub rather than an `x86-interrupt` function. `set_handler_fn` only uses the address.
     idt[FIRST_IDT as usize]
-        .set_handler_fn(irq_0)
+        .set_handler_fn(core::mem::transmute(irq_0 as unsafe extern "C" fn()))
         .set_stack_index(IRQ_IST_FRAME_INDEX);
     idt[FIRST_IDT as usize + 0x1]
         .set_handler_fn(irq_1)
@@ -78,14 +87,10 @@ pub unsafe fn init_irqs(idt: &mut InterruptDescriptorTable) {
     idt[FIRST_IDT as usize + 0xf]
         .set_handler_fn(irq_f)
         .set_stack_index(IRQ_IST_FRAME_INDEX);
-
-    // Good for debugging
-    idt.breakpoint
-        .set_handler_fn(breakpoint_handler)
-        .set_stack_index(IRQ_IST_FRAME_INDEX);
 }
 
-/// Initialize the PIC, but leave interrupts disabled
+/// Initialize the PIC and then mask all of its interrupts. Even though we don't use it, it is
+/// remapped so that any spurious interrupts from it don't look like exceptions.
 pub fn init() {
     // Configure the PIC
     unsafe {
@@ -105,22 +110,16 @@ pub fn init() {
         D1.write(1); /* 8086 mode */
         D2.write(1); /* 8086 mode */
 
-        // enable all
-        D1.write(0);
-        D2.write(0);
+        // disable all
+        D1.write(0xFF);
+        D2.write(0xFF);
     };
 }
 
 /// End of interrupt: send the next irq, but interrupts still disabled
-fn pic_eoi(irq: u8) {
-    unsafe {
-        if irq >= 8 {
-            // let PIC2 know
-            C2.write(0x20);
-        }
-        // we always let PIC1 know because PIC2 is routed though PIC1
-        C1.write(0x20);
-    }
+fn pic_eoi(_irq: u8) {
+    // All interrupts come through the APICs now.
+    lapic::eoi();
 }
 
 /// IRQ handler
@@ -132,27 +131,37 @@ fn pic_eoi(irq: u8) {
 fn pic_irq(irq: usize, _: &mut InterruptStackFrame) {
     // execute handler
     match irq {
-        // PIT interrupts
-        0 => {
-            // tick the clock
-            time::tick();
-        }
+        // PIT interrupts are handled by `handle_timer_irq`.
 
         // Keyboard interrupts
         1 => {
             unsafe { crate::io::kbd::handler() };
         }
 
+        // Serial interrupts: COM2 is the GDB stub
+        3 => {
+            crate::gdb::handle_irq();
+            crate::io::serial::handle_irq(3);
+        }
+        4 => crate::io::serial::handle_irq(4),
+
+        // PS/2 mouse interrupts
+        12 => {
+            unsafe { crate::io::mouse::handler() };
+        }
+
         // Processor and FPU interrupts
         13 => {}
 
         // IDE interrupts
-        15 => {}
+        14 | 15 => crate::io::ata::handle_irq(irq as u8),
 
-        // Other (unknown) interrupts
+        // PCI interrupts (see `pci::set_handler`), or unknown ones
         _ => {
-            interrupts::disable();
-            panic!("unknown interrupt {}\n", irq)
+            if !crate::pci::handle_irq(irq as u8) {
+                interrupts::disable();
+                panic!("unknown interrupt {}\n", irq)
+            }
         }
     }
 
@@ -167,8 +176,72 @@ fn pic_irq(irq: usize, _: &mut InterruptStackFrame) {
 // hard work for them.
 ////////////////////////////////////////////////////////////////////////////////
 
-extern "x86-interrupt" fn irq_0(esf: &mut InterruptStackFrame) {
-    pic_irq(0, esf);
+/// Entry point for the timer interrupt. This saves all general-purpose registers in a `TrapFrame`
+/// so that a user continuation can be preempted and resumed later.
+#[naked]
+unsafe extern "C" fn irq_0() {
+    asm!(
+        "
+        pushq %rax
+        pushq %rbx
+        pushq %rcx
+        pushq %rdx
+        pushq %rdi
+        pushq %rsi
+        pushq %rbp
+        pushq %r8
+        pushq %r9
+        pushq %r10
+        pushq %r11
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+
+        # The hardware frame is 5 words and we pushed 15 more, so the stack is still 16B-aligned.
+        mov %rsp, %rdi
+        cld
+        call handle_timer_irq
+
+        popq %r15
+        popq %r14
+        popq %r13
+        popq %r12
+        popq %r11
+        popq %r10
+        popq %r9
+        popq %r8
+        popq %rbp
+        popq %rsi
+        popq %rdi
+        popq %rdx
+        popq %rcx
+        popq %rbx
+        popq %rax
+
+        iretq
+        "
+        : /* no outputs */
+        : /* no inputs */
+        : "memory"
+        : "volatile"
+    );
+
+    unreachable!();
+}
+
+/// Handle the timer interrupt. Called by `irq_0`. Every core has its own timer.
+#[no_mangle]
+extern "C" fn handle_timer_irq(frame: &mut TrapFrame) {
+    // tick the clock, but only on one core so that time passes at the same rate
+    if smp::is_bsp() {
+        time::tick();
+    }
+
+    // Acknowledge the interrupt first, since we might not return.
+    pic_eoi(0);
+
+    crate::sched::user::maybe_preempt(frame);
 }
 
 extern "x86-interrupt" fn irq_1(esf: &mut InterruptStackFrame) {
@@ -230,8 +303,3 @@ extern "x86-interrupt" fn irq_e(esf: &mut InterruptStackFrame) {
 extern "x86-interrupt" fn irq_f(esf: &mut InterruptStackFrame) {
     pic_irq(0xf, esf);
 }
-
-/// Handle a breakpoint exception
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
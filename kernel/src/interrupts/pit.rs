@@ -1,55 +1,52 @@
-//! A module for the programmable interrupt timer
+//! A module for the programmable interrupt timer.
+//!
+//! We don't use the PIT for interrupts anymore (the local APIC timer is the tick source), but it
+//! has a known frequency, so we use it to measure how fast the local APIC timer is.
 
-use x86_64::{
-    instructions::{interrupts, port::Port},
-    registers::rflags,
-};
+use x86_64::instructions::port::Port;
 
 /// Max frequency of the PIT
 const MAX_HZ: usize = 1_193_182;
 
-/// The frequency of the PIT
-pub const HZ: usize = 1000;
-
 /// The command port of the PIT
 const PIT_CMD: Port<u8> = Port::new(0x43);
 
-/// The data port of the PIT
-const PIT_DATA: Port<u8> = Port::new(0x40);
+/// The data port of channel 2 of the PIT
+const PIT_CH2_DATA: Port<u8> = Port::new(0x42);
 
-/// Initialize the PIT to the given frequency
-pub fn init() {
-    let divide = MAX_HZ / HZ;
+/// The keyboard controller port B, which controls the gate of channel 2 and lets us read its output
+const PORT_B: Port<u8> = Port::new(0x61);
 
-    if (divide & 0xffff) != divide {
-        panic!("PIT init divide={} doesn't fit in 16 bits", divide);
-    }
+/// Busy-wait for `ms` milliseconds, by polling channel 2 of the PIT. This doesn't need interrupts.
+pub fn sleep_polled(ms: usize) {
+    let count = MAX_HZ * ms / 1000;
 
-    printk!("pit inited - {} hz\n", HZ);
+    if (count & 0xffff) != count {
+        panic!("PIT count={} doesn't fit in 16 bits", count);
+    }
 
     unsafe {
-        // save flags
-        let saved_flags = rflags::read();
-
-        // disable interrupts
-        interrupts::disable();
+        // Enable the channel 2 gate, but not the speaker.
+        let port_b = PORT_B.read();
+        PORT_B.write((port_b & !0b10) | 0b1);
 
         // command
-        // 00 (channel 0)
-        // 110 (lobyte/hibyte)
-        // 100 (rate generator)
-        let cmd = 0b_0011_0100_u8;
+        // 10 (channel 2)
+        // 11 (lobyte/hibyte)
+        // 000 (interrupt on terminal count)
+        let cmd = 0b_1011_0000_u8;
 
         // write commmand
         PIT_CMD.write(cmd);
 
-        // Set the divide, one byte at a time
-        let first_byte = (divide & 0xFF) as u8;
-        let second_byte = ((divide & 0xFF00) >> 8) as u8;
-        PIT_DATA.write(first_byte);
-        PIT_DATA.write(second_byte);
+        // Set the count, one byte at a time. This starts the countdown.
+        PIT_CH2_DATA.write((count & 0xFF) as u8);
+        PIT_CH2_DATA.write(((count & 0xFF00) >> 8) as u8);
+
+        // Wait for the output to go high.
+        while PORT_B.read() & 0b10_0000 == 0 {}
 
-        // restore flags
-        rflags::write(saved_flags);
+        // Restore the gate.
+        PORT_B.write(port_b);
     }
 }
@@ -0,0 +1,670 @@
+//! An ATA/ATAPI driver for the PC's IDE controller, using PIO.
+//!
+//! The controller has two channels (primary and secondary), each with up to two drives (master
+//! and slave). We use the legacy ("compatibility mode") I/O ports and IRQs 14 and 15; controllers
+//! in PCI native mode aren't supported. The drives are found with IDENTIFY (or IDENTIFY PACKET
+//! for ATAPI drives, i.e. CD-ROMs) while probing, with interrupts off, and each one is registered
+//! as a block device (see `block`).
+//!
+//! Only one drive of a channel can be busy at a time, so requests are queued per channel. Data
+//! moves through the data port one sector at a time, and the drive interrupts whenever it has a
+//! sector for us or wants the next one, so after a command has been sent, the interrupt handler
+//! does all of the work and completes the request at the end. Disks are addressed with LBA28, or
+//! LBA48 when a request needs it and the drive supports it. Writes are followed by a cache flush.
+//!
+//! ATAPI drives are read-only, and are read with SCSI READ (12) commands sent as packets. The
+//! capacity of the medium is only read while probing, so changing CDs isn't noticed.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use core::cmp;
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    io::block::{self, BlockDevice, BlockError, Op, Request},
+    pci,
+};
+
+/// The IRQs of the primary and secondary channels.
+const IRQS: [u8; 2] = [14, 15];
+
+/// Command block registers (offsets from the first port)
+const REG_DATA: u16 = 0;
+const REG_FEATURES: u16 = 1; // when written
+const REG_COUNT: u16 = 2;
+const REG_LBA0: u16 = 3;
+const REG_LBA1: u16 = 4; // the byte count for ATAPI
+const REG_LBA2: u16 = 5; // the byte count for ATAPI
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7; // when read
+const REG_COMMAND: u16 = 7; // when written
+
+/// Status bits
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Device control: don't interrupt.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+/// Drive register bits. The obsolete bits 5 and 7 are always set.
+const DRIVE_BASE: u8 = 0xA0;
+const DRIVE_LBA: u8 = 1 << 6;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+/// Commands
+const CMD_READ_PIO: u8 = 0x20;
+const CMD_READ_PIO_EXT: u8 = 0x24;
+const CMD_WRITE_PIO: u8 = 0x30;
+const CMD_WRITE_PIO_EXT: u8 = 0x34;
+const CMD_PACKET: u8 = 0xA0;
+const CMD_IDENTIFY_PACKET: u8 = 0xA1;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// After IDENTIFY is aborted, LBA1 and LBA2 hold these if the drive is an ATAPI drive.
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+/// SCSI commands (for ATAPI packets)
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xA8;
+
+/// IDENTIFY words
+const ID_CAPABILITIES: usize = 49;
+const ID_LBA28_SECTORS: usize = 60;
+const ID_COMMAND_SETS: usize = 83;
+const ID_LBA48_SECTORS: usize = 100;
+
+/// Capabilities: LBA addressing is supported.
+const CAPABILITIES_LBA: u16 = 1 << 9;
+
+/// Command sets: LBA48 is supported.
+const COMMAND_SETS_LBA48: u16 = 1 << 10;
+
+/// The programming interface of an IDE controller has these bits set if the primary or secondary
+/// channel is in PCI native mode.
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+
+/// Sector sizes of ATA and ATAPI drives
+const ATA_SECTOR_SIZE: usize = 512;
+const ATAPI_SECTOR_SIZE: usize = 2048;
+
+/// The largest number of sectors in one command with LBA28 and LBA48.
+const LBA28_MAX_SECTORS: usize = 256;
+const LBA48_MAX_SECTORS: usize = 65536;
+
+/// The first sector that can't be addressed with LBA28.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How many times to poll the status before giving up on a drive (roughly a microsecond each).
+const POLL_LIMIT: usize = 1_000_000;
+
+/// A drive, as far as requests for it are concerned.
+#[derive(Copy, Clone)]
+struct Drive {
+    /// 0 for the primary channel, 1 for the secondary one.
+    channel: usize,
+    slave: bool,
+    atapi: bool,
+    lba48: bool,
+}
+
+/// A request in progress.
+struct Transfer {
+    drive: Drive,
+    request: Request,
+
+    /// How many bytes of the buffer have been moved.
+    done: usize,
+
+    /// Where the data of the current command ends in the buffer.
+    end: usize,
+
+    /// Has the cache flush been sent (i.e. has all of the data been written)?
+    flushing: bool,
+}
+
+/// An IDE channel.
+struct Channel {
+    /// The command block and control ports.
+    base: u16,
+    control: u16,
+
+    /// Requests waiting for the channel.
+    queue: Vec<(Drive, Request)>,
+
+    /// The request in progress.
+    current: Option<Transfer>,
+}
+
+/// The primary and secondary channels.
+static CHANNELS: [Mutex<Channel>; 2] = [
+    Mutex::new(Channel::new(0x1F0, 0x3F6)),
+    Mutex::new(Channel::new(0x170, 0x376)),
+];
+
+/// A disk or CD-ROM drive.
+struct Disk {
+    drive: Drive,
+    sectors: u64,
+    model: String,
+}
+
+impl Channel {
+    const fn new(base: u16, control: u16) -> Self {
+        Channel {
+            base,
+            control,
+            queue: Vec::new(),
+            current: None,
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        unsafe { Port::new(self.base + reg).read() }
+    }
+
+    fn write(&self, reg: u16, value: u8) {
+        unsafe { Port::new(self.base + reg).write(value) }
+    }
+
+    /// Read the status. This acknowledges the drive's interrupt.
+    fn status(&self) -> u8 {
+        self.read(REG_STATUS)
+    }
+
+    /// Read the status without acknowledging the interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    fn set_control(&self, value: u8) {
+        unsafe { Port::new(self.control).write(value) }
+    }
+
+    /// Select the master or slave drive, with the given extra bits in the drive register.
+    fn select(&self, slave: bool, bits: u8) {
+        self.write(
+            REG_DRIVE,
+            DRIVE_BASE | if slave { DRIVE_SLAVE } else { 0 } | bits,
+        );
+
+        // The drive takes 400ns to put its status up; each read takes about 100ns.
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    /// Wait for the selected drive to stop being busy. Returns its status.
+    fn wait_idle(&self) -> Result<u8, ()> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+
+        Err(())
+    }
+
+    /// Wait for the selected drive to be ready to move data.
+    fn wait_data(&self) -> Result<(), ()> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(());
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+
+        Err(())
+    }
+
+    /// Read `buf.len()` bytes from the data port.
+    fn read_data(&self, buf: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks_mut(2) {
+            let value = unsafe { port.read() }.to_le_bytes();
+            word.copy_from_slice(&value[..word.len()]);
+        }
+    }
+
+    /// Write `buf` to the data port.
+    fn write_data(&self, buf: &[u8]) {
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks(2) {
+            let value = u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0)]);
+            unsafe { port.write(value) };
+        }
+    }
+
+    /// Send an ATAPI packet to the selected drive, which will transfer at most `limit` bytes
+    /// before each interrupt.
+    fn send_packet(&self, packet: &[u8; 12], limit: u16) -> Result<(), ()> {
+        self.wait_idle()?;
+        self.write(REG_FEATURES, 0); // PIO, not DMA
+        self.write(REG_LBA1, limit as u8);
+        self.write(REG_LBA2, (limit >> 8) as u8);
+        self.write(REG_COMMAND, CMD_PACKET);
+
+        self.wait_data()?;
+        self.write_data(packet);
+        Ok(())
+    }
+
+    /// Identify the master or slave drive of channel `channel`, by polling. Returns `None` if
+    /// there is no drive (or none that we can use).
+    fn identify(&self, channel: usize, slave: bool) -> Option<Disk> {
+        self.select(slave, 0);
+        self.write(REG_COUNT, 0);
+        self.write(REG_LBA0, 0);
+        self.write(REG_LBA1, 0);
+        self.write(REG_LBA2, 0);
+        self.write(REG_COMMAND, CMD_IDENTIFY);
+
+        // Nothing answers if there is no drive.
+        if self.alt_status() == 0 {
+            return None;
+        }
+        self.wait_idle().ok()?;
+
+        // ATAPI drives abort IDENTIFY, and leave their signature.
+        let signature = (self.read(REG_LBA1), self.read(REG_LBA2));
+        let atapi = signature == ATAPI_SIGNATURE;
+        if atapi {
+            self.write(REG_COMMAND, CMD_IDENTIFY_PACKET);
+        } else if signature != (0, 0) {
+            return None;
+        }
+
+        self.wait_data().ok()?;
+        let mut id = [0; 512];
+        self.read_data(&mut id);
+        self.status();
+
+        let word = |i: usize| u16::from_le_bytes([id[2 * i], id[2 * i + 1]]);
+
+        // The model is in words 27 to 46, with the first character of each pair in the high byte.
+        let model: Vec<u8> = id[54..94]
+            .chunks(2)
+            .flat_map(|pair| pair.iter().rev().cloned())
+            .collect();
+        let model: String = String::from_utf8_lossy(&model).trim().into();
+
+        let drive = Drive {
+            channel,
+            slave,
+            atapi,
+            lba48: !atapi && word(ID_COMMAND_SETS) & COMMAND_SETS_LBA48 != 0,
+        };
+
+        let sectors = if atapi {
+            self.capacity(slave).unwrap_or(0)
+        } else if word(ID_CAPABILITIES) & CAPABILITIES_LBA == 0 {
+            warn!("ata: {} only supports CHS addressing", model);
+            return None;
+        } else if drive.lba48 {
+            (0..4).fold(0, |sectors, i| {
+                sectors | (word(ID_LBA48_SECTORS + i) as u64) << (16 * i)
+            })
+        } else {
+            word(ID_LBA28_SECTORS) as u64 | (word(ID_LBA28_SECTORS + 1) as u64) << 16
+        };
+
+        Some(Disk {
+            drive,
+            sectors,
+            model,
+        })
+    }
+
+    /// Read the number of sectors of the medium in an ATAPI drive, by polling. Fails if there is
+    /// no medium.
+    fn capacity(&self, slave: bool) -> Result<u64, ()> {
+        let mut packet = [0; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+
+        // The first command after a reset fails with a "unit attention", so try twice.
+        for _ in 0..2 {
+            self.select(slave, 0);
+            let mut data = [0; 8];
+            let read = self.send_packet(&packet, data.len() as u16).and_then(|()| {
+                self.wait_data()?;
+                self.read_data(&mut data);
+                self.wait_idle()
+            });
+            self.status();
+
+            if read.is_ok() {
+                // The number of the last sector, then the sector size, both big-endian.
+                let last = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                return Ok(last as u64 + 1);
+            }
+        }
+
+        Err(())
+    }
+
+    /// Start the next queued request, if the channel is free. Requests that can't be started
+    /// fail.
+    fn start_next(&mut self) {
+        while self.current.is_none() && !self.queue.is_empty() {
+            let (drive, request) = self.queue.remove(0);
+            let mut transfer = Transfer {
+                drive,
+                request,
+                done: 0,
+                end: 0,
+                flushing: false,
+            };
+
+            match self.issue(&mut transfer) {
+                Ok(()) => self.current = Some(transfer),
+                Err(()) => block::complete(transfer.request, Err(BlockError::Io)),
+            }
+        }
+    }
+
+    /// Send the command for the rest of `transfer`, or as much of it as fits in one command.
+    fn issue(&self, transfer: &mut Transfer) -> Result<(), ()> {
+        let drive = transfer.drive;
+        let len = transfer.request.buffer.len();
+
+        if drive.atapi {
+            let lba = transfer.request.lba as u32;
+            let count = (len / ATAPI_SECTOR_SIZE) as u32;
+
+            let mut packet = [0; 12];
+            packet[0] = SCSI_READ_12;
+            packet[2..6].copy_from_slice(&lba.to_be_bytes());
+            packet[6..10].copy_from_slice(&count.to_be_bytes());
+
+            // One sector per interrupt.
+            self.select(drive.slave, 0);
+            transfer.end = len;
+            return self.send_packet(&packet, ATAPI_SECTOR_SIZE as u16);
+        }
+
+        let lba = transfer.request.lba + (transfer.done / ATA_SECTOR_SIZE) as u64;
+        let max = if drive.lba48 {
+            LBA48_MAX_SECTORS
+        } else {
+            LBA28_MAX_SECTORS
+        };
+        let count = cmp::min((len - transfer.done) / ATA_SECTOR_SIZE, max);
+        let ext = drive.lba48 && (lba + count as u64 > LBA28_LIMIT || count > LBA28_MAX_SECTORS);
+
+        // A count of 0 means the largest count (which is why `as u8` works).
+        if ext {
+            self.select(drive.slave, DRIVE_LBA);
+            self.wait_idle()?;
+
+            // The high bytes go first.
+            self.write(REG_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA0, (lba >> 24) as u8);
+            self.write(REG_LBA1, (lba >> 32) as u8);
+            self.write(REG_LBA2, (lba >> 40) as u8);
+        } else {
+            self.select(drive.slave, DRIVE_LBA | ((lba >> 24) as u8 & 0x0F));
+            self.wait_idle()?;
+        }
+        self.write(REG_COUNT, count as u8);
+        self.write(REG_LBA0, lba as u8);
+        self.write(REG_LBA1, (lba >> 8) as u8);
+        self.write(REG_LBA2, (lba >> 16) as u8);
+
+        transfer.end = transfer.done + count * ATA_SECTOR_SIZE;
+
+        match (transfer.request.op, ext) {
+            (Op::Read, false) => self.write(REG_COMMAND, CMD_READ_PIO),
+            (Op::Read, true) => self.write(REG_COMMAND, CMD_READ_PIO_EXT),
+            (Op::Write, false) => self.write(REG_COMMAND, CMD_WRITE_PIO),
+            (Op::Write, true) => self.write(REG_COMMAND, CMD_WRITE_PIO_EXT),
+        }
+
+        // The drive doesn't interrupt for the first sector of a write.
+        if transfer.request.op == Op::Write {
+            self.wait_data()?;
+            self.write_next(transfer);
+        }
+
+        Ok(())
+    }
+
+    /// Read the next sector of `transfer`.
+    fn read_next(&self, transfer: &mut Transfer) {
+        let done = transfer.done;
+        self.read_data(&mut transfer.request.buffer[done..done + ATA_SECTOR_SIZE]);
+        transfer.done += ATA_SECTOR_SIZE;
+    }
+
+    /// Write the next sector of `transfer`.
+    fn write_next(&self, transfer: &mut Transfer) {
+        let done = transfer.done;
+        self.write_data(&transfer.request.buffer[done..done + ATA_SECTOR_SIZE]);
+        transfer.done += ATA_SECTOR_SIZE;
+    }
+
+    /// Move `transfer` along after the drive interrupted with the given status. Returns whether
+    /// it's finished.
+    fn advance(&self, transfer: &mut Transfer, status: u8) -> Result<bool, ()> {
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(());
+        }
+
+        if transfer.drive.atapi {
+            // Without DRQ, the command is over.
+            if status & STATUS_DRQ == 0 {
+                return if transfer.done == transfer.end {
+                    Ok(true)
+                } else {
+                    Err(())
+                };
+            }
+
+            let count = self.read(REG_LBA1) as usize | (self.read(REG_LBA2) as usize) << 8;
+            let done = transfer.done;
+            if count == 0 || done + count > transfer.end {
+                return Err(());
+            }
+            self.read_data(&mut transfer.request.buffer[done..done + count]);
+            transfer.done += count;
+            return Ok(false);
+        }
+
+        if transfer.flushing {
+            return Ok(true);
+        }
+
+        match transfer.request.op {
+            Op::Read => {
+                if status & STATUS_DRQ == 0 {
+                    return Err(());
+                }
+                self.read_next(transfer);
+            }
+
+            // The interrupt says the last sector we wrote has been taken.
+            Op::Write if transfer.done < transfer.end => {
+                if status & STATUS_DRQ == 0 {
+                    return Err(());
+                }
+                self.write_next(transfer);
+                return Ok(false);
+            }
+            Op::Write => {}
+        }
+
+        if transfer.done < transfer.end {
+            Ok(false)
+        } else if transfer.done < transfer.request.buffer.len() {
+            self.issue(transfer)?;
+            Ok(false)
+        } else if transfer.request.op == Op::Write {
+            self.write(
+                REG_COMMAND,
+                if transfer.drive.lba48 {
+                    CMD_CACHE_FLUSH_EXT
+                } else {
+                    CMD_CACHE_FLUSH
+                },
+            );
+            transfer.flushing = true;
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    fn handle_irq(&mut self) {
+        // Reading the status acknowledges the interrupt.
+        let status = self.status();
+        if status & STATUS_BSY != 0 {
+            return;
+        }
+
+        let mut transfer = match self.current.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+
+        match self.advance(&mut transfer, status) {
+            Ok(false) => self.current = Some(transfer),
+            Ok(true) => block::complete(transfer.request, Ok(())),
+            Err(()) => {
+                warn!(
+                    "ata: request for sector {} failed (status {:#x})",
+                    transfer.request.lba, status
+                );
+                block::complete(transfer.request, Err(BlockError::Io));
+            }
+        }
+
+        self.start_next();
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_size(&self) -> usize {
+        if self.drive.atapi {
+            ATAPI_SECTOR_SIZE
+        } else {
+            ATA_SECTOR_SIZE
+        }
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn writable(&self) -> bool {
+        !self.drive.atapi
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn start(&self, request: Request) {
+        without_interrupts(|| {
+            let mut channel = CHANNELS[self.drive.channel].lock();
+            channel.queue.push((self.drive, request));
+            channel.start_next();
+        });
+    }
+}
+
+/// Find the drives of an IDE controller (see `pci::DRIVERS`), and register them as block
+/// devices.
+pub fn probe(dev: &pci::Device) -> Result<(), &'static str> {
+    if dev.prog_if & (PROG_IF_PRIMARY_NATIVE | PROG_IF_SECONDARY_NATIVE) != 0 {
+        return Err("PCI native mode is not supported");
+    }
+    pci::enable(dev);
+
+    let mut found = 0;
+    for (i, channel) in CHANNELS.iter().enumerate() {
+        let channel = channel.lock();
+
+        // A floating bus means there is no channel.
+        if channel.alt_status() == 0xFF {
+            continue;
+        }
+
+        channel.set_control(CONTROL_NIEN);
+        for &slave in &[false, true] {
+            if let Some(disk) = channel.identify(i, slave) {
+                let name = format!("ata{}", 2 * i + slave as usize);
+                info!(
+                    "{}: {} ({}, {} sectors)",
+                    name,
+                    disk.model,
+                    if disk.drive.atapi { "ATAPI" } else { "ATA" },
+                    disk.sectors
+                );
+                block::register(name, Arc::new(disk));
+                found += 1;
+            }
+        }
+        channel.set_control(0);
+    }
+
+    if found == 0 {
+        Err("no drives")
+    } else {
+        Ok(())
+    }
+}
+
+/// Handle IRQ 14 or 15.
+pub fn handle_irq(irq: u8) {
+    for (channel, &line) in CHANNELS.iter().zip(IRQS.iter()) {
+        if line == irq {
+            channel.lock().handle_irq();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use crate::io::block;
+
+    // QEMU boots us from the primary master.
+    kernel_test!(ata_reads_boot_sector, 5, async {
+        let disk = block::find("ata0").expect("no boot disk");
+        let sector = block::read(disk, 0, 1).await.unwrap();
+        assert_eq!(sector[510..], [0x55, 0xAA]);
+    });
+
+    // This writes to the scratch disk on the secondary master (see `test-args` in Cargo.toml).
+    kernel_test!(ata_writes_sectors, 5, async {
+        let disk = block::find("ata2").expect("no scratch disk");
+        let last = block::get(disk).unwrap().sectors() - 2;
+
+        let data: Vec<u8> = (0..2 * 512).map(|i| (i % 251) as u8).collect();
+        let data = block::write(disk, last, data).await.unwrap();
+        assert_eq!(block::read(disk, last, 2).await.unwrap(), data);
+
+        assert_eq!(
+            block::read(disk, last + 1, 2).await,
+            Err(block::BlockError::OutOfRange)
+        );
+        assert_eq!(
+            block::write(disk, 0, vec![0; 100]).await,
+            Err(block::BlockError::BadBuffer)
+        );
+    });
+}
@@ -0,0 +1,242 @@
+//! Block devices (disks).
+//!
+//! Drivers register every disk they find with `register`. A request to read or write some sectors
+//! is handed to the disk's driver by `submit`, and the driver calls `complete` when it's done,
+//! usually from its interrupt handler. The result is kept until a continuation waiting for
+//! `EventKind::Block` with the request's ID takes it (see `task::block`), so nobody has to
+//! busy-wait for a disk. `finish` waits for a request and gives its buffer back (or throws the
+//! result away if the waiting task is cancelled), and `read` and `write` wrap all of this up.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{continuation::Event, task};
+
+/// A disk, as seen by its driver.
+pub trait BlockDevice: Send + Sync {
+    /// The size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// The number of sectors.
+    fn sectors(&self) -> u64;
+
+    /// Can the disk be written to?
+    fn writable(&self) -> bool;
+
+    /// What the disk says it is.
+    fn model(&self) -> &str;
+
+    /// Start `request`, and call `complete` with it when it's done (possibly before returning).
+    /// Requests are checked before they get here: the buffer is a non-zero number of whole
+    /// sectors, and all of them are on the disk.
+    fn start(&self, request: Request);
+}
+
+/// What a request does.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// Read sectors into the buffer.
+    Read,
+
+    /// Write the buffer to the sectors.
+    Write,
+}
+
+/// A request to read or write consecutive sectors.
+pub struct Request {
+    /// Identifies the request in `EventKind::Block`.
+    pub id: u64,
+
+    pub op: Op,
+
+    /// The first sector.
+    pub lba: u64,
+
+    /// The data, which covers a whole number of sectors.
+    pub buffer: Vec<u8>,
+}
+
+/// The ways a request can fail.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlockError {
+    /// There is no disk with that number.
+    NoDevice,
+
+    /// The buffer isn't a non-zero number of whole sectors.
+    BadBuffer,
+
+    /// The sectors are (partly) past the end of the disk.
+    OutOfRange,
+
+    /// The disk can't be written to.
+    ReadOnly,
+
+    /// The disk reported an error, or didn't answer.
+    Io,
+}
+
+/// A registered disk.
+struct Disk {
+    name: String,
+    dev: Arc<dyn BlockDevice>,
+}
+
+/// All of the disks, numbered by their index.
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+
+/// Finished requests whose results haven't been taken yet: their IDs, results and buffers.
+static DONE: Mutex<Vec<(u64, Result<(), BlockError>, Vec<u8>)>> = Mutex::new(Vec::new());
+
+/// Requests whose waiters were cancelled before they finished. Their results are thrown away.
+static ABANDONED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// The ID of the next request.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Add a disk. Returns its number.
+pub fn register(name: String, dev: Arc<dyn BlockDevice>) -> usize {
+    let mut disks = DISKS.lock();
+    disks.push(Disk { name, dev });
+    disks.len() - 1
+}
+
+/// The disk with the given number.
+pub fn get(disk: usize) -> Option<Arc<dyn BlockDevice>> {
+    DISKS.lock().get(disk).map(|disk| disk.dev.clone())
+}
+
+/// The number of the disk with the given name.
+#[allow(dead_code)]
+pub fn find(name: &str) -> Option<usize> {
+    DISKS.lock().iter().position(|disk| disk.name == name)
+}
+
+/// Start a request for `disk`. Returns the request's ID, which is passed to `task::block` to wait
+/// for it.
+pub fn submit(disk: usize, op: Op, lba: u64, buffer: Vec<u8>) -> Result<u64, BlockError> {
+    let dev = get(disk).ok_or(BlockError::NoDevice)?;
+
+    let size = dev.sector_size();
+    if buffer.is_empty() || buffer.len() % size != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (buffer.len() / size) as u64;
+    if lba
+        .checked_add(count)
+        .map_or(true, |end| end > dev.sectors())
+    {
+        return Err(BlockError::OutOfRange);
+    }
+    if op == Op::Write && !dev.writable() {
+        return Err(BlockError::ReadOnly);
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    dev.start(Request {
+        id,
+        op,
+        lba,
+        buffer,
+    });
+
+    Ok(id)
+}
+
+/// Called by drivers when `request` is done.
+pub fn complete(request: Request, result: Result<(), BlockError>) {
+    without_interrupts(|| {
+        let mut done = DONE.lock();
+        let mut abandoned = ABANDONED.lock();
+        match abandoned.iter().position(|&id| id == request.id) {
+            Some(i) => {
+                abandoned.swap_remove(i);
+            }
+            None => done.push((request.id, result, request.buffer)),
+        }
+    });
+}
+
+/// The event for a continuation waiting for request `id`, if it's done.
+pub fn poll(id: u64) -> Option<Event> {
+    without_interrupts(|| {
+        DONE.lock()
+            .iter()
+            .find(|(done, _, _)| *done == id)
+            .map(|&(_, result, _)| Event::Block(result))
+    })
+}
+
+/// Wait for request `id` (see `submit`), and take back its buffer. Submitting several requests
+/// before finishing any of them lets the disk work on them at the same time.
+pub async fn finish(id: u64) -> Result<Vec<u8>, BlockError> {
+    // If the task is cancelled while it waits, this is dropped with it and forgets the request.
+    let waiter = Waiter(id);
+    let result = task::block(id).await;
+    mem::forget(waiter);
+
+    let buffer = without_interrupts(|| {
+        let mut done = DONE.lock();
+        let i = done.iter().position(|(done, _, _)| *done == id).unwrap();
+        done.swap_remove(i).2
+    });
+
+    result.map(|()| buffer)
+}
+
+/// Forgets request `id` when dropped: its result is thrown away if it is done, or as soon as it
+/// is.
+struct Waiter(u64);
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut done = DONE.lock();
+            match done.iter().position(|(done, _, _)| *done == self.0) {
+                Some(i) => {
+                    done.swap_remove(i);
+                }
+                None => ABANDONED.lock().push(self.0),
+            }
+        });
+    }
+}
+
+/// Read `count` sectors of `disk`, starting with sector `lba`.
+#[allow(dead_code)]
+pub async fn read(disk: usize, lba: u64, count: usize) -> Result<Vec<u8>, BlockError> {
+    let size = get(disk).ok_or(BlockError::NoDevice)?.sector_size();
+    let id = submit(disk, Op::Read, lba, vec![0; count * size])?;
+    finish(id).await
+}
+
+/// Write `buffer` to `disk`, starting with sector `lba`. The buffer is handed back afterwards.
+#[allow(dead_code)]
+pub async fn write(disk: usize, lba: u64, buffer: Vec<u8>) -> Result<Vec<u8>, BlockError> {
+    let id = submit(disk, Op::Write, lba, buffer)?;
+    finish(id).await
+}
+
+/// Print the list of disks.
+pub fn dump() {
+    for (i, disk) in DISKS.lock().iter().enumerate() {
+        let dev = &disk.dev;
+        printk!(
+            "{} {:6} {:>10} x {:4} ({} MiB){} {}\n",
+            i,
+            disk.name,
+            dev.sectors(),
+            dev.sector_size(),
+            (dev.sectors() * dev.sector_size() as u64) >> 20,
+            if dev.writable() { "" } else { " ro" },
+            dev.model()
+        );
+    }
+}
@@ -1,15 +1,40 @@
-//! For simplicity, we just buffer all keyboard input and let continuations waiting on keyboard
-//! events dequeue from the front. Not efficient and kind of weird, but keyboard handling is a bit
-//! boring IMHO, and just need something that works.
+//! The PS/2 keyboard.
+//!
+//! The keyboard sends scan code set 1 (the controller translates for us). Each key has a make code
+//! sent when it is pressed and a break code (the make code with bit 7 set) sent when it is
+//! released. Keys added after the original XT keyboard (arrows, Home/End, right Ctrl/Alt, ...) are
+//! prefixed with `0xE0`. We turn all of that into a `KeyCode` per key: the make code, with bit 7
+//! set for `0xE0` keys.
+//!
+//! The decoder keeps track of the modifier keys and the lock keys (whose state is shown on the
+//! keyboard's LEDs), and turns each scan code into a `KeyEvent`, which has the key code, whether
+//! the key was pressed or released, the modifiers, and the character the key produced (if any).
+//!
+//! Keyboard input goes to holders of a keyboard capability (see `open`), but only to the one with
+//! the input focus. The focus goes to the first one, and moves to the next one with Alt+F12 (or
+//! `focus`). Each holder gets input in one of two modes (see `Mode`): text, or raw key events,
+//! which have the key code, modifiers, and releases too. Input is buffered until a continuation
+//! waiting for `EventKind::Keyboard` with that capability takes it. Unless the focus wants raw
+//! events, typed text goes to the virtual terminal on screen instead if somebody owns it (see
+//! `vt`).
 
-use alloc::collections::linked_list::LinkedList;
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+
+use core::fmt;
 
 use spin::Mutex;
 
 use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
-/// The difference between a capital and lowercase
-const CAP: u8 = ('a' as u8) - ('A' as u8);
+use crate::{
+    cap::{Capability, ResourceHandle, UnregisteredResourceHandle},
+    continuation::Event,
+};
+
+use super::{
+    keymap::{self, Keymap, Sym},
+    vt,
+};
 
 /// Keyboard command port
 const KBD_CMD: Port<u8> = Port::new(0x64);
@@ -17,94 +42,843 @@ const KBD_CMD: Port<u8> = Port::new(0x64);
 /// Keyboard data port
 const KBD_DATA: Port<u8> = Port::new(0x60);
 
-/// Buffered keyboard input.
-static KBD_BUFFER: Mutex<Option<LinkedList<u8>>> = Mutex::new(None);
+/// Status register: there is a byte to read from the data port.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+
+/// Status register: the controller hasn't taken the last byte we wrote yet.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// Status register: the byte to read is from the mouse (see `mouse`).
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// Keyboard command: set the LEDs (followed by the LED bits).
+const CMD_SET_LEDS: u8 = 0xED;
+
+/// Keyboard responses
+const RESP_ACK: u8 = 0xFA;
+const RESP_RESEND: u8 = 0xFE;
+const RESP_ERROR: u8 = 0xFF;
+const RESP_OVERRUN: u8 = 0x00;
+
+/// LED bits for `CMD_SET_LEDS`
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Scan code prefixes
+const PREFIX_EXTENDED: u8 = 0xE0;
+const PREFIX_PAUSE: u8 = 0xE1;
+
+/// The number of bytes after `PREFIX_PAUSE` in the Pause key's scan code (`E1 1D 45 E1 9D C5`).
+const PAUSE_LEN: u8 = 5;
+
+/// The most key events we buffer per consumer. Older events are dropped if nobody reads them.
+const MAX_EVENTS: usize = 128;
+
+/// With Alt, moves the input focus to the next consumer.
+const FOCUS_HOTKEY: KeyCode = KeyCode::F12;
+
+/// Identifies a key: its set 1 make code, with bit 7 set if it has the `0xE0` prefix.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct KeyCode(pub u8);
+
+#[allow(dead_code)]
+impl KeyCode {
+    pub const ESCAPE: KeyCode = KeyCode(0x01);
+    pub const BACKSPACE: KeyCode = KeyCode(0x0E);
+    pub const TAB: KeyCode = KeyCode(0x0F);
+    pub const ENTER: KeyCode = KeyCode(0x1C);
+    pub const LEFT_CTRL: KeyCode = KeyCode(0x1D);
+    pub const LEFT_SHIFT: KeyCode = KeyCode(0x2A);
+    pub const RIGHT_SHIFT: KeyCode = KeyCode(0x36);
+    pub const KEYPAD_STAR: KeyCode = KeyCode(0x37);
+    pub const LEFT_ALT: KeyCode = KeyCode(0x38);
+    pub const SPACE: KeyCode = KeyCode(0x39);
+    pub const CAPS_LOCK: KeyCode = KeyCode(0x3A);
+    pub const F1: KeyCode = KeyCode(0x3B);
+    pub const F2: KeyCode = KeyCode(0x3C);
+    pub const F3: KeyCode = KeyCode(0x3D);
+    pub const F4: KeyCode = KeyCode(0x3E);
+    pub const F5: KeyCode = KeyCode(0x3F);
+    pub const F6: KeyCode = KeyCode(0x40);
+    pub const F7: KeyCode = KeyCode(0x41);
+    pub const F8: KeyCode = KeyCode(0x42);
+    pub const F9: KeyCode = KeyCode(0x43);
+    pub const F10: KeyCode = KeyCode(0x44);
+    pub const NUM_LOCK: KeyCode = KeyCode(0x45);
+    pub const SCROLL_LOCK: KeyCode = KeyCode(0x46);
+    pub const KEYPAD_7: KeyCode = KeyCode(0x47);
+    pub const KEYPAD_DOT: KeyCode = KeyCode(0x53);
+    pub const F11: KeyCode = KeyCode(0x57);
+    pub const F12: KeyCode = KeyCode(0x58);
+
+    pub const KEYPAD_ENTER: KeyCode = KeyCode(0x80 | 0x1C);
+    pub const RIGHT_CTRL: KeyCode = KeyCode(0x80 | 0x1D);
+    pub const KEYPAD_SLASH: KeyCode = KeyCode(0x80 | 0x35);
+    pub const PRINT_SCREEN: KeyCode = KeyCode(0x80 | 0x37);
+    /// Right Alt, which is AltGr on many layouts.
+    pub const RIGHT_ALT: KeyCode = KeyCode(0x80 | 0x38);
+    pub const HOME: KeyCode = KeyCode(0x80 | 0x47);
+    pub const UP: KeyCode = KeyCode(0x80 | 0x48);
+    pub const PAGE_UP: KeyCode = KeyCode(0x80 | 0x49);
+    pub const LEFT: KeyCode = KeyCode(0x80 | 0x4B);
+    pub const RIGHT: KeyCode = KeyCode(0x80 | 0x4D);
+    pub const END: KeyCode = KeyCode(0x80 | 0x4F);
+    pub const DOWN: KeyCode = KeyCode(0x80 | 0x50);
+    pub const PAGE_DOWN: KeyCode = KeyCode(0x80 | 0x51);
+    pub const INSERT: KeyCode = KeyCode(0x80 | 0x52);
+    pub const DELETE: KeyCode = KeyCode(0x80 | 0x53);
+    pub const LEFT_GUI: KeyCode = KeyCode(0x80 | 0x5B);
+    pub const RIGHT_GUI: KeyCode = KeyCode(0x80 | 0x5C);
+    pub const MENU: KeyCode = KeyCode(0x80 | 0x5D);
+
+    /// The Pause key, which has its own special scan code.
+    pub const PAUSE: KeyCode = KeyCode(0xFF);
+}
+
+/// The state of the modifier and lock keys.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    /// Right Alt
+    pub altgr: bool,
+
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// A key being pressed or released.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+
+    /// Pressed (or repeated, if the key is held down), or released?
+    pub pressed: bool,
+
+    /// The modifiers after this event.
+    pub modifiers: Modifiers,
+
+    /// The text the key produced, translated with the current keymap. This is empty for released
+    /// keys, and for keys that don't produce characters (including dead keys).
+    pub text: Text,
+}
+
+/// The text produced by a key press, as UTF-8. This is one character, or two if the key follows a
+/// dead key whose accent doesn't go on the key's character (e.g. `^` then `x` gives `^x`).
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Text {
+    bytes: [u8; 8],
+    len: usize,
+}
+
+impl Text {
+    const fn new() -> Self {
+        Text {
+            bytes: [0; 8],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        self.len += c.encode_utf8(&mut self.bytes[self.len..]).len();
+    }
+
+    pub fn as_str(&self) -> &str {
+        // We only ever push whole characters.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// The characters produced by the keypad (`KEYPAD_7` to `KEYPAD_DOT`) with Num Lock on. Without
+/// Num Lock, the digits are navigation keys, which don't produce characters.
+const KEYPAD_KEYS: &[u8; 13] = b"789-456+1230.";
+
+/// Turns scan codes into key events.
+struct Decoder {
+    /// Did we just get `PREFIX_EXTENDED`?
+    extended: bool,
+
+    /// The number of bytes of the Pause key's scan code left to skip.
+    pause: u8,
+
+    /// Which modifier keys are held down (left and right separately).
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    left_alt: bool,
+    right_alt: bool,
+
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+
+    /// The keymap used to translate keys.
+    keymap: &'static Keymap,
+
+    /// The accent of the dead key that was just pressed, if any.
+    dead: Option<char>,
+}
+
+impl Decoder {
+    const fn new(keymap: &'static Keymap) -> Self {
+        Decoder {
+            extended: false,
+            pause: 0,
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+            keymap,
+            dead: None,
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.left_shift || self.right_shift,
+            ctrl: self.left_ctrl || self.right_ctrl,
+            alt: self.left_alt,
+            altgr: self.right_alt,
+            caps_lock: self.caps_lock,
+            num_lock: self.num_lock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+
+    /// The LED bits for the lock keys.
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+
+    /// Decode the next byte from the keyboard. Returns the key event, if the byte completes one.
+    fn decode(&mut self, b: u8) -> Option<KeyEvent> {
+        if self.pause > 0 {
+            self.pause -= 1;
+            return None;
+        }
+
+        match b {
+            PREFIX_EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+
+            // Pause only has a make code, so we report it as pressed and released at once.
+            PREFIX_PAUSE => {
+                self.pause = PAUSE_LEN;
+                return Some(KeyEvent {
+                    code: KeyCode::PAUSE,
+                    pressed: true,
+                    modifiers: self.modifiers(),
+                    text: Text::new(),
+                });
+            }
+
+            _ => {}
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = b & 0x80 == 0;
+        let code = KeyCode((b & 0x7F) | if extended { 0x80 } else { 0 });
+
+        // The keyboard sends fake shifts around some extended keys (e.g. Print Screen is
+        // `E0 2A E0 37`), which we don't want to see as Shift.
+        if extended && (b & 0x7F == 0x2A || b & 0x7F == 0x36) {
+            return None;
+        }
+
+        match code {
+            KeyCode::LEFT_SHIFT => self.left_shift = pressed,
+            KeyCode::RIGHT_SHIFT => self.right_shift = pressed,
+            KeyCode::LEFT_CTRL => self.left_ctrl = pressed,
+            KeyCode::RIGHT_CTRL => self.right_ctrl = pressed,
+            KeyCode::LEFT_ALT => self.left_alt = pressed,
+            KeyCode::RIGHT_ALT => self.right_alt = pressed,
+
+            // Lock keys toggle when pressed (but not when repeated; that's close enough, since
+            // repeats are rare for lock keys and the LEDs will show it).
+            KeyCode::CAPS_LOCK if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NUM_LOCK if pressed => self.num_lock = !self.num_lock,
+            KeyCode::SCROLL_LOCK if pressed => self.scroll_lock = !self.scroll_lock,
+
+            _ => {}
+        }
+
+        let modifiers = self.modifiers();
+        let text = if pressed {
+            self.translate(code, modifiers)
+        } else {
+            Text::new()
+        };
+
+        Some(KeyEvent {
+            code,
+            pressed,
+            modifiers,
+            text,
+        })
+    }
+
+    /// The text produced by pressing `code` with `modifiers`.
+    fn translate(&mut self, code: KeyCode, modifiers: Modifiers) -> Text {
+        let mut text = Text::new();
+
+        // Keys that are the same on every layout
+        let sym = match code {
+            KeyCode::ESCAPE => Sym::Char('\x1B'),
+            KeyCode::BACKSPACE => Sym::Char('\x08'),
+            KeyCode::TAB => Sym::Char('\t'),
+            KeyCode::ENTER | KeyCode::KEYPAD_ENTER => Sym::Char('\n'),
+            KeyCode::SPACE => Sym::Char(' '),
+            KeyCode::KEYPAD_STAR => Sym::Char('*'),
+            KeyCode::KEYPAD_SLASH => Sym::Char('/'),
+
+            KeyCode(n) if n >= KeyCode::KEYPAD_7.0 && n <= KeyCode::KEYPAD_DOT.0 => {
+                let c = KEYPAD_KEYS[(n - KeyCode::KEYPAD_7.0) as usize];
+                if modifiers.num_lock || c == b'-' || c == b'+' {
+                    Sym::Char(c as char)
+                } else {
+                    return text;
+                }
+            }
+
+            _ => {
+                // Caps Lock only affects letters, and Shift undoes it.
+                let letter = match self.keymap.lookup(code, false, false) {
+                    Some(Sym::Char(c)) => c.is_alphabetic(),
+                    _ => false,
+                };
+                let shift = modifiers.shift != (letter && modifiers.caps_lock);
+
+                match self.keymap.lookup(code, shift, modifiers.altgr) {
+                    Some(sym) => sym,
+                    None => return text,
+                }
+            }
+        };
+
+        match sym {
+            Sym::Dead(accent) => match self.dead.take() {
+                // Pressing a dead key twice gives the accent.
+                Some(pending) if pending == accent => text.push(accent),
+                Some(pending) => {
+                    text.push(pending);
+                    self.dead = Some(accent);
+                }
+                None => self.dead = Some(accent),
+            },
+
+            // Ctrl+letter produces the corresponding control character, as on a terminal.
+            Sym::Char(c) if modifiers.ctrl && c.is_ascii_alphabetic() => {
+                self.dead = None;
+                text.push((c.to_ascii_lowercase() as u8 - b'a' + 1) as char);
+            }
+
+            Sym::Char(c) => match self
+                .dead
+                .take()
+                .map(|accent| (accent, keymap::compose(accent, c)))
+            {
+                Some((_, Some(composed))) => text.push(composed),
+
+                // A dead key followed by space gives the accent.
+                Some((accent, None)) if c == ' ' => text.push(accent),
+
+                Some((accent, None)) => {
+                    text.push(accent);
+                    text.push(c);
+                }
+
+                None => text.push(c),
+            },
+        }
+
+        text
+    }
+}
+
+/// The state of the keyboard.
+struct Keyboard {
+    decoder: Decoder,
+
+    /// The last command byte sent to the keyboard, in case it asks us to resend it.
+    last_sent: Option<u8>,
+
+    /// The LED bits to send once the keyboard acknowledges `CMD_SET_LEDS`.
+    pending_leds: Option<u8>,
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+    decoder: Decoder::new(&keymap::US),
+    last_sent: None,
+    pending_leds: None,
+});
+
+/// A holder of a keyboard capability.
+struct Consumer {
+    kbd: ResourceHandle,
+    mode: Mode,
+
+    /// Text received while this consumer had the focus, not read yet (UTF-8).
+    text: VecDeque<u8>,
+
+    /// Key events received while this consumer had the focus, not read yet.
+    events: VecDeque<KeyEvent>,
+}
+
+/// Everyone that wants keyboard input.
+struct Consumers {
+    /// In the order they were opened.
+    list: Vec<Consumer>,
+
+    /// The index of the one with the input focus, if any.
+    focus: Option<usize>,
+}
+
+impl Consumers {
+    fn get(&mut self, kbd: ResourceHandle) -> Option<&mut Consumer> {
+        self.list.iter_mut().find(|consumer| consumer.kbd == kbd)
+    }
+
+    fn focused(&mut self) -> Option<&mut Consumer> {
+        let focus = self.focus?;
+        self.list.get_mut(focus)
+    }
+}
+
+static CONSUMERS: Mutex<Consumers> = Mutex::new(Consumers {
+    list: Vec::new(),
+    focus: None,
+});
 
-/// Is this character capital? Safe because we really don't care too much...
-static mut SHIFT: bool = false;
+/// How a keyboard consumer gets its input.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// The text typed, one byte (of UTF-8) per `Event::Keyboard`.
+    Text,
+
+    /// Every key press and release, with the modifiers, as `Event::Key`.
+    Raw,
+}
+
+/// Send a byte to the keyboard.
+fn send(kbd: &mut Keyboard, b: u8) {
+    unsafe {
+        // Don't wait forever if there is no controller.
+        for _ in 0..100_000 {
+            if KBD_CMD.read() & STATUS_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        KBD_DATA.write(b);
+    }
+    kbd.last_sent = Some(b);
+}
+
+/// Start updating the LEDs. The LED bits are sent once the keyboard acknowledges the command (see
+/// `handler`).
+fn update_leds(kbd: &mut Keyboard) {
+    kbd.pending_leds = Some(kbd.decoder.leds());
+    send(kbd, CMD_SET_LEDS);
+}
 
 /// The keyboard interrupt handler
 ///
-/// Get a character from the keyboard and place it in the buffer.
+/// Get a byte from the keyboard, decode it, and give the resulting key event to whoever has the
+/// input focus. This should be called exactly once after a keyboard interrupt and nowhere else.
 pub unsafe fn handler() {
-    if let Some(key) = read() {
-        KBD_BUFFER.lock().as_mut().unwrap().push_back(key);
+    let status = KBD_CMD.read();
+    if status & STATUS_OUTPUT_FULL == 0 || status & STATUS_AUX_DATA != 0 {
+        return;
+    }
+    let b = KBD_DATA.read();
+
+    let mut kbd = KEYBOARD.lock();
+
+    // Responses to our commands
+    match b {
+        RESP_ACK => {
+            kbd.last_sent = None;
+            if let Some(leds) = kbd.pending_leds.take() {
+                send(&mut kbd, leds);
+            }
+            return;
+        }
+        RESP_RESEND => {
+            if let Some(last) = kbd.last_sent {
+                send(&mut kbd, last);
+            }
+            return;
+        }
+        RESP_ERROR | RESP_OVERRUN => return,
+        _ => {}
+    }
+
+    let event = match kbd.decoder.decode(b) {
+        Some(event) => event,
+        None => return,
+    };
+
+    let lock_key = event.code == KeyCode::CAPS_LOCK
+        || event.code == KeyCode::NUM_LOCK
+        || event.code == KeyCode::SCROLL_LOCK;
+    if lock_key && event.pressed {
+        update_leds(&mut kbd);
+    }
+
+    drop(kbd);
+
+    {
+        let mut consumers = CONSUMERS.lock();
+
+        // The focus hotkey is never delivered.
+        if event.code == FOCUS_HOTKEY && event.modifiers.alt {
+            if event.pressed {
+                cycle_focus(&mut consumers);
+            }
+            return;
+        }
+
+        // Raw key events go to the focus even if a terminal would want them.
+        if let Some(consumer) = consumers.focused() {
+            if consumer.mode == Mode::Raw {
+                if consumer.events.len() == MAX_EVENTS {
+                    consumer.events.pop_front();
+                }
+                consumer.events.push_back(event);
+                return;
+            }
+        }
     }
+
+    if vt::handle_key(&event) {
+        return;
+    }
+
+    give_text(event.text.as_str().as_bytes());
+}
+
+/// Give the focus to the next consumer (in the order they were opened).
+fn cycle_focus(consumers: &mut Consumers) {
+    let n = consumers.list.len();
+    consumers.focus = match consumers.focus {
+        _ if n == 0 => None,
+        Some(focus) => Some((focus + 1) % n),
+        None => Some(0),
+    };
 }
 
-/// Determine if this character is capital or not
-unsafe fn ul(c: u8) -> u8 {
-    if SHIFT {
-        c - CAP
-    } else {
-        c
+/// Give `text` to whoever has the input focus, if they want text. This should be called with
+/// interrupts off.
+fn give_text(text: &[u8]) {
+    if let Some(consumer) = CONSUMERS.lock().focused() {
+        if consumer.mode == Mode::Text {
+            consumer.text.extend(text.iter().cloned());
+        }
     }
 }
 
-/// Get a character from the keyboard. This should be called exactly once after a keyboard
-/// interrupt and nowhere else.
-unsafe fn read() -> Option<u8> {
-    while KBD_CMD.read() & 1 == 0 {}
-    let b: u8 = KBD_DATA.read();
-    match b {
-        0x02..=0x0a => Some(b'0' + b - 1),
-        0x0b => Some(b'0'),
-
-        0x10 => Some(ul(b'q')),
-        0x11 => Some(ul(b'w')),
-        0x12 => Some(ul(b'e')),
-        0x13 => Some(ul(b'r')),
-        0x14 => Some(ul(b't')),
-        0x15 => Some(ul(b'y')),
-        0x16 => Some(ul(b'u')),
-        0x17 => Some(ul(b'i')),
-        0x18 => Some(ul(b'o')),
-        0x19 => Some(ul(b'p')),
-        0x1e => Some(ul(b'a')),
-        0x1f => Some(ul(b's')),
-        0x20 => Some(ul(b'd')),
-        0x21 => Some(ul(b'f')),
-        0x22 => Some(ul(b'g')),
-        0x23 => Some(ul(b'h')),
-        0x24 => Some(ul(b'j')),
-        0x25 => Some(ul(b'k')),
-        0x26 => Some(ul(b'l')),
-        0x2c => Some(ul(b'z')),
-        0x2d => Some(ul(b'x')),
-        0x2e => Some(ul(b'c')),
-        0x2f => Some(ul(b'v')),
-        0x30 => Some(ul(b'b')),
-        0x31 => Some(ul(b'n')),
-        0x32 => Some(ul(b'm')),
-
-        0x1c => Some(b'\n'),
-        0x39 => Some(b' '),
-
-        0x0e => Some(8),
-
-        // Handle shift
-        0x2a | 0x36 => {
-            SHIFT = true;
-            None
-        }
-        0xaa | 0xb6 => {
-            SHIFT = false;
-            None
-        }
-
-        // TODO: map other ascii characters
-        _ => None,
-    }
-}
-
-/// Initialize the buffer.
+/// Use the keymap chosen at build time (see `keymap`), and turn off the LEDs.
 pub fn init() {
-    *KBD_BUFFER.lock() = Some(LinkedList::new());
+    if let Some(name) = option_env!("OS2_KEYMAP") {
+        if set_keymap(name).is_err() {
+            warn!("unknown keymap `{}`; using `{}`", name, keymap::US.name);
+        }
+    }
+
+    without_interrupts(|| update_leds(&mut KEYBOARD.lock()));
 }
 
-/// Return the first buffered character.
-pub fn kbd_next() -> Option<u8> {
+/// Get a capability to receive keyboard input in the given mode. If nobody has the input focus,
+/// the new consumer gets it.
+pub fn open(mode: Mode) -> ResourceHandle {
+    let kbd = UnregisteredResourceHandle::new(Capability::Keyboard).register();
+
     // Without interrupts to avoid deadlocks because the keyboard handler grabs a lock. Yeah, I
     // know. So sue me.
-    without_interrupts(|| KBD_BUFFER.lock().as_mut().unwrap().pop_front())
+    without_interrupts(|| {
+        let mut consumers = CONSUMERS.lock();
+        consumers.list.push(Consumer {
+            kbd,
+            mode,
+            text: VecDeque::new(),
+            events: VecDeque::new(),
+        });
+        if consumers.focus.is_none() {
+            consumers.focus = Some(consumers.list.len() - 1);
+        }
+    });
+
+    kbd
+}
+
+/// Stop receiving keyboard input on `kbd`. If it had the focus, the next consumer gets it.
+#[allow(dead_code)]
+pub fn close(kbd: ResourceHandle) {
+    without_interrupts(|| {
+        let mut consumers = CONSUMERS.lock();
+        let idx = match consumers
+            .list
+            .iter()
+            .position(|consumer| consumer.kbd == kbd)
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        consumers.list.remove(idx);
+
+        let n = consumers.list.len();
+        consumers.focus = match consumers.focus {
+            _ if n == 0 => None,
+            Some(focus) if focus > idx => Some(focus - 1),
+            Some(focus) => Some(focus % n),
+            None => None,
+        };
+    });
+}
+
+/// Switch `kbd` to `mode`. Any input it hasn't read yet is dropped.
+#[allow(dead_code)]
+pub fn set_mode(kbd: ResourceHandle, mode: Mode) {
+    without_interrupts(|| {
+        if let Some(consumer) = CONSUMERS.lock().get(kbd) {
+            consumer.mode = mode;
+            consumer.text.clear();
+            consumer.events.clear();
+        }
+    });
+}
+
+/// Give the input focus to `kbd`.
+#[allow(dead_code)]
+pub fn focus(kbd: ResourceHandle) {
+    without_interrupts(|| {
+        let mut consumers = CONSUMERS.lock();
+        if let Some(idx) = consumers
+            .list
+            .iter()
+            .position(|consumer| consumer.kbd == kbd)
+        {
+            consumers.focus = Some(idx);
+        }
+    });
+}
+
+/// Does `kbd` have the input focus?
+#[allow(dead_code)]
+pub fn has_focus(kbd: ResourceHandle) -> bool {
+    without_interrupts(|| {
+        let mut consumers = CONSUMERS.lock();
+        consumers.focused().map(|consumer| consumer.kbd) == Some(kbd)
+    })
+}
+
+/// Return the next input for `kbd`, if there is any: `Event::Keyboard` or `Event::Key`, depending
+/// on its mode.
+pub fn next_input(kbd: ResourceHandle) -> Option<Event> {
+    without_interrupts(|| {
+        let mut consumers = CONSUMERS.lock();
+        let consumer = consumers.get(kbd)?;
+        match consumer.mode {
+            Mode::Text => consumer.text.pop_front().map(Event::Keyboard),
+            Mode::Raw => consumer.events.pop_front().map(Event::Key),
+        }
+    })
+}
+
+/// Translate keys with the keymap called `name` from now on.
+pub fn set_keymap(name: &str) -> Result<(), &'static str> {
+    let keymap = keymap::find(name).ok_or("no such keymap")?;
+
+    without_interrupts(|| {
+        let mut kbd = KEYBOARD.lock();
+        kbd.decoder.keymap = keymap;
+        kbd.decoder.dead = None;
+    });
+
+    Ok(())
+}
+
+/// The name of the current keymap.
+pub fn keymap_name() -> &'static str {
+    without_interrupts(|| KEYBOARD.lock().decoder.keymap.name)
+}
+
+/// The current state of the modifier and lock keys.
+#[allow(dead_code)]
+pub fn modifiers() -> Modifiers {
+    without_interrupts(|| KEYBOARD.lock().decoder.modifiers())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use crate::continuation::Event;
+
+    use super::{
+        close, focus, give_text, has_focus, keymap, next_input, open, set_mode, Decoder, KeyCode,
+        KeyEvent, Mode,
+    };
+
+    /// Decode `bytes` with a fresh decoder.
+    fn decode(bytes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = Decoder::new(&keymap::US);
+        bytes.iter().filter_map(|&b| decoder.decode(b)).collect()
+    }
+
+    /// The text produced by `bytes` with the given keymap.
+    fn text_with(keymap: &'static keymap::Keymap, bytes: &[u8]) -> String {
+        let mut decoder = Decoder::new(keymap);
+        bytes
+            .iter()
+            .filter_map(|&b| decoder.decode(b))
+            .map(|event| event.text)
+            .fold(String::new(), |s, text| s + text.as_str())
+    }
+
+    /// The text produced by `bytes` on a US keyboard.
+    fn text(bytes: &[u8]) -> String {
+        text_with(&keymap::US, bytes)
+    }
+
+    kernel_test!(decodes_characters, 5, async {
+        // a, Shift+a, Shift+1, then a after releasing Shift
+        assert_eq!(text(&[0x1E, 0x9E, 0x2A, 0x1E, 0x02, 0xAA, 0x1E]), "aA!a");
+
+        // Caps Lock only affects letters, and Shift undoes it
+        assert_eq!(text(&[0x3A, 0xBA, 0x1E, 0x02, 0x2A, 0x1E]), "A1a");
+
+        // Ctrl+C
+        assert_eq!(text(&[0x1D, 0x2E]), "\x03");
+
+        // The keypad needs Num Lock, except for + and -
+        assert_eq!(text(&[0x47, 0x4E, 0x45, 0x47]), "+7");
+    });
+
+    kernel_test!(decodes_extended_keys, 5, async {
+        // Up pressed and released
+        let events = decode(&[0xE0, 0x48, 0xE0, 0xC8]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].code, KeyCode::UP);
+        assert!(events[0].pressed);
+        assert!(events[0].text.is_empty());
+        assert_eq!(events[1].code, KeyCode::UP);
+        assert!(!events[1].pressed);
+
+        // Print Screen's fake shift doesn't count as Shift
+        let events = decode(&[0xE0, 0x2A, 0xE0, 0x37, 0x1E]);
+        assert_eq!(events[0].code, KeyCode::PRINT_SCREEN);
+        assert_eq!(events[1].text.as_str(), "a");
+
+        // Right Alt is AltGr
+        let events = decode(&[0xE0, 0x38]);
+        assert!(events[0].modifiers.altgr);
+        assert!(!events[0].modifiers.alt);
+
+        // Pause is one event, and doesn't confuse what follows
+        let events = decode(&[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x1E]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].code, KeyCode::PAUSE);
+        assert_eq!(events[1].text.as_str(), "a");
+    });
+
+    kernel_test!(translates_with_keymaps, 5, async {
+        // The key labelled Y on a US keyboard, then the one labelled Q
+        assert_eq!(text_with(&keymap::DE, &[0x15, 0x10]), "zq");
+        assert_eq!(text_with(&keymap::FR, &[0x15, 0x10]), "ya");
+        assert_eq!(text_with(&keymap::DVORAK, &[0x15, 0x10]), "f'");
+
+        // Shift+2 on UK and DE
+        assert_eq!(text_with(&keymap::UK, &[0x2A, 0x03]), "\"");
+        assert_eq!(text_with(&keymap::DE, &[0x2A, 0x03]), "\"");
+
+        // AltGr+Q is @ on DE, and the key works as usual if it has no AltGr character
+        assert_eq!(text_with(&keymap::DE, &[0xE0, 0x38, 0x10, 0x1E]), "@a");
+
+        // Caps Lock works on non-ASCII letters
+        assert_eq!(text_with(&keymap::DE, &[0x3A, 0x27]), "Ö");
+    });
+
+    kernel_test!(composes_dead_keys, 5, async {
+        // ^ then e on DE
+        assert_eq!(text_with(&keymap::DE, &[0x29, 0x12]), "ê");
+
+        // Shift+´ (`) then Shift+a on DE
+        assert_eq!(text_with(&keymap::DE, &[0x2A, 0x0D, 0x1E]), "À");
+
+        // ¨ then u on FR
+        assert_eq!(text_with(&keymap::FR, &[0x2A, 0x1A, 0xAA, 0x16]), "ü");
+
+        // A dead key then space, twice, or followed by a key it doesn't combine with
+        assert_eq!(text_with(&keymap::DE, &[0x29, 0x39]), "^");
+        assert_eq!(text_with(&keymap::DE, &[0x29, 0x29]), "^");
+        assert_eq!(text_with(&keymap::DE, &[0x29, 0x2D]), "^x");
+    });
+
+    kernel_test!(only_the_focus_gets_input, 5, async {
+        let first = open(Mode::Text);
+        let second = open(Mode::Text);
+
+        // Someone else might have had the focus already.
+        focus(first);
+        assert!(has_focus(first));
+
+        without_interrupts(|| give_text(b"a"));
+        assert!(match next_input(first) {
+            Some(Event::Keyboard(b'a')) => true,
+            _ => false,
+        });
+        assert!(next_input(second).is_none());
+
+        focus(second);
+        without_interrupts(|| give_text(b"b"));
+        assert!(next_input(first).is_none());
+        assert!(match next_input(second) {
+            Some(Event::Keyboard(b'b')) => true,
+            _ => false,
+        });
+
+        // Raw mode doesn't get text.
+        set_mode(second, Mode::Raw);
+        without_interrupts(|| give_text(b"c"));
+        assert!(next_input(second).is_none());
+
+        // Closing the focus moves it along.
+        close(second);
+        assert!(!has_focus(second));
+        close(first);
+    });
 }
@@ -0,0 +1,183 @@
+//! Keyboard layouts.
+//!
+//! A keymap says which character each key produces, without and with Shift, and with AltGr (right
+//! Alt). Some keys are dead keys: they don't produce anything by themselves, but add an accent to
+//! the next key (e.g. `^` then `e` gives `ê`). Keys that are the same on every layout (Enter,
+//! Backspace, the keypad, ...) are handled by `kbd` and aren't part of the keymap.
+//!
+//! The keymap the kernel starts with is chosen at build time with the `OS2_KEYMAP` environment
+//! variable (e.g. `OS2_KEYMAP=de cargo xbuild`). It can't be chosen at boot, since the bootloader
+//! doesn't give us a command line. At run time, it can be changed with `kbd::set_keymap` (e.g.
+//! from the shell's `keymap` command).
+
+use super::kbd::KeyCode;
+
+/// What a key produces.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Sym {
+    Char(char),
+
+    /// A dead key with the given accent.
+    Dead(char),
+}
+
+/// A keyboard layout.
+pub struct Keymap {
+    pub name: &'static str,
+
+    /// Rows of keys: the code of the first key in the row, then the characters of the keys
+    /// without and with Shift.
+    rows: &'static [(u8, &'static str, &'static str)],
+
+    /// The keys that produce something different with AltGr, and what they produce.
+    altgr: &'static [(u8, char)],
+
+    /// The keys in `rows` that are dead keys: the key code, and whether it is dead with Shift or
+    /// without.
+    dead: &'static [(u8, bool)],
+}
+
+impl Keymap {
+    /// What pressing `code` with the given modifiers produces, if anything. If AltGr is down but
+    /// the key has no AltGr character, the key works as if AltGr wasn't down.
+    pub fn lookup(&self, code: KeyCode, shift: bool, altgr: bool) -> Option<Sym> {
+        if altgr {
+            if let Some(&(_, c)) = self.altgr.iter().find(|&&(key, _)| key == code.0) {
+                return Some(Sym::Char(c));
+            }
+        }
+
+        let c = self.rows.iter().find_map(|&(first, normal, shifted)| {
+            let idx = code.0.checked_sub(first)? as usize;
+            let row = if shift { shifted } else { normal };
+            row.chars().nth(idx)
+        })?;
+
+        if self.dead.contains(&(code.0, shift)) {
+            Some(Sym::Dead(c))
+        } else {
+            Some(Sym::Char(c))
+        }
+    }
+}
+
+/// US English (QWERTY).
+pub static US: Keymap = Keymap {
+    name: "us",
+    rows: &[
+        (0x02, "1234567890-=", "!@#$%^&*()_+"),
+        (0x10, "qwertyuiop[]", "QWERTYUIOP{}"),
+        (0x1E, "asdfghjkl;'`", "ASDFGHJKL:\"~"),
+        (0x2B, "\\zxcvbnm,./", "|ZXCVBNM<>?"),
+        (0x56, "\\", "|"),
+    ],
+    altgr: &[],
+    dead: &[],
+};
+
+/// UK English (QWERTY).
+pub static UK: Keymap = Keymap {
+    name: "uk",
+    rows: &[
+        (0x02, "1234567890-=", "!\"£$%^&*()_+"),
+        (0x10, "qwertyuiop[]", "QWERTYUIOP{}"),
+        (0x1E, "asdfghjkl;'`", "ASDFGHJKL:@¬"),
+        (0x2B, "#zxcvbnm,./", "~ZXCVBNM<>?"),
+        (0x56, "\\", "|"),
+    ],
+    altgr: &[(0x05, '€'), (0x29, '¦')],
+    dead: &[],
+};
+
+/// German (QWERTZ).
+pub static DE: Keymap = Keymap {
+    name: "de",
+    rows: &[
+        (0x02, "1234567890ß´", "!\"§$%&/()=?`"),
+        (0x10, "qwertzuiopü+", "QWERTZUIOPÜ*"),
+        (0x1E, "asdfghjklöä^", "ASDFGHJKLÖÄ°"),
+        (0x2B, "#yxcvbnm,.-", "'YXCVBNM;:_"),
+        (0x56, "<", ">"),
+    ],
+    altgr: &[
+        (0x03, '²'),
+        (0x04, '³'),
+        (0x08, '{'),
+        (0x09, '['),
+        (0x0A, ']'),
+        (0x0B, '}'),
+        (0x0C, '\\'),
+        (0x10, '@'),
+        (0x12, '€'),
+        (0x1B, '~'),
+        (0x32, 'µ'),
+        (0x56, '|'),
+    ],
+    dead: &[(0x0D, false), (0x0D, true), (0x29, false)],
+};
+
+/// French (AZERTY).
+pub static FR: Keymap = Keymap {
+    name: "fr",
+    rows: &[
+        (0x02, "&é\"'(-è_çà)=", "1234567890°+"),
+        (0x10, "azertyuiop^$", "AZERTYUIOP¨£"),
+        (0x1E, "qsdfghjklmù²", "QSDFGHJKLM%²"),
+        (0x2B, "*wxcvbn,;:!", "µWXCVBN?./§"),
+        (0x56, "<", ">"),
+    ],
+    altgr: &[
+        (0x03, '~'),
+        (0x04, '#'),
+        (0x05, '{'),
+        (0x06, '['),
+        (0x07, '|'),
+        (0x08, '`'),
+        (0x09, '\\'),
+        (0x0A, '^'),
+        (0x0B, '@'),
+        (0x0C, ']'),
+        (0x0D, '}'),
+        (0x12, '€'),
+        (0x1B, '¤'),
+    ],
+    dead: &[(0x1A, false), (0x1A, true)],
+};
+
+/// US Dvorak.
+pub static DVORAK: Keymap = Keymap {
+    name: "dvorak",
+    rows: &[
+        (0x02, "1234567890[]", "!@#$%^&*(){}"),
+        (0x10, "',.pyfgcrl/=", "\"<>PYFGCRL?+"),
+        (0x1E, "aoeuidhtns-`", "AOEUIDHTNS_~"),
+        (0x2B, "\\;qjkxbmwvz", "|:QJKXBMWVZ"),
+        (0x56, "\\", "|"),
+    ],
+    altgr: &[],
+    dead: &[],
+};
+
+/// All the keymaps.
+pub static KEYMAPS: &[&Keymap] = &[&US, &UK, &DE, &FR, &DVORAK];
+
+/// Find a keymap by name.
+pub fn find(name: &str) -> Option<&'static Keymap> {
+    KEYMAPS.iter().cloned().find(|keymap| keymap.name == name)
+}
+
+/// Accents, the letters they can go on, and the accented letters.
+const ACCENTS: &[(char, &str, &str)] = &[
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('~', "anoANO", "ãñõÃÑÕ"),
+    ('¨', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+/// Put `accent` on `c`, if there is such a character.
+pub fn compose(accent: char, c: char) -> Option<char> {
+    let &(_, plain, accented) = ACCENTS.iter().find(|&&(a, _, _)| a == accent)?;
+    let idx = plain.chars().position(|p| p == c)?;
+    accented.chars().nth(idx)
+}
@@ -1,7 +1,20 @@
 //! All things I/O related.
 
+pub mod ata;
+pub mod block;
 pub mod kbd;
+pub mod keymap;
+pub mod mouse;
+pub mod serial;
+pub mod vga;
+pub mod virtio;
+pub mod vt;
 
 pub fn init() {
+    vt::init();
+    // The mouse first: it reads and rewrites the controller's configuration, which mustn't get
+    // mixed up with the keyboard's answers.
+    mouse::init();
     kbd::init();
+    serial::init();
 }
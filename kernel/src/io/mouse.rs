@@ -0,0 +1,439 @@
+//! The PS/2 mouse.
+//!
+//! The mouse is the auxiliary device of the PS/2 controller (the keyboard is the first one), and
+//! interrupts on IRQ 12. It sends a packet of 3 bytes for every change: the buttons, and the
+//! movement since the last packet. If it turns out to be an IntelliMouse (which we find out by
+//! setting a magic sequence of sample rates), packets have a fourth byte with the wheel movement.
+//!
+//! Packets are turned into `MouseEvent`s: movement, button presses and releases, and wheel
+//! movement. Every holder of a mouse capability (see `open`) gets all of them, buffered until a
+//! continuation waiting for `EventKind::Mouse` with that capability takes them.
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    cap::{Capability, ResourceHandle, UnregisteredResourceHandle},
+    continuation::Event,
+};
+
+/// Controller command/status port
+const CONTROLLER_CMD: Port<u8> = Port::new(0x64);
+
+/// Controller data port
+const CONTROLLER_DATA: Port<u8> = Port::new(0x60);
+
+/// Status register: there is a byte to read from the data port.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+
+/// Status register: the controller hasn't taken the last byte we wrote yet.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// Status register: the byte to read is from the mouse, not the keyboard.
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// Controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_WRITE_AUX: u8 = 0xD4;
+
+/// Controller configuration bits
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+/// Mouse commands
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_GET_ID: u8 = 0xF2;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+
+/// Mouse responses
+const RESP_ACK: u8 = 0xFA;
+const RESP_RESEND: u8 = 0xFE;
+
+/// Setting these sample rates in a row turns on the wheel of an IntelliMouse, which then says its
+/// ID is `ID_INTELLIMOUSE`.
+const INTELLIMOUSE_RATES: [u8; 3] = [200, 100, 80];
+const ID_INTELLIMOUSE: u8 = 3;
+
+/// How long to wait for the controller, in polls.
+const TIMEOUT: usize = 100_000;
+
+/// The most events we buffer per consumer. Older events are dropped if nobody reads them.
+const MAX_EVENTS: usize = 128;
+
+/// Bits of the first byte of a packet
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_SET: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// A mouse button.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+}
+
+/// The buttons, and their bits in the first byte of a packet.
+const BUTTONS: [(Button, u8); 3] = [
+    (Button::Left, PACKET_LEFT),
+    (Button::Right, PACKET_RIGHT),
+    (Button::Middle, PACKET_MIDDLE),
+];
+
+/// Something the mouse did.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MouseEvent {
+    /// The mouse moved. Like screen coordinates, x grows to the right and y grows downwards.
+    Move { dx: i16, dy: i16 },
+
+    /// A button was pressed or released.
+    Button { button: Button, pressed: bool },
+
+    /// The wheel turned by the given number of notches, positive towards the user (i.e. scrolling
+    /// down).
+    Wheel(i8),
+}
+
+/// Turns the bytes from the mouse into events.
+struct Decoder {
+    packet: [u8; 4],
+    len: usize,
+
+    /// Whether packets have the wheel byte.
+    wheel: bool,
+
+    /// The button bits of the last packet.
+    buttons: u8,
+}
+
+impl Decoder {
+    const fn new(wheel: bool) -> Self {
+        Decoder {
+            packet: [0; 4],
+            len: 0,
+            wheel,
+            buttons: 0,
+        }
+    }
+
+    /// Take the next byte from the mouse. Returns the events for the packet it finishes, if any.
+    fn decode(&mut self, b: u8) -> Vec<MouseEvent> {
+        let mut events = Vec::new();
+
+        // If we lost a byte somewhere, skip ahead to something that looks like the start of a
+        // packet.
+        if self.len == 0 && b & PACKET_ALWAYS_SET == 0 {
+            return events;
+        }
+
+        self.packet[self.len] = b;
+        self.len += 1;
+        if self.len < if self.wheel { 4 } else { 3 } {
+            return events;
+        }
+        self.len = 0;
+
+        let flags = self.packet[0];
+
+        // The movement is 9-bit two's complement, with the sign in the first byte. It is dropped
+        // if it overflowed.
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) == 0 {
+            let dx = i16::from(self.packet[1]) - if flags & PACKET_X_SIGN != 0 { 256 } else { 0 };
+            let dy = i16::from(self.packet[2]) - if flags & PACKET_Y_SIGN != 0 { 256 } else { 0 };
+            if dx != 0 || dy != 0 {
+                // The mouse counts y upwards.
+                events.push(MouseEvent::Move { dx, dy: -dy });
+            }
+        }
+
+        for &(button, bit) in BUTTONS.iter() {
+            if (flags ^ self.buttons) & bit != 0 {
+                events.push(MouseEvent::Button {
+                    button,
+                    pressed: flags & bit != 0,
+                });
+            }
+        }
+        self.buttons = flags & (PACKET_LEFT | PACKET_RIGHT | PACKET_MIDDLE);
+
+        if self.wheel {
+            // The low 4 bits are the (signed) wheel movement.
+            let wheel = ((self.packet[3] << 4) as i8) >> 4;
+            if wheel != 0 {
+                events.push(MouseEvent::Wheel(wheel));
+            }
+        }
+
+        events
+    }
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new(false));
+
+/// A holder of a mouse capability.
+struct Consumer {
+    mouse: ResourceHandle,
+
+    /// Events not read yet.
+    events: VecDeque<MouseEvent>,
+}
+
+static CONSUMERS: Mutex<Vec<Consumer>> = Mutex::new(Vec::new());
+
+/// Wait until the controller can take a byte.
+fn wait_write() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if unsafe { CONTROLLER_CMD.read() } & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err("controller timed out")
+}
+
+/// Wait for a byte from the controller. Keyboard bytes are skipped if `aux` is set.
+fn read(aux: bool) -> Result<u8, &'static str> {
+    for _ in 0..TIMEOUT {
+        let status = unsafe { CONTROLLER_CMD.read() };
+        if status & STATUS_OUTPUT_FULL != 0 {
+            let b = unsafe { CONTROLLER_DATA.read() };
+            if !aux || status & STATUS_AUX_DATA != 0 {
+                return Ok(b);
+            }
+        }
+    }
+    Err("no response")
+}
+
+/// Throw away whatever the controller has for us, so that the next byte read is an answer.
+fn drain() {
+    for _ in 0..TIMEOUT {
+        if unsafe { CONTROLLER_CMD.read() } & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        unsafe { CONTROLLER_DATA.read() };
+    }
+}
+
+/// The configuration byte with the mouse's interrupt and clock turned on. The keyboard's bits are
+/// left alone.
+fn aux_config(config: u8) -> u8 {
+    (config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED
+}
+
+/// Send a command to the controller.
+fn controller_command(cmd: u8) -> Result<(), &'static str> {
+    wait_write()?;
+    unsafe { CONTROLLER_CMD.write(cmd) };
+    Ok(())
+}
+
+/// Send a byte to the mouse and wait for it to be acknowledged.
+fn mouse_command(b: u8) -> Result<(), &'static str> {
+    for _ in 0..3 {
+        controller_command(CMD_WRITE_AUX)?;
+        wait_write()?;
+        unsafe { CONTROLLER_DATA.write(b) };
+
+        match read(true)? {
+            RESP_ACK => return Ok(()),
+            RESP_RESEND => continue,
+            _ => return Err("mouse command failed"),
+        }
+    }
+    Err("mouse command failed")
+}
+
+/// Set up the controller and the mouse, and turn on the wheel if there is one.
+///
+/// This runs before the keyboard driver sends anything (see `io::init`), but a key press can still
+/// leave a byte in the output buffer, which would be taken for the configuration byte.
+fn setup() -> Result<bool, &'static str> {
+    controller_command(CMD_ENABLE_AUX)?;
+
+    drain();
+    controller_command(CMD_READ_CONFIG)?;
+    let config = read(false)?;
+    controller_command(CMD_WRITE_CONFIG)?;
+    wait_write()?;
+    unsafe {
+        CONTROLLER_DATA.write(aux_config(config));
+    }
+
+    mouse_command(MOUSE_SET_DEFAULTS)?;
+
+    for &rate in INTELLIMOUSE_RATES.iter() {
+        mouse_command(MOUSE_SET_SAMPLE_RATE)?;
+        mouse_command(rate)?;
+    }
+    mouse_command(MOUSE_GET_ID)?;
+    let wheel = read(true)? == ID_INTELLIMOUSE;
+
+    mouse_command(MOUSE_ENABLE_REPORTING)?;
+
+    Ok(wheel)
+}
+
+/// Set up the mouse, if there is one.
+pub fn init() {
+    without_interrupts(|| match setup() {
+        Ok(wheel) => {
+            *DECODER.lock() = Decoder::new(wheel);
+            info!("PS/2 mouse inited (wheel: {})", wheel);
+        }
+        Err(err) => warn!("no PS/2 mouse: {}", err),
+    });
+}
+
+/// The mouse interrupt handler
+///
+/// Get a byte from the mouse, and give the events of the packet it finishes (if any) to everyone
+/// with a mouse capability. This should be called exactly once after a mouse interrupt and nowhere
+/// else.
+pub unsafe fn handler() {
+    let status = CONTROLLER_CMD.read();
+    if status & STATUS_OUTPUT_FULL == 0 || status & STATUS_AUX_DATA == 0 {
+        return;
+    }
+    let b = CONTROLLER_DATA.read();
+
+    let events = DECODER.lock().decode(b);
+    if events.is_empty() {
+        return;
+    }
+
+    for consumer in CONSUMERS.lock().iter_mut() {
+        for &event in events.iter() {
+            if consumer.events.len() == MAX_EVENTS {
+                consumer.events.pop_front();
+            }
+            consumer.events.push_back(event);
+        }
+    }
+}
+
+/// Get a capability to receive mouse events.
+pub fn open() -> ResourceHandle {
+    let mouse = UnregisteredResourceHandle::new(Capability::Mouse).register();
+
+    // Without interrupts to avoid deadlocks with the interrupt handler.
+    without_interrupts(|| {
+        CONSUMERS.lock().push(Consumer {
+            mouse,
+            events: VecDeque::new(),
+        })
+    });
+
+    mouse
+}
+
+/// Stop receiving mouse events on `mouse`.
+pub fn close(mouse: ResourceHandle) {
+    without_interrupts(|| CONSUMERS.lock().retain(|consumer| consumer.mouse != mouse));
+}
+
+/// Return the next event for `mouse`, if there is one.
+pub fn next_input(mouse: ResourceHandle) -> Option<Event> {
+    without_interrupts(|| {
+        CONSUMERS
+            .lock()
+            .iter_mut()
+            .find(|consumer| consumer.mouse == mouse)?
+            .events
+            .pop_front()
+            .map(Event::Mouse)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{
+        aux_config, Button, Decoder, MouseEvent, CONFIG_AUX_CLOCK_DISABLED, CONFIG_AUX_IRQ,
+    };
+
+    /// Decode `bytes` with `decoder`.
+    fn decode(decoder: &mut Decoder, bytes: &[u8]) -> Vec<MouseEvent> {
+        bytes.iter().flat_map(|&b| decoder.decode(b)).collect()
+    }
+
+    kernel_test!(decodes_mouse_packets, 5, async {
+        let mut decoder = Decoder::new(false);
+
+        // Right 5, up 3 (which is -3 on the screen)
+        assert_eq!(
+            decode(&mut decoder, &[0x08, 5, 3]),
+            [MouseEvent::Move { dx: 5, dy: -3 }]
+        );
+
+        // Left 2 and down 1, with the left button pressed
+        assert_eq!(
+            decode(&mut decoder, &[0x39, 0xFE, 0xFF]),
+            [
+                MouseEvent::Move { dx: -2, dy: 1 },
+                MouseEvent::Button {
+                    button: Button::Left,
+                    pressed: true
+                },
+            ]
+        );
+
+        // Left released, middle pressed; overflowed movement is dropped.
+        assert_eq!(
+            decode(&mut decoder, &[0x4C, 0xFF, 0]),
+            [
+                MouseEvent::Button {
+                    button: Button::Left,
+                    pressed: false
+                },
+                MouseEvent::Button {
+                    button: Button::Middle,
+                    pressed: true
+                },
+            ]
+        );
+
+        // A stray byte that can't start a packet is skipped.
+        assert_eq!(
+            decode(&mut decoder, &[0x00, 0x0C, 1, 0]),
+            [MouseEvent::Move { dx: 1, dy: 0 }]
+        );
+    });
+
+    kernel_test!(decodes_mouse_wheel, 5, async {
+        let mut decoder = Decoder::new(true);
+
+        assert_eq!(
+            decode(&mut decoder, &[0x08, 0, 0, 0x01]),
+            [MouseEvent::Wheel(1)]
+        );
+        assert_eq!(
+            decode(&mut decoder, &[0x08, 0, 0, 0x0F]),
+            [MouseEvent::Wheel(-1)]
+        );
+        assert!(decode(&mut decoder, &[0x08, 0, 0]).is_empty());
+    });
+
+    kernel_test!(aux_config_keeps_keyboard_bits, 5, async {
+        // The keyboard's interrupt (bit 0) and clock (bit 4) are kept as they were.
+        const KEYBOARD_BITS: u8 = (1 << 0) | (1 << 4);
+
+        for &config in [0x00, 0x01, 0x10, 0x45, 0x65, 0xFA].iter() {
+            let new = aux_config(config);
+            assert_eq!(new & KEYBOARD_BITS, config & KEYBOARD_BITS);
+            assert_eq!(new & CONFIG_AUX_IRQ, CONFIG_AUX_IRQ);
+            assert_eq!(new & CONFIG_AUX_CLOCK_DISABLED, 0);
+        }
+    });
+}
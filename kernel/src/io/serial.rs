@@ -0,0 +1,520 @@
+//! An interrupt-driven driver for 16550 UARTs (the PC's serial ports, COM1 to COM4).
+//!
+//! Each port has a transmit and a receive buffer. Output goes into the transmit buffer, and the
+//! transmit interrupt moves it into the UART's FIFO as space frees up. Input is moved from the
+//! UART's FIFO into the receive buffer by the receive interrupt, and continuations take it from
+//! there by waiting for `EventKind::Serial` (see `task::serial`).
+//!
+//! A port is polled until it is set up with `configure` (and always while interrupts are off), so
+//! `printk!`, which writes to COM1, works from the very start of boot and in panics.
+//!
+//! COM1 and COM3 share IRQ 4, and COM2 and COM4 share IRQ 3. COM2 belongs to the GDB stub (see
+//! `gdb`), which has its own polled driver, so it can't be configured here.
+
+use spin::{Mutex, MutexGuard};
+
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    continuation::{ContResult, Continuation},
+    io::vt,
+    task,
+};
+
+/// The I/O ports of COM1 to COM4.
+const BASES: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+
+/// The IRQs of COM1 to COM4.
+const IRQS: [u8; 4] = [4, 3, 4, 3];
+
+/// The port used by the GDB stub.
+const GDB_PORT: u8 = 2;
+
+/// Registers (offsets from the base port). With `LCR_DLAB` set, the first two are the divisor.
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1;
+const REG_IIR: u16 = 2; // when read
+const REG_FCR: u16 = 2; // when written
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
+const REG_MSR: u16 = 6;
+const REG_DIVISOR_LO: u16 = 0;
+const REG_DIVISOR_HI: u16 = 1;
+
+/// Interrupt enable bits
+const IER_RX: u8 = 1 << 0;
+const IER_TX: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+/// Interrupt identification: bit 0 is clear if an interrupt is pending, and bits 1-3 say which.
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0x0E;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_DATA: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0C;
+
+/// Bits 6 and 7 of the IIR are set if the FIFOs are enabled and work (i.e. this is a 16550A or
+/// later, not an 8250 or a buggy 16550).
+const IIR_FIFO_WORKS: u8 = 0xC0;
+
+/// FIFO control: enable, clear both FIFOs, and interrupt when 14 bytes have been received.
+const FCR_ENABLE_14: u8 = 0xC7;
+
+/// Line control bits
+const LCR_STOP_BITS_2: u8 = 1 << 2;
+const LCR_DLAB: u8 = 1 << 7;
+
+/// Modem control: DTR, RTS, and OUT2, which connects the UART's interrupt line to the PIC.
+const MCR_NORMAL: u8 = 0x0B;
+
+/// Modem control for the loopback test: RTS, OUT1, OUT2, and loopback.
+const MCR_LOOPBACK: u8 = 0x1E;
+
+/// Line status bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// The size of the UART's transmit FIFO, if it works.
+const FIFO_SIZE: usize = 16;
+
+/// The rate that the divisor divides (Hz).
+const BASE_BAUD: u32 = 115_200;
+
+/// The size of each port's transmit and receive buffers (bytes).
+const RING_SIZE: usize = 4096;
+
+/// How many times to try the lock before assuming this core holds it (see `write`).
+const LOCK_TRIES: usize = 100_000;
+
+/// Parity settings
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[allow(dead_code)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+/// Line settings for a serial port.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// Bits per second. This must divide 115200.
+    pub baud: u32,
+
+    /// 5 to 8
+    pub data_bits: u8,
+
+    pub parity: Parity,
+
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl Config {
+    /// 115200 baud, 8N1
+    pub const DEFAULT: Config = Config {
+        baud: BASE_BAUD,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+
+    /// The divisor and line control register for these settings.
+    fn registers(&self) -> Result<(u16, u8), &'static str> {
+        if self.baud == 0 || BASE_BAUD % self.baud != 0 {
+            return Err("the baud rate must divide 115200");
+        }
+
+        let data_bits = match self.data_bits {
+            5..=8 => self.data_bits - 5,
+            _ => return Err("there must be 5 to 8 data bits"),
+        };
+
+        let stop_bits = match self.stop_bits {
+            1 => 0,
+            2 => LCR_STOP_BITS_2,
+            _ => return Err("there must be 1 or 2 stop bits"),
+        };
+
+        let parity = match self.parity {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        };
+
+        Ok((
+            (BASE_BAUD / self.baud) as u16,
+            data_bits | stop_bits | parity,
+        ))
+    }
+}
+
+/// A fixed-size FIFO of bytes.
+struct Ring {
+    buf: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            buf: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Add `b` at the end. Returns false if there is no room.
+    fn push(&mut self, b: u8) -> bool {
+        if self.len == RING_SIZE {
+            return false;
+        }
+
+        self.buf[(self.start + self.len) % RING_SIZE] = b;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let b = self.buf[self.start];
+        self.start = (self.start + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(b)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The state of one port.
+struct Uart {
+    base: u16,
+
+    /// Has it been set up with `configure`? Until then, output is polled and there is no input.
+    enabled: bool,
+
+    /// The number of bytes we can write to the transmit FIFO at once.
+    fifo_size: usize,
+
+    tx: Ring,
+    rx: Ring,
+
+    /// Received bytes that were lost, because the receive buffer or the UART's FIFO was full.
+    dropped: usize,
+}
+
+impl Uart {
+    const fn new(base: u16) -> Self {
+        Uart {
+            base,
+            enabled: false,
+            fifo_size: 1,
+            tx: Ring::new(),
+            rx: Ring::new(),
+            dropped: 0,
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        unsafe { Port::new(self.base + reg).read() }
+    }
+
+    fn write(&self, reg: u16, val: u8) {
+        unsafe { Port::new(self.base + reg).write(val) }
+    }
+
+    /// Wait until the UART can take a byte, then write it.
+    fn put_polled(&self, b: u8) {
+        poll_put(self.base, b);
+    }
+
+    /// Write everything in the transmit buffer, polling.
+    fn flush_polled(&mut self) {
+        while let Some(b) = self.tx.pop() {
+            self.put_polled(b);
+        }
+    }
+
+    /// Fill the UART's transmit FIFO from the transmit buffer, if it's empty. Ask for an
+    /// interrupt when it's empty again if there is more to send.
+    fn start_tx(&mut self) {
+        if self.read(REG_LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..self.fifo_size {
+                match self.tx.pop() {
+                    Some(b) => self.write(REG_DATA, b),
+                    None => break,
+                }
+            }
+        }
+
+        let ier = if self.tx.is_empty() {
+            IER_RX | IER_LINE_STATUS
+        } else {
+            IER_RX | IER_LINE_STATUS | IER_TX
+        };
+        self.write(REG_IER, ier);
+    }
+
+    /// Move everything the UART has received into the receive buffer.
+    fn receive(&mut self) {
+        while self.read(REG_LSR) & LSR_DATA_READY != 0 {
+            let b = self.read(REG_DATA);
+            if !self.rx.push(b) {
+                self.dropped += 1;
+            }
+        }
+    }
+
+    /// Handle all pending interrupts of this UART.
+    fn handle_irq(&mut self) {
+        loop {
+            let iir = self.read(REG_IIR);
+            if iir & IIR_NONE_PENDING != 0 {
+                return;
+            }
+
+            match iir & IIR_ID_MASK {
+                IIR_LINE_STATUS => {
+                    if self.read(REG_LSR) & LSR_OVERRUN != 0 {
+                        self.dropped += 1;
+                    }
+                }
+                IIR_RX_DATA | IIR_RX_TIMEOUT => self.receive(),
+                IIR_TX_EMPTY => self.start_tx(),
+                IIR_MODEM_STATUS => {
+                    self.read(REG_MSR);
+                }
+
+                // Shouldn't happen, but reading everything clears any interrupt.
+                _ => {
+                    self.read(REG_LSR);
+                    self.read(REG_MSR);
+                    self.receive();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// COM1 to COM4.
+static PORTS: [Mutex<Uart>; 4] = [
+    Mutex::new(Uart::new(BASES[0])),
+    Mutex::new(Uart::new(BASES[1])),
+    Mutex::new(Uart::new(BASES[2])),
+    Mutex::new(Uart::new(BASES[3])),
+];
+
+/// Wait until the UART at `base` can take a byte, then write it.
+fn poll_put(base: u16, b: u8) {
+    unsafe {
+        while Port::<u8>::new(base + REG_LSR).read() & LSR_THR_EMPTY == 0 {}
+        Port::new(base + REG_DATA).write(b);
+    }
+}
+
+/// The state of COM`port`.
+fn uart(port: u8) -> &'static Mutex<Uart> {
+    assert!(port >= 1 && port <= 4, "no such serial port: COM{}", port);
+    &PORTS[port as usize - 1]
+}
+
+/// Lock `uart`, giving up after a while (see `write`).
+fn try_lock(uart: &Mutex<Uart>) -> Option<MutexGuard<Uart>> {
+    (0..LOCK_TRIES).find_map(|_| uart.try_lock())
+}
+
+/// Set up the serial console (COM1).
+pub fn init() {
+    match configure(1, Config::DEFAULT) {
+        Ok(()) => info!("serial console inited on COM1"),
+        Err(err) => warn!("serial console not inited: {}", err),
+    }
+}
+
+/// Set up COM`port` (1 to 4) with the given settings, and turn on its interrupts. Anything
+/// buffered is kept.
+pub fn configure(port: u8, config: Config) -> Result<(), &'static str> {
+    if port < 1 || port > 4 {
+        return Err("no such port");
+    }
+    if port == GDB_PORT {
+        return Err("COM2 belongs to the GDB stub");
+    }
+
+    let (divisor, lcr) = config.registers()?;
+
+    interrupts::without_interrupts(|| {
+        let mut uart = uart(port).lock();
+
+        // Send whatever is still buffered with the old settings.
+        uart.flush_polled();
+
+        uart.write(REG_IER, 0);
+
+        // Check that there is a UART: in loopback mode, it should receive what it sends.
+        uart.write(REG_MCR, MCR_LOOPBACK);
+        uart.write(REG_DATA, 0xAE);
+        if uart.read(REG_DATA) != 0xAE {
+            uart.write(REG_MCR, MCR_NORMAL);
+            return Err("no UART found");
+        }
+
+        uart.write(REG_LCR, LCR_DLAB);
+        uart.write(REG_DIVISOR_LO, divisor as u8);
+        uart.write(REG_DIVISOR_HI, (divisor >> 8) as u8);
+        uart.write(REG_LCR, lcr);
+
+        uart.write(REG_FCR, FCR_ENABLE_14);
+        uart.fifo_size = if uart.read(REG_IIR) & IIR_FIFO_WORKS == IIR_FIFO_WORKS {
+            FIFO_SIZE
+        } else {
+            1
+        };
+
+        uart.write(REG_MCR, MCR_NORMAL);
+
+        // Drop anything left over from the loopback test or from before.
+        while uart.read(REG_LSR) & LSR_DATA_READY != 0 {
+            uart.read(REG_DATA);
+        }
+
+        uart.enabled = true;
+        uart.start_tx();
+
+        Ok(())
+    })
+}
+
+/// Write `bytes` to COM`port`.
+///
+/// Once the port is configured and if interrupts are on, this just buffers the bytes (waiting if
+/// the buffer is full). Otherwise, it polls the UART until all of the bytes are sent.
+pub fn write(port: u8, bytes: &[u8]) {
+    let polled = !interrupts::are_enabled();
+
+    interrupts::without_interrupts(|| {
+        let uart = uart(port);
+
+        // If the lock doesn't come free, we are probably in an exception handler that
+        // interrupted a write on this core, so just poll the bytes out.
+        let mut uart = match try_lock(uart) {
+            Some(uart) => uart,
+            None => {
+                for &b in bytes {
+                    poll_put(BASES[port as usize - 1], b);
+                }
+                return;
+            }
+        };
+
+        if polled || !uart.enabled {
+            // Keep the output in order.
+            uart.flush_polled();
+            for &b in bytes {
+                uart.put_polled(b);
+            }
+            return;
+        }
+
+        for &b in bytes {
+            if !uart.tx.push(b) {
+                // The buffer is full, so make some room.
+                let old = uart.tx.pop().unwrap();
+                uart.put_polled(old);
+                uart.tx.push(b);
+            }
+        }
+
+        uart.start_tx();
+    });
+}
+
+/// Take the next received byte from COM`port`, if there is one.
+pub fn read_byte(port: u8) -> Option<u8> {
+    interrupts::without_interrupts(|| uart(port).lock().rx.pop())
+}
+
+/// The number of received bytes COM`port` has lost.
+#[allow(dead_code)]
+pub fn dropped(port: u8) -> usize {
+    interrupts::without_interrupts(|| uart(port).lock().dropped)
+}
+
+/// Handle an interrupt from the ports on `irq`.
+pub fn handle_irq(irq: u8) {
+    for port in 1..=4 {
+        if IRQS[port as usize - 1] != irq || port == GDB_PORT {
+            continue;
+        }
+
+        let mut uart = uart(port).lock();
+        if uart.enabled {
+            uart.handle_irq();
+        }
+    }
+}
+
+/// Returns a continuation that types input from the serial console on the console terminal (see
+/// `vt`), so the serial console can be used like the keyboard (e.g. with `-nographic`).
+pub fn console_input() -> Continuation {
+    task::continuation(forward_console_input())
+}
+
+async fn forward_console_input() -> ContResult {
+    loop {
+        let c = match task::serial(1).await {
+            // Terminals send CR for enter and DEL for backspace.
+            b'\r' => b'\n',
+            0x7F => 8,
+            c => c,
+        };
+
+        vt::push(vt::CONSOLE, c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Parity};
+
+    kernel_test!(line_settings, 5, async {
+        assert_eq!(Config::DEFAULT.registers(), Ok((1, 0x03)));
+
+        let config = Config {
+            baud: 9600,
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: 2,
+        };
+        assert_eq!(config.registers(), Ok((12, 0x02 | 0x04 | 0x18)));
+
+        assert!(Config { baud: 7, ..config }.registers().is_err());
+        assert!(Config {
+            data_bits: 9,
+            ..config
+        }
+        .registers()
+        .is_err());
+        assert!(Config {
+            stop_bits: 3,
+            ..config
+        }
+        .registers()
+        .is_err());
+    });
+}
@@ -0,0 +1,610 @@
+//! The VGA text-mode console.
+//!
+//! The screen is 80x25 cells at physical address 0xB8000, which the bootloader identity maps, so
+//! the console works from the very start of boot. Each cell is a code page 437 character and an
+//! attribute byte (the foreground color in the low nibble and the background color in the high
+//! one). `printk!` writes here as well as to the serial console.
+//!
+//! Output is UTF-8; characters that aren't in code page 437 show up as `?`. A few ANSI escape
+//! sequences are understood:
+//! - `ESC [ n m`: colors (0 reset, 1 bold/bright, 7 reverse, 22, 27, 30-37, 39, 40-47, 49,
+//!   90-97, 100-107)
+//! - `ESC [ n A/B/C/D`: move the cursor up/down/right/left
+//! - `ESC [ row ; col H` (or `f`): move the cursor (1-based)
+//! - `ESC [ n J`: clear to the end (0), the start (1), or all (2) of the screen
+//! - `ESC [ n K`: clear to the end (0), the start (1), or all (2) of the line
+//!
+//! Anything else is ignored.
+//!
+//! Once the virtual terminals are up (see `vt`), each has its own `Console` drawing into memory,
+//! and the one on screen is copied here.
+
+use alloc::collections::vec_deque::VecDeque;
+
+use core::{mem, ops::Range, ptr};
+
+use spin::Mutex;
+
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The size of the screen.
+pub const WIDTH: usize = 80;
+pub const HEIGHT: usize = 25;
+
+/// Where the text buffer is.
+const BUFFER: u64 = 0xB8000;
+
+/// The CRT controller's index and data ports, and the registers of the hardware cursor.
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_HI: u8 = 0x0E;
+const CRTC_CURSOR_LO: u8 = 0x0F;
+
+/// Cursor start register bit that hides the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// The scan lines of the cursor: an underline.
+const CURSOR_FIRST_LINE: u8 = 14;
+const CURSOR_LAST_LINE: u8 = 15;
+
+/// How many lines scroll out of a console before the oldest ones are forgotten.
+pub const SCROLLBACK: usize = 200;
+
+/// Light gray on black.
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+/// The VGA colors of the ANSI colors (black, red, green, yellow, blue, magenta, cyan, white).
+const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// Code page 437 characters 0x80 to 0xFF.
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼\
+                          ╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// The most parameters an escape sequence can have. Any more are ignored.
+const MAX_PARAMS: usize = 4;
+
+/// Where we are in an escape sequence.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Normal,
+
+    /// After `ESC`.
+    Escape,
+
+    /// After `ESC [`.
+    Csi,
+}
+
+/// A text console drawing into a buffer of `WIDTH * HEIGHT` cells.
+pub struct Console {
+    cells: *mut u16,
+
+    /// Whether `cells` is the screen, so the hardware cursor should follow the cursor.
+    hw_cursor: bool,
+
+    row: usize,
+    col: usize,
+
+    fg: u8,
+    bg: u8,
+    bright: bool,
+    reverse: bool,
+
+    state: State,
+    params: [u16; MAX_PARAMS],
+    nparams: usize,
+
+    /// The character being decoded, and how many continuation bytes it still needs.
+    utf8: u32,
+    utf8_left: u8,
+
+    /// Lines that scrolled off the top, oldest first, if this console keeps them.
+    scrollback: Option<VecDeque<[u16; WIDTH]>>,
+
+    /// The rows written since `take_dirty` was last called (first, last + 1).
+    dirty: (usize, usize),
+}
+
+// The cells are only accessed through the console.
+unsafe impl Send for Console {}
+
+/// The console on the screen.
+static CONSOLE: Mutex<Console> = Mutex::new(Console::new(BUFFER as *mut u16, true));
+
+impl Console {
+    /// A console drawing into `cells`, which must be `WIDTH * HEIGHT` cells long.
+    pub const fn new(cells: *mut u16, hw_cursor: bool) -> Self {
+        Console {
+            cells,
+            hw_cursor,
+            row: 0,
+            col: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bright: false,
+            reverse: false,
+            state: State::Normal,
+            params: [0; MAX_PARAMS],
+            nparams: 0,
+            utf8: 0,
+            utf8_left: 0,
+            scrollback: None,
+            dirty: (HEIGHT, 0),
+        }
+    }
+
+    /// A console drawing into `cells` (`WIDTH * HEIGHT` cells) that remembers the last
+    /// `SCROLLBACK` lines scrolled off the top.
+    pub fn with_scrollback(cells: *mut u16) -> Self {
+        let mut console = Console::new(cells, false);
+        console.scrollback = Some(VecDeque::with_capacity(SCROLLBACK));
+        console
+    }
+
+    /// Copy the cells, cursor, and colors of `other`.
+    pub fn copy_from(&mut self, other: &Console) {
+        for i in 0..WIDTH * HEIGHT {
+            self.set_cell(i / WIDTH, i % WIDTH, other.cell(i / WIDTH, i % WIDTH));
+        }
+        self.row = other.row;
+        self.col = other.col;
+        self.fg = other.fg;
+        self.bg = other.bg;
+        self.bright = other.bright;
+        self.reverse = other.reverse;
+    }
+
+    /// The number of lines in the scrollback.
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.as_ref().map_or(0, |lines| lines.len())
+    }
+
+    /// Screen row `row` as it looks when scrolled back by `back` lines (at most
+    /// `scrollback_len()`).
+    pub fn view_row(&self, row: usize, back: usize) -> [u16; WIDTH] {
+        let len = self.scrollback_len();
+        let line = len + row - back;
+        match self.scrollback {
+            Some(ref lines) if line < len => lines[line],
+            _ => {
+                let mut cells = [0; WIDTH];
+                for (col, cell) in cells.iter_mut().enumerate() {
+                    *cell = self.cell(line - len, col);
+                }
+                cells
+            }
+        }
+    }
+
+    /// The cursor position (row, column).
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col.min(WIDTH - 1))
+    }
+
+    /// Read the cell at the given position.
+    pub fn cell(&self, row: usize, col: usize) -> u16 {
+        assert!(row < HEIGHT && col < WIDTH);
+        unsafe { ptr::read_volatile(self.cells.add(row * WIDTH + col)) }
+    }
+
+    fn set_cell(&mut self, row: usize, col: usize, cell: u16) {
+        self.dirty = (self.dirty.0.min(row), self.dirty.1.max(row + 1));
+        unsafe { ptr::write_volatile(self.cells.add(row * WIDTH + col), cell) }
+    }
+
+    /// The rows written since the last call. Scrolling writes all of them.
+    pub fn take_dirty(&mut self) -> Range<usize> {
+        let (first, end) = mem::replace(&mut self.dirty, (HEIGHT, 0));
+        first..end.max(first)
+    }
+
+    /// The current attribute byte.
+    fn attribute(&self) -> u8 {
+        let fg = self.fg | if self.bright { 8 } else { 0 };
+        let (fg, bg) = if self.reverse {
+            (self.bg, fg)
+        } else {
+            (fg, self.bg)
+        };
+        (bg << 4) | fg
+    }
+
+    /// A blank cell in the current colors.
+    fn blank(&self) -> u16 {
+        (u16::from(self.attribute()) << 8) | u16::from(b' ')
+    }
+
+    /// Clear cells `from..to` (indices into the buffer).
+    fn clear(&mut self, from: usize, to: usize) {
+        let blank = self.blank();
+        for i in from..to {
+            self.set_cell(i / WIDTH, i % WIDTH, blank);
+        }
+    }
+
+    /// Clear the screen and move the cursor to the top left.
+    pub fn clear_screen(&mut self) {
+        self.clear(0, WIDTH * HEIGHT);
+        self.row = 0;
+        self.col = 0;
+        self.update_cursor();
+    }
+
+    /// Scroll everything up a line and clear the bottom line.
+    fn scroll(&mut self) {
+        if self.scrollback.is_some() {
+            let top = self.view_row(0, 0);
+            let lines = self.scrollback.as_mut().unwrap();
+            if lines.len() == SCROLLBACK {
+                lines.pop_front();
+            }
+            lines.push_back(top);
+        }
+
+        for row in 1..HEIGHT {
+            for col in 0..WIDTH {
+                let cell = self.cell(row, col);
+                self.set_cell(row - 1, col, cell);
+            }
+        }
+        self.clear((HEIGHT - 1) * WIDTH, HEIGHT * WIDTH);
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 == HEIGHT {
+            self.scroll();
+        } else {
+            self.row += 1;
+        }
+    }
+
+    /// Put a code page 437 character at the cursor and move it on.
+    fn put(&mut self, c: u8) {
+        if self.col == WIDTH {
+            self.newline();
+        }
+        let cell = (u16::from(self.attribute()) << 8) | u16::from(c);
+        self.set_cell(self.row, self.col, cell);
+        self.col += 1;
+    }
+
+    /// Write some UTF-8 text, which may contain escape sequences.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_byte(b);
+        }
+        self.update_cursor();
+    }
+
+    fn write_byte(&mut self, b: u8) {
+        match self.state {
+            State::Normal => {}
+            State::Escape => {
+                self.state = if b == b'[' {
+                    self.params = [0; MAX_PARAMS];
+                    self.nparams = 0;
+                    State::Csi
+                } else {
+                    State::Normal
+                };
+                return;
+            }
+            State::Csi => {
+                self.csi_byte(b);
+                return;
+            }
+        }
+
+        // Decode UTF-8. Malformed input comes out as `?`.
+        if self.utf8_left > 0 {
+            if b & 0xC0 == 0x80 {
+                self.utf8 = (self.utf8 << 6) | u32::from(b & 0x3F);
+                self.utf8_left -= 1;
+                if self.utf8_left == 0 {
+                    let c = core::char::from_u32(self.utf8).unwrap_or('?');
+                    self.put(to_cp437(c));
+                }
+                return;
+            }
+            self.utf8_left = 0;
+            self.put(b'?');
+        }
+
+        match b {
+            0x1B => self.state = State::Escape,
+            b'\n' => self.newline(),
+            b'\r' => self.col = 0,
+            0x08 => self.col = self.col.saturating_sub(1),
+            b'\t' => {
+                let stop = (self.col / 8 + 1) * 8;
+                while self.col < stop.min(WIDTH) {
+                    self.put(b' ');
+                }
+            }
+            0x20..=0x7E => self.put(b),
+            0xC0..=0xDF => {
+                self.utf8 = u32::from(b & 0x1F);
+                self.utf8_left = 1;
+            }
+            0xE0..=0xEF => {
+                self.utf8 = u32::from(b & 0x0F);
+                self.utf8_left = 2;
+            }
+            0xF0..=0xF7 => {
+                self.utf8 = u32::from(b & 0x07);
+                self.utf8_left = 3;
+            }
+            0x80..=0xFF => self.put(b'?'),
+
+            // Other control characters
+            _ => {}
+        }
+    }
+
+    /// Handle a byte of a `ESC [` sequence.
+    fn csi_byte(&mut self, b: u8) {
+        match b {
+            b'0'..=b'9' => {
+                if self.nparams == 0 {
+                    self.nparams = 1;
+                }
+                if let Some(p) = self.params.get_mut(self.nparams - 1) {
+                    *p = p.saturating_mul(10).saturating_add(u16::from(b - b'0'));
+                }
+            }
+            b';' => self.nparams = (self.nparams.max(1) + 1).min(MAX_PARAMS + 1),
+
+            // Intermediate bytes: nothing we understand uses them.
+            0x20..=0x3F => {}
+
+            // The final byte
+            _ => {
+                self.state = State::Normal;
+                self.csi_command(b);
+            }
+        }
+    }
+
+    /// Parameter `i` of the current sequence, or `default` if it is missing or 0.
+    fn param(&self, i: usize, default: usize) -> usize {
+        match self.params.get(i) {
+            Some(&p) if i < self.nparams && p != 0 => p as usize,
+            _ => default,
+        }
+    }
+
+    fn csi_command(&mut self, cmd: u8) {
+        // If the cursor is past the end of the line, it is really at the end of it.
+        self.col = self.col.min(WIDTH - 1);
+
+        match cmd {
+            b'A' => self.row = self.row.saturating_sub(self.param(0, 1)),
+            b'B' => self.row = (self.row + self.param(0, 1)).min(HEIGHT - 1),
+            b'C' => self.col = (self.col + self.param(0, 1)).min(WIDTH - 1),
+            b'D' => self.col = self.col.saturating_sub(self.param(0, 1)),
+            b'H' | b'f' => {
+                self.row = self.param(0, 1).min(HEIGHT) - 1;
+                self.col = self.param(1, 1).min(WIDTH) - 1;
+            }
+            b'J' | b'K' => {
+                let here = self.row * WIDTH + self.col;
+                let (start, end) = if cmd == b'J' {
+                    (0, WIDTH * HEIGHT)
+                } else {
+                    (self.row * WIDTH, (self.row + 1) * WIDTH)
+                };
+                match self.param(0, 0) {
+                    0 => self.clear(here, end),
+                    1 => self.clear(start, here + 1),
+                    2 => self.clear(start, end),
+                    _ => {}
+                }
+            }
+            b'm' => {
+                // `ESC [ m` is a reset.
+                for i in 0..self.nparams.max(1).min(MAX_PARAMS) {
+                    self.sgr(self.param(i, 0));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a "select graphic rendition" parameter.
+    fn sgr(&mut self, p: usize) {
+        match p {
+            0 => {
+                self.fg = DEFAULT_FG;
+                self.bg = DEFAULT_BG;
+                self.bright = false;
+                self.reverse = false;
+            }
+            1 => self.bright = true,
+            7 => self.reverse = true,
+            22 => self.bright = false,
+            27 => self.reverse = false,
+            30..=37 => self.fg = ANSI_TO_VGA[p - 30],
+            39 => self.fg = DEFAULT_FG,
+            40..=47 => self.bg = ANSI_TO_VGA[p - 40],
+            49 => self.bg = DEFAULT_BG,
+            90..=97 => self.fg = ANSI_TO_VGA[p - 90] | 8,
+            100..=107 => self.bg = ANSI_TO_VGA[p - 100] | 8,
+            _ => {}
+        }
+    }
+
+    /// Move the hardware cursor to the cursor, if this console is on the screen.
+    fn update_cursor(&self) {
+        if self.hw_cursor {
+            show_cursor(Some(self.cursor()));
+        }
+    }
+}
+
+/// Map a character to code page 437.
+fn to_cp437(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        '✔' | '✓' => 0xFB, // √
+        _ => CP437_HIGH
+            .chars()
+            .position(|h| h == c)
+            .map(|i| 0x80 + i as u8)
+            .unwrap_or(b'?'),
+    }
+}
+
+fn crtc_write(reg: u8, value: u8) {
+    unsafe {
+        Port::new(CRTC_INDEX).write(reg);
+        Port::new(CRTC_DATA).write(value);
+    }
+}
+
+/// Put the hardware cursor at the given position (row, column), or hide it.
+pub fn show_cursor(pos: Option<(usize, usize)>) {
+    match pos {
+        Some((row, col)) => {
+            let pos = (row * WIDTH + col) as u16;
+            crtc_write(CRTC_CURSOR_START, CURSOR_FIRST_LINE);
+            crtc_write(CRTC_CURSOR_HI, (pos >> 8) as u8);
+            crtc_write(CRTC_CURSOR_LO, pos as u8);
+        }
+        None => crtc_write(CRTC_CURSOR_START, CURSOR_DISABLE),
+    }
+}
+
+/// Put `cells` on row `row` of the screen.
+pub fn show_row(row: usize, cells: &[u16; WIDTH]) {
+    assert!(row < HEIGHT);
+    let screen = BUFFER as *mut u16;
+    for (col, &cell) in cells.iter().enumerate() {
+        unsafe { ptr::write_volatile(screen.add(row * WIDTH + col), cell) }
+    }
+}
+
+/// Clear the screen and turn on the hardware cursor as an underline.
+pub fn init() {
+    crtc_write(CRTC_CURSOR_END, CURSOR_LAST_LINE);
+    interrupts::without_interrupts(|| CONSOLE.lock().clear_screen());
+}
+
+/// Write to the screen. If the console is busy (e.g. we are in a panic that happened while
+/// writing to it), the output is dropped rather than deadlocking; it still goes to serial.
+///
+/// This is only for boot: once the virtual terminals are up, output goes through them.
+pub fn write(bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        if let Some(mut console) = CONSOLE.try_lock() {
+            console.write_bytes(bytes);
+        }
+    });
+}
+
+/// Copy what is on the screen into `console` (see `vt::init`).
+pub fn hand_over(console: &mut Console) {
+    interrupts::without_interrupts(|| console.copy_from(&CONSOLE.lock()));
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::{Console, HEIGHT, SCROLLBACK, WIDTH};
+
+    /// The character in a cell.
+    fn ch(console: &Console, row: usize, col: usize) -> u8 {
+        console.cell(row, col) as u8
+    }
+
+    /// The attribute of a cell.
+    fn attr(console: &Console, row: usize, col: usize) -> u8 {
+        (console.cell(row, col) >> 8) as u8
+    }
+
+    kernel_test!(vga_writes_and_scrolls, 5, async {
+        let mut cells = vec![0u16; WIDTH * HEIGHT];
+        let mut console = Console::new(cells.as_mut_ptr(), false);
+        console.clear_screen();
+
+        console.write_bytes(b"ab\ncd\tx\r");
+        assert_eq!((ch(&console, 0, 0), ch(&console, 0, 1)), (b'a', b'b'));
+        assert_eq!((ch(&console, 1, 0), ch(&console, 1, 8)), (b'c', b'x'));
+        assert_eq!(console.cursor(), (1, 0));
+
+        // UTF-8 goes to code page 437.
+        console.write_bytes("é✔€".as_bytes());
+        assert_eq!(ch(&console, 1, 0), 0x82);
+        assert_eq!(ch(&console, 1, 1), 0xFB);
+        assert_eq!(ch(&console, 1, 2), b'?');
+
+        // Only the rows written are dirty.
+        console.take_dirty();
+        console.write_bytes(b"\x1b[4;1Hy\x1b[6;1Hy");
+        assert_eq!(console.take_dirty(), 3..6);
+        assert!(console.take_dirty().is_empty());
+        console.write_bytes(b"\x1b[2;1H");
+
+        // Long lines wrap, and the screen scrolls at the bottom.
+        for _ in 0..HEIGHT - 1 {
+            console.write_bytes(b"\n");
+        }
+        assert_eq!(ch(&console, 0, 8), b'x');
+        console.write_bytes(&[b'z'; WIDTH + 1]);
+        assert_eq!(ch(&console, HEIGHT - 2, WIDTH - 1), b'z');
+        assert_eq!(ch(&console, HEIGHT - 1, 0), b'z');
+        assert_eq!(console.cursor(), (HEIGHT - 1, 1));
+        assert_eq!(console.take_dirty(), 0..HEIGHT);
+    });
+
+    kernel_test!(vga_scrollback, 5, async {
+        let mut cells = vec![0u16; WIDTH * HEIGHT];
+        let mut console = Console::with_scrollback(cells.as_mut_ptr());
+        console.clear_screen();
+
+        // Line `i` says `i`, so lines 0 to 4 scroll off the top.
+        for i in 0..HEIGHT + 5 {
+            console.write_bytes(&[b'0' + (i % 10) as u8, b'\n']);
+        }
+        assert_eq!(console.scrollback_len(), 6);
+        assert_eq!(console.view_row(0, 0)[0] as u8, b'6');
+        assert_eq!(console.view_row(0, 6)[0] as u8, b'0');
+        assert_eq!(console.view_row(HEIGHT - 1, 6)[0] as u8, b'4');
+
+        for _ in 0..SCROLLBACK {
+            console.write_bytes(b"\n");
+        }
+        assert_eq!(console.scrollback_len(), SCROLLBACK);
+    });
+
+    kernel_test!(vga_escapes, 5, async {
+        let mut cells = vec![0u16; WIDTH * HEIGHT];
+        let mut console = Console::new(cells.as_mut_ptr(), false);
+        console.clear_screen();
+
+        // Colors
+        console.write_bytes(b"\x1b[31;44mr\x1b[1mR\x1b[0mn\x1b[7mv\x1b[m");
+        assert_eq!(attr(&console, 0, 0), 0x14);
+        assert_eq!(attr(&console, 0, 1), 0x1C);
+        assert_eq!(attr(&console, 0, 2), 0x07);
+        assert_eq!(attr(&console, 0, 3), 0x70);
+
+        // Cursor movement
+        console.write_bytes(b"\x1b[5;10H*\x1b[2A\x1b[3D#");
+        assert_eq!(ch(&console, 4, 9), b'*');
+        assert_eq!(ch(&console, 2, 7), b'#');
+        console.write_bytes(b"\x1b[H");
+        assert_eq!(console.cursor(), (0, 0));
+
+        // Clearing
+        console.write_bytes(b"\x1b[2C\x1b[K");
+        assert_eq!(ch(&console, 0, 1), b'R');
+        assert_eq!(ch(&console, 0, 2), b' ');
+        console.write_bytes(b"\x1b[2J");
+        assert_eq!(ch(&console, 4, 9), b' ');
+    });
+}
@@ -0,0 +1,415 @@
+//! The virtio block device.
+//!
+//! A request is a chain of three buffers in the device's virtqueue: a header saying what to do,
+//! the data, and a status byte that the device writes when it's done. The request's own buffer is
+//! on the kernel heap, which isn't physically contiguous, so every request in flight gets a slot
+//! with DMA memory for all three, and the data is copied in or out. There are `SLOTS` slots, so
+//! that many requests can be in flight at once; the rest wait for a free slot. Requests larger
+//! than a slot are done in pieces, and writes are followed by a flush if the device has a write
+//! cache.
+//!
+//! When the device interrupts, the finished requests are taken off the used ring and completed
+//! (see `block::complete`), which resumes the continuations waiting for them.
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+
+use core::cmp;
+
+use spin::Mutex;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    io::block::{self, BlockDevice, BlockError, Op, Request},
+    memory::alloc_dma,
+    pci,
+};
+
+use super::{Transport, Virtqueue};
+
+/// Feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Device configuration: the capacity in sectors (u64).
+const CONFIG_CAPACITY: u16 = 0;
+
+/// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+/// The status of a request that worked.
+const S_OK: u8 = 0;
+
+/// The device counts sectors of 512 bytes, whatever its real block size is.
+const SECTOR_SIZE: usize = 512;
+
+/// The number of requests in flight per device (at most).
+const SLOTS: usize = 8;
+
+/// The layout of a slot: the header, the status byte, and then the data on the next page.
+const SLOT_HEADER: u64 = 0;
+const SLOT_STATUS: u64 = 16;
+const SLOT_DATA: u64 = 4096;
+
+/// The amount of data a slot can hold, and the pages for a slot.
+const SLOT_DATA_SIZE: usize = 64 * 1024;
+const SLOT_PAGES: usize = 1 + SLOT_DATA_SIZE / 4096;
+
+/// Each request takes three descriptors.
+const DESCS_PER_REQUEST: usize = 3;
+
+/// A request in progress.
+struct Transfer {
+    request: Request,
+
+    /// How many bytes of the buffer have been moved.
+    done: usize,
+
+    /// The length of the piece in the device.
+    len: usize,
+
+    /// Has all of the data been written, so that the device is flushing?
+    flushing: bool,
+}
+
+/// DMA memory for a request.
+struct Slot {
+    paddr: u64,
+    vaddr: u64,
+
+    /// The ID of the slot's chain in the virtqueue.
+    chain: u16,
+
+    transfer: Option<Transfer>,
+}
+
+/// A virtio block device.
+struct Device {
+    transport: Transport,
+    queue: Virtqueue,
+    slots: Vec<Slot>,
+
+    /// Requests waiting for a free slot.
+    waiting: Vec<Request>,
+
+    /// Does the device have a write cache that needs flushing?
+    flush: bool,
+}
+
+/// All of the virtio block devices.
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+/// A disk, as registered with `block`.
+struct Disk {
+    /// The index in `DEVICES`.
+    index: usize,
+    sectors: u64,
+    writable: bool,
+    model: &'static str,
+}
+
+impl Device {
+    /// Hand the next piece of the transfer in slot `i` to the device.
+    fn issue(&mut self, i: usize) -> Result<(), ()> {
+        let slot = &mut self.slots[i];
+        let transfer = slot.transfer.as_mut().unwrap();
+        let op = transfer.request.op;
+
+        let (kind, len) = if transfer.flushing {
+            (T_FLUSH, 0)
+        } else {
+            let len = cmp::min(
+                transfer.request.buffer.len() - transfer.done,
+                SLOT_DATA_SIZE,
+            );
+            (if op == Op::Read { T_IN } else { T_OUT }, len)
+        };
+        transfer.len = len;
+
+        // type (u32), reserved (u32), sector (u64, which must be 0 for a flush)
+        let sector = if transfer.flushing {
+            0
+        } else {
+            transfer.request.lba + (transfer.done / SECTOR_SIZE) as u64
+        };
+        unsafe {
+            ((slot.vaddr + SLOT_HEADER) as *mut u32).write_volatile(kind);
+            ((slot.vaddr + SLOT_HEADER + 4) as *mut u32).write_volatile(0);
+            ((slot.vaddr + SLOT_HEADER + 8) as *mut u64).write_volatile(sector);
+            ((slot.vaddr + SLOT_STATUS) as *mut u8).write_volatile(!0);
+        }
+
+        if op == Op::Write && len > 0 {
+            let data = &transfer.request.buffer[transfer.done..transfer.done + len];
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data.as_ptr(),
+                    (slot.vaddr + SLOT_DATA) as *mut u8,
+                    len,
+                );
+            }
+        }
+
+        let mut chain = vec![(slot.paddr + SLOT_HEADER, 16, false)];
+        if len > 0 {
+            chain.push((slot.paddr + SLOT_DATA, len as u32, op == Op::Read));
+        }
+        chain.push((slot.paddr + SLOT_STATUS, 1, true));
+
+        slot.chain = self.queue.push(&chain).ok_or(())?;
+        self.transport.notify(&self.queue);
+        Ok(())
+    }
+
+    /// Start waiting requests in free slots.
+    fn start_waiting(&mut self) {
+        while !self.waiting.is_empty() {
+            let i = match self.slots.iter().position(|slot| slot.transfer.is_none()) {
+                Some(i) => i,
+                None => return,
+            };
+
+            self.slots[i].transfer = Some(Transfer {
+                request: self.waiting.remove(0),
+                done: 0,
+                len: 0,
+                flushing: false,
+            });
+            if self.issue(i).is_err() {
+                let transfer = self.slots[i].transfer.take().unwrap();
+                block::complete(transfer.request, Err(BlockError::Io));
+            }
+        }
+    }
+
+    /// The device is done with `chain`: start the next piece of its request, or complete it.
+    fn finish(&mut self, chain: u16) {
+        let i = match self
+            .slots
+            .iter()
+            .position(|slot| slot.transfer.is_some() && slot.chain == chain)
+        {
+            Some(i) => i,
+            None => return,
+        };
+
+        let slot = &mut self.slots[i];
+        let mut transfer = slot.transfer.take().unwrap();
+        let status = unsafe { ((slot.vaddr + SLOT_STATUS) as *const u8).read_volatile() };
+
+        if status != S_OK {
+            warn!(
+                "virtio-blk: request for sector {} failed (status {})",
+                transfer.request.lba, status
+            );
+            block::complete(transfer.request, Err(BlockError::Io));
+            return;
+        }
+
+        if !transfer.flushing {
+            if transfer.request.op == Op::Read {
+                let data =
+                    &mut transfer.request.buffer[transfer.done..transfer.done + transfer.len];
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        (slot.vaddr + SLOT_DATA) as *const u8,
+                        data.as_mut_ptr(),
+                        transfer.len,
+                    );
+                }
+            }
+            transfer.done += transfer.len;
+        }
+
+        let more = if transfer.flushing {
+            false
+        } else if transfer.done < transfer.request.buffer.len() {
+            true
+        } else if transfer.request.op == Op::Write && self.flush {
+            transfer.flushing = true;
+            true
+        } else {
+            false
+        };
+
+        if !more {
+            block::complete(transfer.request, Ok(()));
+            return;
+        }
+
+        slot.transfer = Some(transfer);
+        if self.issue(i).is_err() {
+            let transfer = self.slots[i].transfer.take().unwrap();
+            block::complete(transfer.request, Err(BlockError::Io));
+        }
+    }
+
+    fn handle_irq(&mut self) {
+        // Acknowledge first, so that requests finishing from now on interrupt again.
+        if !self.transport.ack_interrupt() {
+            return;
+        }
+
+        while let Some((chain, _)) = self.queue.pop_used() {
+            self.finish(chain);
+        }
+        self.start_waiting();
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn writable(&self) -> bool {
+        self.writable
+    }
+
+    fn model(&self) -> &str {
+        self.model
+    }
+
+    fn start(&self, request: Request) {
+        without_interrupts(|| {
+            let mut devices = DEVICES.lock();
+            let device = &mut devices[self.index];
+            device.waiting.push(request);
+            device.start_waiting();
+        });
+    }
+}
+
+/// Set up a virtio block device (see `pci::DRIVERS`), and register it as a block device.
+pub fn probe(dev: &pci::Device) -> Result<(), &'static str> {
+    let transport = Transport::new(dev)?;
+    let features = transport.start(F_RO | F_FLUSH)?;
+
+    let device = match setup(dev, transport, features) {
+        Ok(device) => device,
+        Err((transport, err)) => {
+            transport.fail();
+            return Err(err);
+        }
+    };
+    device.transport.ready();
+
+    let sectors = device.transport.config_read64(CONFIG_CAPACITY);
+    let nslots = device.slots.len();
+    let model = if device.transport.is_modern() {
+        "virtio"
+    } else {
+        "virtio (legacy)"
+    };
+
+    let index = without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        devices.push(device);
+        devices.len() - 1
+    });
+
+    let name = format!("virtio{}", index);
+    info!(
+        "{}: {} sectors, {} slots{}",
+        name,
+        sectors,
+        nslots,
+        if features & F_RO != 0 {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    block::register(
+        name,
+        Arc::new(Disk {
+            index,
+            sectors,
+            writable: features & F_RO == 0,
+            model,
+        }),
+    );
+
+    Ok(())
+}
+
+/// Set up the virtqueue, slots and interrupt of a device whose features have been negotiated.
+/// Gives the transport back on error.
+fn setup(
+    dev: &pci::Device,
+    transport: Transport,
+    features: u64,
+) -> Result<Device, (Transport, &'static str)> {
+    let queue = match transport.setup_queue(0) {
+        Ok(queue) => queue,
+        Err(err) => return Err((transport, err)),
+    };
+
+    let nslots = cmp::min(SLOTS, queue.size() as usize / DESCS_PER_REQUEST);
+    let slots: Option<Vec<Slot>> = (0..nslots)
+        .map(|_| {
+            let (paddr, vaddr) = alloc_dma(SLOT_PAGES)?;
+            Some(Slot {
+                paddr,
+                vaddr,
+                chain: 0,
+                transfer: None,
+            })
+        })
+        .collect();
+    let slots = match slots {
+        Some(slots) if !slots.is_empty() => slots,
+        _ => return Err((transport, "out of memory")),
+    };
+
+    if let Err(err) = pci::set_handler(dev, handle_irq) {
+        return Err((transport, err));
+    }
+
+    Ok(Device {
+        transport,
+        queue,
+        slots,
+        waiting: Vec::new(),
+        flush: features & F_FLUSH != 0,
+    })
+}
+
+/// Handle an interrupt of any of the devices (they may share IRQs).
+fn handle_irq() {
+    for device in DEVICES.lock().iter_mut() {
+        device.handle_irq();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use crate::io::block::{self, Op};
+
+    /// The most sectors the test reads, which is more than fit in a slot.
+    const SECTORS: u64 = 256;
+
+    // This needs the scratch virtio disk from `test-args` in Cargo.toml.
+    kernel_test!(virtio_blk_requests_in_flight, 10, async {
+        let disk = block::find("virtio0").expect("no virtio disk");
+        assert!(block::get(disk).unwrap().sectors() >= SECTORS);
+
+        let whole = block::read(disk, 0, SECTORS as usize).await.unwrap();
+
+        let ids: Vec<u64> = (0..SECTORS / 8)
+            .map(|i| block::submit(disk, Op::Read, i * 8, vec![0; 8 * 512]).unwrap())
+            .collect();
+        for (i, id) in ids.into_iter().enumerate() {
+            let part = block::finish(id).await.unwrap();
+            assert_eq!(part[..], whole[i * 4096..(i + 1) * 4096]);
+        }
+    });
+}
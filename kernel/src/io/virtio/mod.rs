@@ -0,0 +1,534 @@
+//! Virtio devices on PCI: the transports and split virtqueues. See `blk` for the block device.
+//!
+//! There are two PCI transports. Legacy devices have all of their registers in I/O BAR 0. Modern
+//! (virtio 1.0) devices say where their registers are with vendor-specific PCI capabilities,
+//! usually in a memory BAR. Transitional devices have both, and we use the modern registers.
+//!
+//! Requests are put in a split virtqueue as chains of descriptors pointing at physically
+//! contiguous buffers (see `memory::alloc_dma`). The device takes them from the available ring,
+//! and puts them on the used ring when it's done, which it tells us with an interrupt.
+
+pub mod blk;
+
+use alloc::vec::Vec;
+
+use core::sync::atomic::{fence, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::{
+    memory::alloc_dma,
+    pci::{self, Bar},
+};
+
+/// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Interrupt status: a virtqueue has new used buffers.
+const ISR_QUEUE: u8 = 1 << 0;
+
+/// Feature bit: the device is a virtio 1.0 device. Modern devices insist on it.
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Legacy registers (offsets in I/O BAR 0). The device-specific configuration follows them.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;
+
+/// Modern common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// The PCI capability ID of virtio's capabilities.
+const CAP_VENDOR: u8 = 0x09;
+
+/// Virtio capability fields (offsets from the capability)
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+
+/// Virtio capability types
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+/// Descriptor flags
+const DESC_NEXT: u16 = 1 << 0;
+const DESC_WRITE: u16 = 1 << 1;
+
+/// The largest virtqueue we set up (legacy devices choose the size themselves).
+const MAX_QUEUE_SIZE: u16 = 128;
+
+/// Legacy devices want the used ring aligned to a page.
+const PAGE_SIZE: u64 = 4096;
+
+/// How to get at a device's registers.
+pub enum Transport {
+    Legacy {
+        /// I/O BAR 0
+        port: u16,
+    },
+
+    Modern {
+        /// The virtual addresses of the register structures.
+        common: u64,
+        notify: u64,
+        isr: u64,
+        device: u64,
+
+        /// The notification address of a queue is `notify + notify_off * notify_multiplier`.
+        notify_multiplier: u32,
+    },
+}
+
+/// A descriptor of a split virtqueue.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue. Its memory has the descriptor table, the available ring, and, at the next
+/// page, the used ring, which is the layout legacy devices expect.
+pub struct Virtqueue {
+    /// The index of the queue in its device.
+    index: u16,
+
+    /// The number of descriptors.
+    size: u16,
+
+    /// The physical and virtual address of the queue memory.
+    paddr: u64,
+    vaddr: u64,
+
+    /// Where the rings are, relative to the start.
+    avail: u64,
+    used: u64,
+
+    /// Descriptors that aren't in a chain.
+    free: Vec<u16>,
+
+    /// The index of the next entry of the available ring.
+    avail_idx: u16,
+
+    /// The index of the next entry of the used ring to look at.
+    used_idx: u16,
+
+    /// Where to notify the device about this queue (modern devices only).
+    notify_off: u16,
+}
+
+fn read_mmio<T>(addr: u64) -> T {
+    unsafe { (addr as *const T).read_volatile() }
+}
+
+fn write_mmio<T>(addr: u64, value: T) {
+    unsafe { (addr as *mut T).write_volatile(value) }
+}
+
+impl Transport {
+    /// Find the registers of `dev`, and turn it on.
+    pub fn new(dev: &pci::Device) -> Result<Self, &'static str> {
+        pci::enable(dev);
+
+        if let Some(transport) = Self::modern(dev) {
+            return Ok(transport);
+        }
+
+        match dev.bars[0] {
+            Some(Bar::Io { port, .. }) => Ok(Transport::Legacy { port }),
+            _ => Err("no virtio registers"),
+        }
+    }
+
+    /// Find the registers of a modern device from its capabilities.
+    fn modern(dev: &pci::Device) -> Option<Self> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+
+        // The BARs mapped so far, and their virtual addresses.
+        let mut mapped: Vec<(usize, u64)> = Vec::new();
+
+        for (_, cap) in pci::capabilities(dev)
+            .into_iter()
+            .filter(|&(id, _)| id == CAP_VENDOR)
+        {
+            // The first capability of each type is the one to use.
+            let cfg_type = pci::read8(dev.address, cap + CAP_CFG_TYPE);
+            let found = match cfg_type {
+                CFG_COMMON => common.is_some(),
+                CFG_NOTIFY => notify.is_some(),
+                CFG_ISR => isr.is_some(),
+                CFG_DEVICE => device.is_some(),
+                _ => continue,
+            };
+            if found {
+                continue;
+            }
+
+            let bar = pci::read8(dev.address, cap + CAP_BAR) as usize;
+            if bar >= dev.bars.len() {
+                continue;
+            }
+            let vaddr = match mapped.iter().find(|&&(mapped, _)| mapped == bar) {
+                Some(&(_, vaddr)) => vaddr,
+                None => {
+                    let vaddr = pci::map_bar(dev, bar)?;
+                    mapped.push((bar, vaddr));
+                    vaddr
+                }
+            };
+
+            let addr = vaddr + pci::read32(dev.address, cap + CAP_OFFSET) as u64;
+            match cfg_type {
+                CFG_COMMON => common = Some(addr),
+                CFG_NOTIFY => {
+                    notify = Some(addr);
+                    notify_multiplier = pci::read32(dev.address, cap + CAP_NOTIFY_MULTIPLIER);
+                }
+                CFG_ISR => isr = Some(addr),
+                _ => device = Some(addr),
+            }
+        }
+
+        Some(Transport::Modern {
+            common: common?,
+            notify: notify?,
+            isr: isr?,
+            device: device?,
+            notify_multiplier,
+        })
+    }
+
+    /// Is this the modern transport?
+    pub fn is_modern(&self) -> bool {
+        match self {
+            Transport::Legacy { .. } => false,
+            Transport::Modern { .. } => true,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => read_mmio(common + COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => write_mmio(common + COMMON_STATUS, status),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::<u32>::new(port + LEGACY_DEVICE_FEATURES).read() as u64
+            },
+            Transport::Modern { common, .. } => (0..2).fold(0, |features, i| {
+                write_mmio(common + COMMON_DEVICE_FEATURE_SELECT, i as u32);
+                let half: u32 = read_mmio(common + COMMON_DEVICE_FEATURE);
+                features | (half as u64) << (32 * i)
+            }),
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Transport::Modern { common, .. } => {
+                for i in 0..2 {
+                    write_mmio(common + COMMON_DRIVER_FEATURE_SELECT, i as u32);
+                    write_mmio(
+                        common + COMMON_DRIVER_FEATURE,
+                        (features >> (32 * i)) as u32,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Reset the device and negotiate features: we take the ones in `wanted` that the device
+    /// has. Returns them.
+    pub fn start(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        if !self.is_modern() {
+            let features = self.device_features() & wanted;
+            self.set_driver_features(features);
+            return Ok(features);
+        }
+
+        let features = self.device_features() & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("device doesn't support virtio 1.0");
+        }
+        self.set_driver_features(features);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err("device doesn't accept our features");
+        }
+
+        Ok(features)
+    }
+
+    /// Allocate virtqueue `index` and hand it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, &'static str> {
+        let max = match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_QUEUE_SELECT).write(index);
+                Port::<u16>::new(port + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => {
+                write_mmio(common + COMMON_QUEUE_SELECT, index);
+                read_mmio(common + COMMON_QUEUE_SIZE)
+            }
+        };
+        if max == 0 {
+            return Err("no such virtqueue");
+        }
+
+        match *self {
+            Transport::Legacy { port } => {
+                let queue = Virtqueue::new(index, max).ok_or("out of memory")?;
+                unsafe {
+                    Port::new(port + LEGACY_QUEUE_PFN).write((queue.paddr / PAGE_SIZE) as u32);
+                }
+                Ok(queue)
+            }
+
+            Transport::Modern { common, .. } => {
+                // The size has to be a power of two, which the maximum is.
+                let size = core::cmp::min(max, MAX_QUEUE_SIZE);
+                let mut queue = Virtqueue::new(index, size).ok_or("out of memory")?;
+
+                write_mmio(common + COMMON_QUEUE_SIZE, size);
+                write_mmio(common + COMMON_QUEUE_DESC, queue.paddr);
+                write_mmio(common + COMMON_QUEUE_DRIVER, queue.paddr + queue.avail);
+                write_mmio(common + COMMON_QUEUE_DEVICE, queue.paddr + queue.used);
+                queue.notify_off = read_mmio(common + COMMON_QUEUE_NOTIFY_OFF);
+                write_mmio(common + COMMON_QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            }
+        }
+    }
+
+    /// Tell the device that we are ready to use it.
+    pub fn ready(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device that we've given up on it.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Tell the device that there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_QUEUE_NOTIFY).write(queue.index)
+            },
+            Transport::Modern {
+                notify,
+                notify_multiplier,
+                ..
+            } => write_mmio(
+                notify + queue.notify_off as u64 * notify_multiplier as u64,
+                queue.index,
+            ),
+        }
+    }
+
+    /// Acknowledge the device's interrupt. Returns whether it was for a virtqueue.
+    pub fn ack_interrupt(&self) -> bool {
+        let isr: u8 = match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_ISR).read() },
+            Transport::Modern { isr, .. } => read_mmio(isr),
+        };
+        isr & ISR_QUEUE != 0
+    }
+
+    /// Read the 32-bit field at `offset` in the device-specific configuration.
+    pub fn config_read32(&self, offset: u16) -> u32 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_CONFIG + offset).read()
+            },
+            Transport::Modern { device, .. } => read_mmio(device + offset as u64),
+        }
+    }
+
+    /// Read the 64-bit field at `offset` in the device-specific configuration.
+    pub fn config_read64(&self, offset: u16) -> u64 {
+        self.config_read32(offset) as u64 | (self.config_read32(offset + 4) as u64) << 32
+    }
+}
+
+impl Virtqueue {
+    /// Allocate a queue with `size` descriptors.
+    fn new(index: u16, size: u16) -> Option<Self> {
+        let avail = size as u64 * 16;
+        let used = (avail + 6 + 2 * size as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let len = used + 6 + 8 * size as u64;
+        let (paddr, vaddr) = alloc_dma(((len + PAGE_SIZE - 1) / PAGE_SIZE) as usize)?;
+
+        Some(Virtqueue {
+            index,
+            size,
+            paddr,
+            vaddr,
+            avail,
+            used,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            used_idx: 0,
+            notify_off: 0,
+        })
+    }
+
+    /// The number of descriptors.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Put a chain of buffers on the available ring: their physical addresses, lengths, and
+    /// whether the device writes to them (rather than reads them). The device has to be notified
+    /// afterwards (see `Transport::notify`). Returns the chain's ID (the index of its first
+    /// descriptor), or `None` if there aren't enough free descriptors.
+    pub fn push(&mut self, buffers: &[(u64, u32, bool)]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+
+        let descs: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+        for (i, &(addr, len, write)) in buffers.iter().enumerate() {
+            let mut desc = Descriptor {
+                addr,
+                len,
+                flags: if write { DESC_WRITE } else { 0 },
+                next: 0,
+            };
+            if let Some(&next) = descs.get(i + 1) {
+                desc.flags |= DESC_NEXT;
+                desc.next = next;
+            }
+            write_mmio(self.vaddr + descs[i] as u64 * 16, desc);
+        }
+
+        // The descriptors have to be in memory before the device can see the new ring entry, and
+        // the entry before the new index.
+        let slot = (self.avail_idx % self.size) as u64;
+        write_mmio(self.vaddr + self.avail + 4 + 2 * slot, descs[0]);
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        write_mmio(self.vaddr + self.avail + 2, self.avail_idx);
+        fence(Ordering::SeqCst);
+
+        Some(descs[0])
+    }
+
+    /// Take the next chain that the device is done with, and free its descriptors. Returns its ID
+    /// and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let idx: u16 = read_mmio(self.vaddr + self.used + 2);
+        if idx == self.used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = (self.used_idx % self.size) as u64;
+        let id: u32 = read_mmio(self.vaddr + self.used + 4 + 8 * slot);
+        let len: u32 = read_mmio(self.vaddr + self.used + 8 + 8 * slot);
+        self.used_idx = self.used_idx.wrapping_add(1);
+
+        let mut desc = id as u16;
+        loop {
+            self.free.push(desc);
+            let Descriptor { flags, next, .. } = read_mmio(self.vaddr + desc as u64 * 16);
+            if flags & DESC_NEXT == 0 {
+                break;
+            }
+            desc = next;
+        }
+
+        Some((id as u16, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_mmio, write_mmio, Descriptor, Virtqueue, DESC_NEXT, DESC_WRITE};
+
+    /// Play the device: put chain `id` on the used ring of `queue`.
+    fn use_chain(queue: &Virtqueue, id: u16, len: u32) {
+        let idx: u16 = read_mmio(queue.vaddr + queue.used + 2);
+        let slot = (idx % queue.size) as u64;
+        write_mmio(queue.vaddr + queue.used + 4 + 8 * slot, id as u32);
+        write_mmio(queue.vaddr + queue.used + 8 + 8 * slot, len);
+        write_mmio(queue.vaddr + queue.used + 2, idx.wrapping_add(1));
+    }
+
+    kernel_test!(virtqueue_chains, 5, async {
+        let mut queue = Virtqueue::new(0, 4).unwrap();
+        assert_eq!(queue.used, 4096);
+
+        let first = queue
+            .push(&[(0x1000, 16, false), (0x2000, 512, true), (0x3000, 1, true)])
+            .unwrap();
+        assert_eq!(queue.free.len(), 1);
+
+        // The chain is linked up, and is in the available ring.
+        let desc: Descriptor = read_mmio(queue.vaddr + first as u64 * 16);
+        assert_eq!(desc.flags, DESC_NEXT);
+        let desc: Descriptor = read_mmio(queue.vaddr + desc.next as u64 * 16);
+        assert_eq!((desc.addr, desc.len), (0x2000, 512));
+        assert_eq!(desc.flags, DESC_NEXT | DESC_WRITE);
+        assert_eq!(read_mmio::<u16>(queue.vaddr + queue.avail + 2), 1);
+        assert_eq!(read_mmio::<u16>(queue.vaddr + queue.avail + 4), first);
+
+        // Not enough descriptors for another one.
+        assert_eq!(queue.push(&[(0x4000, 16, false), (0x5000, 1, true)]), None);
+
+        assert_eq!(queue.pop_used(), None);
+        use_chain(&queue, first, 513);
+        assert_eq!(queue.pop_used(), Some((first, 513)));
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.free.len(), 4);
+    });
+}
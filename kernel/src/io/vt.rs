@@ -0,0 +1,497 @@
+//! Virtual terminals.
+//!
+//! There are `COUNT` terminals, each with its own screen (with scrollback) and its own input. One
+//! of them is on screen at a time: Alt+F1 to Alt+F6 switch between them, and Shift+PageUp and
+//! Shift+PageDown scroll the one on screen back through what scrolled off the top.
+//!
+//! A terminal belongs to whoever opens it (see `open`), usually a continuation that reads from it
+//! with `task::terminal`. While the terminal on screen has an owner, it gets the text typed on the
+//! keyboard, unless the keyboard's focus wants raw key events. Otherwise, the keyboard's own
+//! consumers get it (see `kbd`).
+//!
+//! Input goes through a line discipline: typed characters are echoed, Backspace erases the last
+//! one, and the owner only gets a line once Enter is pressed. Ctrl-C throws away the line being
+//! typed and any input not read yet, and sends the owner `Event::Interrupt` instead.
+//!
+//! Terminal 0 (Alt+F1) is the console: `printk!` output goes there as well as to the serial port,
+//! and input from the serial console is typed there (see `serial::console_input`).
+
+use alloc::{boxed::Box, collections::vec_deque::VecDeque, vec, vec::Vec};
+
+use core::ops::Range;
+
+use spin::{Mutex, MutexGuard};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    cap::{Capability, ResourceHandle, UnregisteredResourceHandle},
+    continuation::Event,
+    debug::CONSOLE_PORT,
+};
+
+use super::{
+    kbd::{KeyCode, KeyEvent},
+    serial,
+    vga::{self, Console, HEIGHT, WIDTH},
+};
+
+/// The number of terminals.
+pub const COUNT: usize = 6;
+
+/// The terminal `printk!` writes to.
+pub const CONSOLE: usize = 0;
+
+/// The longest line that can be typed (in bytes, not counting the newline).
+const MAX_LINE: usize = 256;
+
+/// The most finished input a terminal buffers for its owner. Lines that don't fit are dropped.
+const MAX_INPUT: usize = 4096;
+
+/// How far Shift+PageUp and Shift+PageDown scroll.
+const SCROLL_STEP: usize = HEIGHT / 2;
+
+/// Control characters
+const BACKSPACE: u8 = 0x08;
+const CTRL_C: u8 = 0x03;
+
+/// How many times to try to take the terminals' lock before giving up on printing (see `print`).
+const LOCK_TRIES: usize = 100_000;
+
+/// The line discipline of a terminal: the line being typed, and the input not read yet.
+struct LineDiscipline {
+    line: Vec<u8>,
+
+    /// Finished lines, not read yet.
+    input: VecDeque<u8>,
+
+    /// Ctrl-C was typed, and the owner hasn't been told yet.
+    interrupted: bool,
+
+    /// A character didn't fit in `line`, so drop the rest of its bytes too.
+    skipping: bool,
+}
+
+impl LineDiscipline {
+    fn new() -> Self {
+        LineDiscipline {
+            line: Vec::new(),
+            input: VecDeque::new(),
+            interrupted: false,
+            skipping: false,
+        }
+    }
+
+    /// Forget everything.
+    fn reset(&mut self) {
+        self.line.clear();
+        self.input.clear();
+        self.interrupted = false;
+        self.skipping = false;
+    }
+
+    /// Handle a typed byte (of UTF-8), adding whatever should be echoed to `echo`.
+    fn feed(&mut self, b: u8, echo: &mut Vec<u8>) {
+        let continuation = b & 0xC0 == 0x80;
+        if !continuation {
+            self.skipping = false;
+        }
+
+        match b {
+            b'\n' => {
+                echo.push(b'\n');
+                if self.input.len() + self.line.len() < MAX_INPUT {
+                    self.input.extend(self.line.iter().cloned());
+                    self.input.push_back(b'\n');
+                }
+                self.line.clear();
+            }
+
+            BACKSPACE => {
+                // Erase a whole character.
+                while let Some(last) = self.line.pop() {
+                    if last & 0xC0 != 0x80 {
+                        echo.extend_from_slice(b"\x08 \x08");
+                        break;
+                    }
+                }
+            }
+
+            CTRL_C => {
+                echo.extend_from_slice(b"^C\n");
+                self.line.clear();
+                self.input.clear();
+                self.interrupted = true;
+            }
+
+            // Other control characters
+            0x00..=0x1F | 0x7F => {}
+
+            _ if continuation && self.skipping => {}
+
+            _ => {
+                // Leave room for the rest of the character.
+                let len = match b {
+                    0xF0..=0xFF => 4,
+                    0xE0..=0xEF => 3,
+                    0xC0..=0xDF => 2,
+                    _ => 1,
+                };
+                if continuation || self.line.len() + len <= MAX_LINE {
+                    self.line.push(b);
+                    echo.push(b);
+                } else {
+                    self.skipping = true;
+                }
+            }
+        }
+    }
+
+    /// The next input for the owner, if any: `Event::Interrupt` or `Event::Terminal`.
+    fn next(&mut self) -> Option<Event> {
+        if self.interrupted {
+            self.interrupted = false;
+            return Some(Event::Interrupt);
+        }
+
+        self.input.pop_front().map(Event::Terminal)
+    }
+}
+
+/// A virtual terminal.
+struct Terminal {
+    console: Console,
+
+    /// The capability of whoever opened the terminal.
+    owner: Option<ResourceHandle>,
+
+    input: LineDiscipline,
+
+    /// How many lines the terminal is scrolled back, if it is on screen.
+    back: usize,
+}
+
+struct Terminals {
+    list: Vec<Terminal>,
+
+    /// The one on screen.
+    active: usize,
+}
+
+impl Terminals {
+    fn get(&mut self, term: ResourceHandle) -> Option<&mut Terminal> {
+        self.list
+            .iter_mut()
+            .find(|terminal| terminal.owner == Some(term))
+    }
+
+    /// Copy the terminal on screen to the screen.
+    fn show(&self) {
+        let terminal = &self.list[self.active];
+        for row in 0..HEIGHT {
+            vga::show_row(row, &terminal.console.view_row(row, terminal.back));
+        }
+        self.show_cursor();
+    }
+
+    /// Copy rows `rows` of the terminal on screen to the screen. When it is scrolled back, they
+    /// are further down the screen, if they are on it at all.
+    fn show_rows(&self, rows: Range<usize>) {
+        let terminal = &self.list[self.active];
+        for row in rows {
+            let row = row + terminal.back;
+            if row < HEIGHT {
+                vga::show_row(row, &terminal.console.view_row(row, terminal.back));
+            }
+        }
+        self.show_cursor();
+    }
+
+    /// Move the cursor to the cursor of the terminal on screen.
+    fn show_cursor(&self) {
+        let terminal = &self.list[self.active];
+
+        // The cursor is only shown when looking at the bottom.
+        if terminal.back == 0 {
+            vga::show_cursor(Some(terminal.console.cursor()));
+        } else {
+            vga::show_cursor(None);
+        }
+    }
+
+    /// Write `bytes` to terminal `n`. Only the rows that changed are redrawn, which is all of
+    /// them if the terminal scrolled.
+    fn write(&mut self, n: usize, bytes: &[u8]) {
+        let console = &mut self.list[n].console;
+        console.write_bytes(bytes);
+        let rows = console.take_dirty();
+        if n == self.active {
+            self.show_rows(rows);
+        }
+    }
+
+    /// Type `b` on terminal `n`. This is dropped if nobody owns the terminal.
+    fn type_byte(&mut self, n: usize, b: u8) {
+        let terminal = &mut self.list[n];
+        if terminal.owner.is_none() {
+            return;
+        }
+
+        let mut echo = Vec::new();
+        terminal.input.feed(b, &mut echo);
+
+        // Typing scrolls back down to the bottom.
+        let scrolled = terminal.back != 0;
+        terminal.back = 0;
+        if scrolled && n == self.active {
+            self.show();
+        }
+        self.write(n, &echo);
+
+        // Whoever is typing on the serial console wants to see the echo too.
+        if n == CONSOLE {
+            serial::write(CONSOLE_PORT, &echo);
+        }
+    }
+}
+
+static TERMINALS: Mutex<Option<Terminals>> = Mutex::new(None);
+
+/// Lock the terminals, giving up after a while (see `print`).
+fn try_lock() -> Option<MutexGuard<'static, Option<Terminals>>> {
+    (0..LOCK_TRIES).find_map(|_| TERMINALS.try_lock())
+}
+
+/// Set up the terminals. The console terminal takes over what is on the screen.
+pub fn init() {
+    let list = (0..COUNT)
+        .map(|n| {
+            // The terminals live forever, so their cells do too.
+            let cells = Box::leak(vec![0u16; WIDTH * HEIGHT].into_boxed_slice());
+            let mut console = Console::with_scrollback(cells.as_mut_ptr());
+            console.clear_screen();
+            if n == CONSOLE {
+                vga::hand_over(&mut console);
+            }
+
+            Terminal {
+                console,
+                owner: None,
+                input: LineDiscipline::new(),
+                back: 0,
+            }
+        })
+        .collect();
+
+    without_interrupts(|| {
+        let terminals = Terminals {
+            list,
+            active: CONSOLE,
+        };
+        terminals.show();
+        *TERMINALS.lock() = Some(terminals);
+    });
+}
+
+/// Write `bytes` to the console terminal, or straight to the screen if the terminals aren't set up
+/// yet. If the terminals are busy (e.g. we are in a panic that happened while writing to them),
+/// the output is dropped rather than deadlocking.
+pub fn print(bytes: &[u8]) {
+    without_interrupts(|| {
+        if let Some(mut terminals) = try_lock() {
+            match terminals.as_mut() {
+                Some(terminals) => terminals.write(CONSOLE, bytes),
+                None => vga::write(bytes),
+            }
+        }
+    });
+}
+
+/// Take ownership of terminal `n`.
+pub fn open(n: usize) -> Result<ResourceHandle, &'static str> {
+    without_interrupts(|| {
+        let mut terminals = TERMINALS.lock();
+        let terminal = terminals
+            .as_mut()
+            .unwrap()
+            .list
+            .get_mut(n)
+            .ok_or("no such terminal")?;
+
+        if terminal.owner.is_some() {
+            return Err("terminal in use");
+        }
+
+        let term = UnregisteredResourceHandle::new(Capability::Terminal(n)).register();
+        terminal.owner = Some(term);
+        terminal.input.reset();
+
+        Ok(term)
+    })
+}
+
+/// Give up ownership of a terminal. Its screen is left as it is.
+#[allow(dead_code)]
+pub fn close(term: ResourceHandle) {
+    without_interrupts(|| {
+        if let Some(terminal) = TERMINALS.lock().as_mut().unwrap().get(term) {
+            terminal.owner = None;
+            terminal.input.reset();
+        }
+    });
+}
+
+/// Write `bytes` to the terminal owned through `term`.
+#[allow(dead_code)]
+pub fn write(term: ResourceHandle, bytes: &[u8]) {
+    without_interrupts(|| {
+        let mut terminals = TERMINALS.lock();
+        let terminals = terminals.as_mut().unwrap();
+        if let Some(n) = terminals
+            .list
+            .iter()
+            .position(|terminal| terminal.owner == Some(term))
+        {
+            terminals.write(n, bytes);
+        }
+    });
+}
+
+/// Type `b` on terminal `n`, as if it came from the keyboard (e.g. for input from the serial
+/// console).
+pub fn push(n: usize, b: u8) {
+    without_interrupts(|| {
+        TERMINALS.lock().as_mut().unwrap().type_byte(n, b);
+    });
+}
+
+/// Return the next input for the terminal owned through `term`, if there is any:
+/// `Event::Terminal` or `Event::Interrupt`.
+pub fn next_input(term: ResourceHandle) -> Option<Event> {
+    without_interrupts(|| TERMINALS.lock().as_mut()?.get(term)?.input.next())
+}
+
+/// Handle a key event from the keyboard interrupt handler. Returns true if the event was for the
+/// terminals, so the keyboard's consumers shouldn't get it: the terminal hotkeys, and keys that
+/// type something on a terminal with an owner.
+pub fn handle_key(event: &KeyEvent) -> bool {
+    let mut terminals = TERMINALS.lock();
+    let terminals = match terminals.as_mut() {
+        Some(terminals) => terminals,
+        None => return false,
+    };
+
+    // Switch terminals with Alt+F1 to Alt+F6.
+    let n = event.code.0.wrapping_sub(KeyCode::F1.0) as usize;
+    if event.modifiers.alt && n < COUNT {
+        if event.pressed && n != terminals.active {
+            terminals.active = n;
+            terminals.show();
+        }
+        return true;
+    }
+
+    // Scroll back with Shift+PageUp and Shift+PageDown.
+    let scroll_key = event.code == KeyCode::PAGE_UP || event.code == KeyCode::PAGE_DOWN;
+    if event.modifiers.shift && scroll_key {
+        if event.pressed {
+            let terminal = &mut terminals.list[terminals.active];
+            terminal.back = if event.code == KeyCode::PAGE_UP {
+                (terminal.back + SCROLL_STEP).min(terminal.console.scrollback_len())
+            } else {
+                terminal.back.saturating_sub(SCROLL_STEP)
+            };
+            terminals.show();
+        }
+        return true;
+    }
+
+    let active = terminals.active;
+    if terminals.list[active].owner.is_none() || event.text.as_str().is_empty() {
+        return false;
+    }
+
+    for b in event.text.as_str().bytes() {
+        terminals.type_byte(active, b);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::continuation::Event;
+
+    use super::{close, next_input, open, push, LineDiscipline, MAX_LINE};
+
+    /// Feed `bytes` to `ld` and return the echo.
+    fn feed(ld: &mut LineDiscipline, bytes: &[u8]) -> Vec<u8> {
+        let mut echo = Vec::new();
+        for &b in bytes {
+            ld.feed(b, &mut echo);
+        }
+        echo
+    }
+
+    /// The input `ld` has ready, with `!` for an interrupt.
+    fn input(ld: &mut LineDiscipline) -> Vec<u8> {
+        let mut input = Vec::new();
+        while let Some(event) = ld.next() {
+            match event {
+                Event::Terminal(b) => input.push(b),
+                Event::Interrupt => input.push(b'!'),
+                _ => unreachable!(),
+            }
+        }
+        input
+    }
+
+    kernel_test!(line_discipline, 5, async {
+        let mut ld = LineDiscipline::new();
+
+        // Nothing until the line is finished
+        assert_eq!(feed(&mut ld, b"ab\x08c"), b"ab\x08 \x08c");
+        assert_eq!(input(&mut ld), b"");
+        assert_eq!(feed(&mut ld, b"\n"), b"\n");
+        assert_eq!(input(&mut ld), b"ac\n");
+
+        // Backspace erases whole characters, and not past the start of the line.
+        feed(&mut ld, "é\x08\x08x\n".as_bytes());
+        assert_eq!(input(&mut ld), b"x\n");
+
+        // Ctrl-C throws away the line and unread input.
+        feed(&mut ld, b"one\ntwo\x03three\n");
+        assert_eq!(input(&mut ld), b"!three\n");
+
+        // Long lines are cut off.
+        let long = [b'y'; MAX_LINE + 10];
+        feed(&mut ld, &long);
+        feed(&mut ld, b"\n");
+        assert_eq!(input(&mut ld).len(), MAX_LINE + 1);
+    });
+
+    kernel_test!(terminal_input, 5, async {
+        // Terminal 5 isn't used by anything else.
+        let term = open(5).unwrap();
+        assert!(open(5).is_err());
+
+        for &b in b"hi\n" {
+            push(5, b);
+        }
+        match next_input(term) {
+            Some(Event::Terminal(b'h')) => {}
+            _ => panic!("expected input"),
+        }
+
+        close(term);
+        assert!(next_input(term).is_none());
+
+        // Nobody owns it now, so typing on it does nothing.
+        push(5, b'x');
+        let term = open(5).unwrap();
+        assert!(next_input(term).is_none());
+        close(term);
+    });
+}
@@ -0,0 +1,343 @@
+//! Kernel logging: levels, per-module filters, and the `dmesg` ring buffer.
+//!
+//! Log with `error!`, `warn!`, `info!`, `debug!`, and `trace!`, which take the same arguments as
+//! `printk!`. Each record is stamped with the time since boot and the module it came from:
+//!
+//! ```txt
+//! [    1.234] INFO  kernel::smp: smp inited - 4 cpus
+//! ```
+//!
+//! Records that pass the filter are written to the serial console and kept in a ring buffer, which
+//! can be read back with `read_dmesg` (and from user mode with the `dmesg` system call). Once the
+//! ring buffer is full, the oldest records are overwritten.
+//!
+//! By default, records up to `Level::Info` pass. `set_module_level` changes that for a module and
+//! its submodules; for example, page faults are logged at `Level::Debug` by
+//! `kernel::memory::paging`.
+//!
+//! `printk!` is still there for output that isn't a log record, like panics and test results.
+
+use alloc::{string::String, vec::Vec};
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use x86_64::instructions::interrupts;
+
+use crate::{debug::Debug, time::SysTime};
+
+/// The size of the `dmesg` ring buffer (bytes).
+const DMESG_SIZE: usize = 64 * 1024;
+
+/// The longest record (bytes, including the header). Longer ones are truncated.
+const MAX_RECORD: usize = 512;
+
+/// The level of records that pass if no module filter says otherwise.
+const DEFAULT_LEVEL: Level = Level::Info;
+
+/// How important a log record is. Each level includes the ones before it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Parse a level from its name (e.g. `debug`), ignoring case.
+    pub fn from_name(name: &str) -> Option<Level> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .iter()
+        .cloned()
+        .find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Which records pass.
+struct Filters {
+    /// The level for modules that don't have a filter.
+    default: Level,
+
+    /// Module path prefixes and their levels.
+    modules: Vec<(String, Level)>,
+}
+
+impl Filters {
+    /// The most verbose level that passes for `module`. The filter for the longest matching
+    /// module path wins.
+    fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix
+                    || (module.starts_with(prefix.as_str())
+                        && module[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    /// The most verbose level that passes for any module.
+    fn max(&self) -> Level {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, core::cmp::max)
+    }
+}
+
+/// The log filters.
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default: DEFAULT_LEVEL,
+    modules: Vec::new(),
+});
+
+/// `FILTERS.max()`, so that most records that don't pass can be dropped without taking a lock.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+
+/// The `dmesg` ring buffer. It holds the text of the most recent records.
+struct Ring {
+    buf: [u8; DMESG_SIZE],
+
+    /// The number of bytes ever written. The next byte goes at `written % DMESG_SIZE`.
+    written: usize,
+}
+
+impl Ring {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[self.written % DMESG_SIZE] = b;
+            self.written += 1;
+        }
+    }
+
+    /// Copy the most recent records that fit into `out`. Returns the number of bytes copied.
+    fn read(&self, out: &mut [u8]) -> usize {
+        let available = core::cmp::min(self.written, DMESG_SIZE);
+        let oldest = self.written - available;
+        let mut start = self.written - core::cmp::min(available, out.len());
+
+        // Don't start in the middle of a record. We can only tell if we still have the byte before
+        // `start` (or if nothing came before it).
+        let aligned = if start > oldest {
+            self.buf[(start - 1) % DMESG_SIZE] == b'\n'
+        } else {
+            start == 0
+        };
+
+        if !aligned {
+            while start < self.written {
+                start += 1;
+                if self.buf[(start - 1) % DMESG_SIZE] == b'\n' {
+                    break;
+                }
+            }
+        }
+
+        let len = self.written - start;
+        for (i, b) in out[..len].iter_mut().enumerate() {
+            *b = self.buf[(start + i) % DMESG_SIZE];
+        }
+
+        len
+    }
+}
+
+static DMESG: Mutex<Ring> = Mutex::new(Ring {
+    buf: [0; DMESG_SIZE],
+    written: 0,
+});
+
+/// A record being formatted. Anything past `MAX_RECORD` bytes is dropped.
+struct Record {
+    buf: [u8; MAX_RECORD],
+    len: usize,
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = core::cmp::min(s.len(), MAX_RECORD - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Set the level for modules without their own filter.
+pub fn set_level(level: Level) {
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        filters.default = level;
+        MAX_LEVEL.store(filters.max() as usize, Ordering::Relaxed);
+    });
+}
+
+/// Set the level for `module` (e.g. `kernel::memory`) and its submodules, or remove its filter if
+/// `level` is `None`.
+pub fn set_module_level(module: &str, level: Option<Level>) {
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        filters.modules.retain(|(prefix, _)| prefix != module);
+        if let Some(level) = level {
+            filters.modules.push((module.into(), level));
+        }
+        MAX_LEVEL.store(filters.max() as usize, Ordering::Relaxed);
+    });
+}
+
+/// Would a record at `level` from `module` pass the filters?
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level as usize > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    interrupts::without_interrupts(|| level <= FILTERS.lock().level(module))
+}
+
+/// Log a record. Use the macros instead of calling this directly.
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+
+    // Format the record before taking the lock, in case formatting takes a page fault, which
+    // would want to log too.
+    let mut record = Record {
+        buf: [0; MAX_RECORD],
+        len: 0,
+    };
+    let _ = write!(
+        record,
+        "[{:9}] {:5} {}: ",
+        SysTime::now(),
+        level.name(),
+        module
+    );
+    let _ = record.write_fmt(args);
+
+    // Every record is one line (possibly truncated).
+    if record.len == MAX_RECORD {
+        record.len -= 1;
+    }
+    record.buf[record.len] = b'\n';
+    record.len += 1;
+
+    let record = &record.buf[..record.len];
+
+    Debug.write_bytes(record);
+    interrupts::without_interrupts(|| DMESG.lock().push(record));
+}
+
+/// Copy the most recent records (as text) into `out`. Only whole records are copied, so less than
+/// `out.len()` bytes may be used. Returns the number of bytes copied.
+pub fn read_dmesg(out: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| DMESG.lock().read(out))
+}
+
+/// Log an error.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ({
+        $crate::log::log($crate::log::Level::Error, module_path!(), format_args!($($arg)*));
+    })
+}
+
+/// Log a warning.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ({
+        $crate::log::log($crate::log::Level::Warn, module_path!(), format_args!($($arg)*));
+    })
+}
+
+/// Log some information.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ({
+        $crate::log::log($crate::log::Level::Info, module_path!(), format_args!($($arg)*));
+    })
+}
+
+/// Log something useful for debugging.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ({
+        $crate::log::log($crate::log::Level::Debug, module_path!(), format_args!($($arg)*));
+    })
+}
+
+/// Log something very verbose.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ({
+        $crate::log::log($crate::log::Level::Trace, module_path!(), format_args!($($arg)*));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec};
+
+    use super::{read_dmesg, set_module_level, Level};
+
+    fn dmesg() -> String {
+        let mut buf = vec![0; 4096];
+        let len = read_dmesg(&mut buf);
+        buf.truncate(len);
+        String::from_utf8(buf).unwrap()
+    }
+
+    kernel_test!(records_are_kept, 5, async {
+        info!("records_are_kept {}", 42);
+
+        // Other cores may have logged something since, so look for the record.
+        let dmesg = dmesg();
+        let line = dmesg
+            .lines()
+            .rev()
+            .find(|line| line.contains("kernel::log::tests: records_are_kept 42"))
+            .unwrap();
+        assert!(line.contains("INFO"));
+    });
+
+    kernel_test!(module_filters, 5, async {
+        debug!("module_filters hidden");
+        assert!(!dmesg().contains("module_filters hidden"));
+
+        set_module_level("kernel::log", Some(Level::Debug));
+        debug!("module_filters shown");
+        set_module_level("kernel::log", None);
+
+        assert!(dmesg().contains("module_filters shown"));
+
+        // A filter for a different module with the same prefix doesn't apply.
+        set_module_level("kernel::lo", Some(Level::Trace));
+        trace!("module_filters prefix");
+        set_module_level("kernel::lo", None);
+
+        assert!(!dmesg().contains("module_filters prefix"));
+    });
+}
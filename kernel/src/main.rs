@@ -6,34 +6,49 @@
     abi_x86_interrupt,
     panic_info_message,
     drain_filter,
-    naked_functions
+    global_asm,
+    naked_functions,
+    custom_test_frameworks
 )]
 // Compile without libstd
 #![no_std]
 #![no_main]
 #![crate_type = "staticlib"]
 #![crate_name = "kernel"]
+// Tests run in the kernel; see `test`
+#![test_runner(crate::test::runner)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
 #[macro_use]
 mod debug;
+#[macro_use]
+mod log;
+#[cfg(test)]
+#[macro_use]
+mod test;
+mod acpi;
+mod backtrace;
 mod bare_bones;
 #[macro_use]
 mod cap;
 mod continuation;
+mod gdb;
 mod interrupts;
 mod io;
 mod memory;
+mod pci;
+mod power;
 mod sched;
+mod shell;
+mod smp;
+mod task;
 mod time;
 
-use alloc::vec;
-
 use bootloader::BootInfo;
 
-use crate::continuation::{ContResult, Continuation, Event, EventKind};
-use crate::time::SysTime;
+use crate::continuation::{ContResult, Continuation};
 
 /// The kernel heap
 #[global_allocator]
@@ -44,8 +59,6 @@ bootloader::entry_point!(kernel_main);
 /// This is the entry point to the kernel. It is the first rust code that runs.
 #[no_mangle]
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use crate::sched::user;
-
     // At this point we are still in the provisional environment with
     // - the temporary page tables (first 2MiB of memory direct mapped)
     // - no IDT
@@ -54,6 +67,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Make sure interrupts are off
     x86_64::instructions::interrupts::disable();
 
+    // Clear the screen so that `printk!` output is readable there too
+    io::vga::init();
+
     // Let everyone know we are here
     printk!("\nYo Yo Yo! Made it to `kernel_main`! Hooray!\n");
 
@@ -64,8 +80,18 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     memory::init(unsafe { &mut ALLOCATOR }, boot_info);
     printk!("Memory ✔\n");
 
+    // Find out about the platform
+    printk!("ACPI ...\n");
+    acpi::init();
+    printk!("ACPI ✔\n");
+
+    // Find the PCI devices
+    printk!("PCI ...\n");
+    pci::init();
+    printk!("PCI ✔\n");
+
     // Set up interrupt/exception handling
-    printk!("Interrupts...\n\t");
+    printk!("Interrupts ...\n");
     interrupts::init();
     sched::user::init();
     printk!("Interrupts ✔\n");
@@ -73,6 +99,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // I/O
     printk!("I/O ...\n");
     io::init();
+    gdb::init();
     printk!("I/O ✔\n");
 
     // Create the init task, which finishes initialization.
@@ -86,34 +113,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         // Init done!
         //
 
-        // Run a test
-        ContResult::Success(vec![(
-            EventKind::Until(SysTime::now().after(4)),
-            Continuation::new(|_| {
-                printk!("Init waited for 4 seconds! Success 🎉\n");
-                ContResult::Success(vec![(
-                    EventKind::Keyboard,
-                    Continuation::new(|ev| {
-                        if let Event::Keyboard(c) = ev {
-                            printk!("User typed '{}'\n", c as char);
-                        } else {
-                            unreachable!();
-                        }
-
-                        ContResult::Success(vec![(
-                            EventKind::Now,
-                            Continuation::new(|_| {
-                                printk!("Attempting to switch to user!\n");
-
-                                let code = user::load_user_code_section();
-                                let stack = user::allocate_user_stack();
-                                user::start_user_task(code, stack);
-                            }),
-                        )])
-                    }),
-                )])
-            }),
-        )])
+        after_init()
     }));
 
     printk!(" ✔\n");
@@ -133,4 +133,30 @@ fn late_init() {
 
     // We can turn on interrupts now.
     x86_64::instructions::interrupts::enable();
+
+    // Wake up the other cores. This needs the timer, so interrupts must be on.
+    printk!("SMP ...\n");
+    smp::init();
+    printk!("SMP ✔\n");
+}
+
+/// Runs once initialization is done: start the debug shell, with input from the keyboard and the
+/// serial console.
+#[cfg(not(test))]
+fn after_init() -> ContResult {
+    use alloc::vec;
+
+    use crate::{continuation::EventKind, io::serial};
+
+    ContResult::Success(vec![
+        (EventKind::Now, shell::continuation()),
+        (EventKind::Now, serial::console_input()),
+    ])
+}
+
+/// Runs once initialization is done. In a test build, run the tests instead of the usual demo.
+#[cfg(test)]
+fn after_init() -> ContResult {
+    test_main();
+    ContResult::Done
 }
@@ -60,8 +60,8 @@ pub fn init(allocator: &mut KernelAllocator, start: usize, size: usize) {
 
     let free_size = allocator.size();
 
-    printk!(
-        "\theap inited - start addr: 0x{:x}, end addr: 0x{:x}, {} bytes\n",
+    info!(
+        "heap inited - start addr: 0x{:x}, end addr: 0x{:x}, {} bytes",
         start,
         start + size,
         free_size,
@@ -105,11 +105,37 @@ pub mod early {
 
         allocator.set_heap(heap);
 
-        printk!(
-            "\tearly heap inited - start addr: 0x{:x}, end addr: 0x{:x}, {} bytes\n",
+        info!(
+            "early heap inited - start addr: 0x{:x}, end addr: 0x{:x}, {} bytes",
             init_heap_start as usize,
             init_heap_start as usize + INITIAL_KHEAP_SPACE_SIZE,
             free_size,
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    kernel_test!(box_alloc, 1, async {
+        let b = Box::new(0xDEAD_BEEF_u64);
+        assert_eq!(*b, 0xDEAD_BEEF);
+    });
+
+    kernel_test!(vec_grow, 1, async {
+        let mut v = Vec::new();
+        for i in 0..10_000_usize {
+            v.push(i);
+        }
+        assert!(v.iter().enumerate().all(|(i, &x)| i == x));
+    });
+
+    kernel_test!(free_and_reuse, 5, async {
+        // In total, this is more than the whole heap, so freed memory must be reused.
+        for i in 0..2048_usize {
+            let v = vec![i as u8; 4096];
+            assert_eq!(v[4095], i as u8);
+        }
+    });
+}
@@ -7,7 +7,10 @@ use x86_64::structures::idt::InterruptDescriptorTable;
 use crate::interrupts::IRQ_IST_FRAME_INDEX;
 
 pub use self::heap::KernelAllocator;
-pub use self::paging::{map_region, VirtualMemoryRegion};
+pub use self::paging::{
+    alloc_dma, for_each_allowed, map_fixed, map_physical, map_region, probe, stats, user_writable,
+    MemoryStats, Probe, VirtualMemoryRegion, IOAPIC_VADDR, LAPIC_VADDR,
+};
 
 mod heap;
 mod paging;
@@ -7,6 +7,8 @@
 //! - Pages [2MB, 32MB-1): reserved for kernel text (with kernel loaded at start of this region)
 //! - Page 32MB-1: heap guard page (to defend against heap errors spilling into the kernel text)
 //! - Page [32MB, 36MB): kernel heap
+//! - Page 36MB: local APIC registers (uncached)
+//! - Page 36MB+4KB: I/O APIC registers (uncached)
 //!
 //! Physical memory is arranged by the bootloader, which first runs E820 to get a memory map. The
 //! `BootInfo` struct contains the current state of memory, including memory already allocated by
@@ -14,6 +16,8 @@
 
 use alloc::collections::BTreeMap;
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use bootloader::BootInfo;
 
 use buddy::BuddyAllocator;
@@ -25,8 +29,8 @@ use x86_64::{
     structures::{
         idt::{InterruptStackFrame, PageFaultErrorCode},
         paging::{
-            FrameAllocator, Mapper, Page, PageSize, PageTable, PageTableFlags, PageTableIndex,
-            PhysFrame, RecursivePageTable, Size2MiB, Size4KiB, UnusedPhysFrame,
+            FrameAllocator, Mapper, MapperAllSizes, Page, PageSize, PageTable, PageTableFlags,
+            PageTableIndex, PhysFrame, RecursivePageTable, Size2MiB, Size4KiB, UnusedPhysFrame,
         },
     },
     PhysAddr, VirtAddr,
@@ -41,6 +45,9 @@ static PHYS_MEM_ALLOC: Mutex<Option<phys::BuddyAllocator>> = Mutex::new(None);
 /// allocator assigns parts of the 48-bit single address space when asked.
 static VIRT_MEM_ALLOC: Mutex<Option<BuddyAllocator<usize>>> = Mutex::new(None);
 
+/// The number of pages allocated from `VIRT_MEM_ALLOC`. We never free any.
+static VIRT_PAGES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
 /// The page tables for the system.
 static PAGE_TABLES: Mutex<Option<RecursivePageTable>> = Mutex::new(None);
 ///
@@ -62,6 +69,13 @@ pub const KERNEL_HEAP_START: u64 = KERNEL_HEAP_GUARD + (1 << 12);
 /// 2MiB pages.
 pub const KERNEL_HEAP_SIZE: u64 = 4 << 20; // 4MiB
 
+/// Address the local APIC registers are mapped at. Every core's local APIC is at the same physical
+/// address, so this works for all cores.
+pub const LAPIC_VADDR: u64 = KERNEL_HEAP_START + KERNEL_HEAP_SIZE;
+
+/// Address the I/O APIC registers are mapped at.
+pub const IOAPIC_VADDR: u64 = LAPIC_VADDR + (1 << 12);
+
 /// The number of bits of virtual address space.
 const ADDRESS_SPACE_WIDTH: u8 = 48;
 
@@ -69,7 +83,7 @@ const ADDRESS_SPACE_WIDTH: u8 = 48;
 const VIRT_ADDR_AVAILABLE: &[(usize, usize)] = &[
     // Lower half - kernel
     (
-        (KERNEL_HEAP_START + KERNEL_HEAP_SIZE) as usize,
+        (IOAPIC_VADDR + (1 << 12)) as usize,
         (1 << (ADDRESS_SPACE_WIDTH - 1)) - 1,
     ),
     // Higher half
@@ -92,32 +106,52 @@ mod phys {
         PhysAddr,
     };
 
+    use crate::smp::TRAMPOLINE_ADDR;
+
     use super::{KERNEL_HEAP_SIZE, KERNEL_HEAP_START, PHYS_MEM_ALLOC};
 
-    /// A thin wrapper around `BuddyAllocator` that just implements `FrameAllocator`.
-    pub struct BuddyAllocator(buddy::BuddyAllocator<usize>);
+    /// A thin wrapper around `BuddyAllocator` that implements `FrameAllocator` and keeps some
+    /// statistics.
+    pub struct BuddyAllocator {
+        frames: buddy::BuddyAllocator<usize>,
+
+        /// The number of frames added to the allocator.
+        pub total: usize,
+
+        /// The number of frames allocated and not freed, as requested (the buddy allocator may
+        /// round up).
+        pub allocated: usize,
+    }
 
     impl BuddyAllocator {
         pub fn new(nbins: u8) -> Self {
-            BuddyAllocator(buddy::BuddyAllocator::new(nbins))
+            BuddyAllocator {
+                frames: buddy::BuddyAllocator::new(nbins),
+                total: 0,
+                allocated: 0,
+            }
         }
 
         pub fn extend(&mut self, start: usize, end: usize) {
-            self.0.extend(start, end);
+            self.frames.extend(start, end);
+            self.total += end - start + 1;
         }
 
         pub fn alloc(&mut self, n: usize) -> Option<usize> {
-            self.0.alloc(n)
+            let frame = self.frames.alloc(n)?;
+            self.allocated += n;
+            Some(frame)
         }
 
         pub fn free(&mut self, val: usize, n: usize) {
-            self.0.free(val, n)
+            self.frames.free(val, n);
+            self.allocated -= n;
         }
     }
 
     unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
         fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
-            self.0.alloc(1).map(|f| {
+            self.alloc(1).map(|f| {
                 let frame = PhysFrame::from_start_address(PhysAddr::new(f as u64 * Size4KiB::SIZE))
                     .unwrap();
                 unsafe { UnusedPhysFrame::new(frame) }
@@ -125,8 +159,29 @@ mod phys {
         }
     }
 
+    /// Frames below this are never added to the allocator.
+    const RESERVED: usize = ((KERNEL_HEAP_START + KERNEL_HEAP_SIZE) / Size4KiB::SIZE) as usize;
+
     /// Initialize the physical memory allocator.
     pub fn init(boot_info: &'static BootInfo) {
+        // The AP trampoline (see `smp`) is copied to a fixed frame, so make sure nothing else has
+        // it: it must be below `RESERVED`, and not be something the bootloader left for us (e.g.
+        // page tables or the boot info).
+        let trampoline = TRAMPOLINE_ADDR / Size4KiB::SIZE;
+        assert!((trampoline as usize) < RESERVED);
+        assert!(
+            boot_info.memory_map.iter().any(|region| {
+                let free = match region.region_type {
+                    MemoryRegionType::Usable | MemoryRegionType::Bootloader => true,
+                    _ => false,
+                };
+                free && region.range.start_frame_number <= trampoline
+                    && trampoline < region.range.end_frame_number
+            }),
+            "AP trampoline frame {:#x} is in use",
+            TRAMPOLINE_ADDR
+        );
+
         let last_page = boot_info
             .memory_map
             .iter()
@@ -157,9 +212,6 @@ mod phys {
                 _ => false,
             })
         {
-            const RESERVED: usize =
-                ((KERNEL_HEAP_START + KERNEL_HEAP_SIZE) / Size4KiB::SIZE) as usize;
-
             let start = region.range.start_frame_number as usize;
             let end = region.range.end_frame_number as usize;
 
@@ -169,16 +221,16 @@ mod phys {
             } else if start > RESERVED {
                 // beyond reserved region
                 pmem_alloc.as_mut().unwrap().extend(start, end);
-                printk!("\tadded frames {:#X} - {:#X}\n", start, end);
+                debug!("added frames {:#X} - {:#X}", start, end);
             } else if start <= RESERVED {
                 // chop off the reserved part
                 pmem_alloc.as_mut().unwrap().extend(RESERVED, end);
-                printk!("\tadded frames {:#X} - {:#X}\n", RESERVED, end);
+                debug!("added frames {:#X} - {:#X}", RESERVED, end);
             }
             total_mem += end - start + 1;
         }
 
-        printk!("\tphysical memory inited - {} frames\n", total_mem);
+        info!("physical memory inited - {} frames", total_mem);
     }
 }
 
@@ -235,7 +287,7 @@ fn init_early_paging(boot_info: &'static BootInfo) {
         }
     }
 
-    printk!("\tearly page tables inited\n");
+    info!("early page tables inited");
 }
 
 /// Do late paging initialization. At this point we have a working physical memory allocator and
@@ -250,14 +302,14 @@ pub fn init(boot_info: &'static BootInfo) {
     *vmem_alloc = Some(BuddyAllocator::new(ADDRESS_SPACE_WIDTH));
 
     for (start, end) in VIRT_ADDR_AVAILABLE {
-        printk!("\tadd virt addrs [{:16X}, {:16X}]\n", start, end);
+        debug!("add virt addrs [{:16X}, {:16X}]", start, end);
         vmem_alloc.as_mut().unwrap().extend(*start, *end);
     }
 
     let mut allowed = ALLOWED.lock();
     *allowed = Some(BTreeMap::new());
 
-    printk!("\tvirtual address allocator inited\n");
+    info!("virtual address allocator inited");
 
     ///////////////////////////////////////////////////////////////////////////
     // Initially all page table entries are black listed for userspace, but we want to disable at
@@ -334,6 +386,7 @@ impl VirtualMemoryRegion {
             .unwrap()
             .alloc(npages)
             .expect("Out of virtual memory.");
+        VIRT_PAGES_ALLOCATED.fetch_add(npages, Ordering::Relaxed);
 
         UnregisteredResourceHandle::new(Capability::VirtualMemoryRegion(VirtualMemoryRegion {
             addr: mem as u64 * Size4KiB::SIZE,
@@ -372,6 +425,144 @@ impl VirtualMemoryRegion {
     }
 }
 
+/// Map the 4KiB page at virtual address `vaddr` to the physical address `paddr` with the given
+/// `flags`. This is for memory-mapped I/O and other fixed mappings; the frame is not taken from
+/// the physical memory allocator.
+///
+/// If `vaddr` is already mapped to `paddr`, nothing is done.
+///
+/// # Panics
+///
+/// If `vaddr` is already mapped to something else.
+pub fn map_fixed(vaddr: u64, paddr: u64, flags: PageTableFlags) {
+    let mut page_tables = PAGE_TABLES.lock();
+    let page_tables = page_tables.as_mut().unwrap();
+
+    match page_tables.translate_addr(VirtAddr::new(vaddr)) {
+        Some(mapped) if mapped.as_u64() == paddr => return,
+        Some(mapped) => panic!(
+            "{:#x} is already mapped to {:#x}, not {:#x}",
+            vaddr,
+            mapped.as_u64(),
+            paddr
+        ),
+        None => {}
+    }
+
+    let page: Page<Size4KiB> =
+        Page::from_start_address(VirtAddr::new(vaddr)).expect("vaddr is unaligned");
+    let frame = PhysFrame::from_start_address(PhysAddr::new(paddr)).expect("paddr is unaligned");
+
+    unsafe {
+        page_tables
+            .map_to(
+                page,
+                UnusedPhysFrame::new(frame),
+                flags,
+                PHYS_MEM_ALLOC.lock().as_mut().unwrap(),
+            )
+            .expect("Unable to map")
+            .flush();
+    }
+}
+
+/// Map `len` bytes of physical memory starting at `paddr` at some unused virtual address with the
+/// given `flags`. Returns the virtual address that `paddr` is mapped to. This is for reading
+/// firmware tables and the like; the mapping is permanent.
+///
+/// # Panics
+///
+/// If we exhaust the virtual address space.
+pub fn map_physical(paddr: u64, len: u64, flags: PageTableFlags) -> u64 {
+    let first = paddr & !(Size4KiB::SIZE - 1);
+    let end = (paddr + len + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+    let npages = (end - first) / Size4KiB::SIZE;
+
+    let vaddr = VIRT_MEM_ALLOC
+        .lock()
+        .as_mut()
+        .unwrap()
+        .alloc(npages as usize)
+        .expect("Out of virtual memory.") as u64
+        * Size4KiB::SIZE;
+    VIRT_PAGES_ALLOCATED.fetch_add(npages as usize, Ordering::Relaxed);
+
+    for i in 0..npages {
+        map_fixed(
+            vaddr + i * Size4KiB::SIZE,
+            first + i * Size4KiB::SIZE,
+            flags,
+        );
+    }
+
+    vaddr + (paddr - first)
+}
+
+/// Allocate `npages` physically contiguous frames, zero them, and map them at some unused virtual
+/// address. Returns the physical and virtual addresses, or `None` if there is no such run of
+/// frames. This is for buffers that devices access directly (DMA); they are never freed.
+pub fn alloc_dma(npages: usize) -> Option<(u64, u64)> {
+    let frame = PHYS_MEM_ALLOC.lock().as_mut().unwrap().alloc(npages)?;
+    let paddr = frame as u64 * Size4KiB::SIZE;
+    let len = npages as u64 * Size4KiB::SIZE;
+
+    let vaddr = map_physical(
+        paddr,
+        len,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    );
+    unsafe {
+        core::ptr::write_bytes(vaddr as *mut u8, 0, len as usize);
+    }
+
+    Some((paddr, vaddr))
+}
+
+/// What is at a virtual address, as far as a debugger is concerned.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Probe {
+    /// The page is mapped, so it can be accessed.
+    Mapped,
+
+    /// The page is in an `ALLOWED` region but has not been faulted in yet. Accessing it would
+    /// allocate a frame for it.
+    NotPaged,
+
+    /// Nothing is mapped there (or we can't tell because the page tables are in use).
+    Unmapped,
+}
+
+/// Find out whether `vaddr` can be accessed without taking a page fault. This doesn't block, so it
+/// is safe to call from a trap handler.
+pub fn probe(vaddr: u64) -> Probe {
+    let vaddr = match VirtAddr::try_new(vaddr) {
+        Ok(vaddr) => vaddr,
+        Err(_) => return Probe::Unmapped,
+    };
+
+    let mapped = match PAGE_TABLES.try_lock() {
+        Some(page_tables) => page_tables
+            .as_ref()
+            .unwrap()
+            .translate_addr(vaddr)
+            .is_some(),
+        None => return Probe::Unmapped,
+    };
+
+    if mapped {
+        return Probe::Mapped;
+    }
+
+    let vaddr = vaddr.as_u64();
+    match ALLOWED.try_lock() {
+        Some(allowed) => match allowed.as_ref().unwrap().range(0..=vaddr).next_back() {
+            Some((&start, &(len, _))) if vaddr < start + len => Probe::NotPaged,
+            _ => Probe::Unmapped,
+        },
+        None => Probe::Unmapped,
+    }
+}
+
 /// Mark the `region` as usable with the given `flags`. This does not allocate any physical memory.
 /// Pages will be allocated by demand paging.
 pub fn map_region(region: ResourceHandle, flags: PageTableFlags) {
@@ -388,6 +579,82 @@ pub fn map_region(region: ResourceHandle, flags: PageTableFlags) {
         .insert(start as u64, (len, flags));
 }
 
+/// Memory usage statistics, for debugging.
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryStats {
+    /// Physical frames managed by the frame allocator.
+    pub phys_frames: usize,
+
+    /// Physical frames allocated (including page tables and demand-paged memory).
+    pub phys_allocated: usize,
+
+    /// Virtual pages allocated (including unmapped guard pages).
+    pub virt_allocated: usize,
+
+    /// The number of regions in `ALLOWED`, and their total size (bytes).
+    pub allowed_regions: usize,
+    pub allowed_bytes: u64,
+}
+
+/// Get the current memory usage statistics.
+pub fn stats() -> MemoryStats {
+    let (phys_frames, phys_allocated) = {
+        let pmem_alloc = PHYS_MEM_ALLOC.lock();
+        let pmem_alloc = pmem_alloc.as_ref().unwrap();
+        (pmem_alloc.total, pmem_alloc.allocated)
+    };
+
+    let (allowed_regions, allowed_bytes) = {
+        let allowed = ALLOWED.lock();
+        let allowed = allowed.as_ref().unwrap();
+        (allowed.len(), allowed.values().map(|&(len, _)| len).sum())
+    };
+
+    MemoryStats {
+        phys_frames,
+        phys_allocated,
+        virt_allocated: VIRT_PAGES_ALLOCATED.load(Ordering::Relaxed),
+        allowed_regions,
+        allowed_bytes,
+    }
+}
+
+/// Call `f` with the start, length, and flags of each `ALLOWED` region, in order of address.
+///
+/// NOTE: This holds the `ALLOWED` lock, so `f` must not take a page fault.
+pub fn for_each_allowed<F>(mut f: F)
+where
+    F: FnMut(u64, u64, PageTableFlags),
+{
+    for (&start, &(len, flags)) in ALLOWED.lock().as_ref().unwrap().iter() {
+        f(start, len, flags);
+    }
+}
+
+/// Can user mode write to all of `[start, start + len)`? That is, is it all in one `ALLOWED` region
+/// that is user-accessible and writable? The pages may not be paged in yet.
+pub fn user_writable(start: u64, len: u64) -> bool {
+    let end = match start.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let needed = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+
+    match ALLOWED
+        .lock()
+        .as_ref()
+        .unwrap()
+        .range(0..=start)
+        .next_back()
+    {
+        Some((&region, &(region_len, flags))) => {
+            end <= region + region_len && flags.contains(needed)
+        }
+        None => false,
+    }
+}
+
 /// Handle a page fault
 pub extern "x86-interrupt" fn handle_page_fault(
     esf: &mut InterruptStackFrame,
@@ -417,8 +684,8 @@ pub extern "x86-interrupt" fn handle_page_fault(
     match ALLOWED.lock().as_ref().unwrap().range(0..=cr2).next_back() {
         // Demand paging
         Some((&start, (len, flags))) if cr2 >= start && cr2 < start + len => {
-            printk!(
-                "Page fault\n\tip {:x}, addr {:x}.\n\tFound region start: {:x}, len: {}\n\tflags: {:?}\n",
+            debug!(
+                "page fault at ip {:x}, addr {:x}: region start {:x}, len {}, flags {:?}",
                 esf.instruction_pointer.as_u64(),
                 cr2,
                 start,
@@ -443,11 +710,16 @@ pub extern "x86-interrupt" fn handle_page_fault(
                 .expect("Unable to map page")
                 .flush();
 
-            printk!("\tDone with page fault.\n");
+            debug!("done with page fault");
         }
 
         // Segfault
         _ => {
+            crate::backtrace::set_exception(
+                esf.instruction_pointer.as_u64(),
+                crate::backtrace::frame_pointer(),
+            );
+
             panic!(
                 "Segfault at ip {:x}, addr {:x}",
                 esf.instruction_pointer.as_u64(),
@@ -456,3 +728,47 @@ pub extern "x86-interrupt" fn handle_page_fault(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+
+    use super::{map_physical, map_region, VirtualMemoryRegion};
+
+    kernel_test!(demand_paging, 5, async {
+        let region = VirtualMemoryRegion::alloc_with_guard(2).register();
+        map_region(
+            region,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        );
+
+        let (start, len) = region.with(|cap| {
+            let region = cap_unwrap!(VirtualMemoryRegion(cap));
+            (region.start(), region.len())
+        });
+        assert_eq!(len, 2 * Size4KiB::SIZE);
+
+        // Touch every byte, taking a page fault on each page the first time.
+        for i in 0..len as isize {
+            unsafe { start.offset(i).write(i as u8) };
+        }
+        for i in 0..len as isize {
+            assert_eq!(unsafe { start.offset(i).read() }, i as u8);
+        }
+    });
+
+    kernel_test!(map_physical_aliases, 1, async {
+        // Map the VGA buffer twice; writes through one mapping should show up in the other.
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        let a = map_physical(0xB8000, 2, flags) as *mut u16;
+        let b = map_physical(0xB8000, 2, flags) as *mut u16;
+        assert_ne!(a, b);
+
+        unsafe {
+            let old = a.read_volatile();
+            a.write_volatile(0x0F21);
+            assert_eq!(b.read_volatile(), 0x0F21);
+            a.write_volatile(old);
+        }
+    });
+}
@@ -0,0 +1,719 @@
+//! PCI: configuration space access, enumeration, and matching devices with drivers.
+//!
+//! Configuration space is read through the memory mapped regions (ECAM) described by the ACPI
+//! MCFG if there are any, or through the legacy I/O ports (`0xCF8`/`0xCFC`) otherwise. Only
+//! segment group 0 is supported. ECAM is mapped one bus (1MiB) at a time, the first time the bus is
+//! accessed.
+//!
+//! At boot, we walk the buses from bus 0 (following PCI-to-PCI bridges), read every function's
+//! IDs and class, and size its BARs. The result is the device table (see `devices`), which the
+//! shell's `pci` command prints. Then every device is offered to the drivers in `DRIVERS` that
+//! match it (by vendor, device, and/or class; see `Id`) until one of them takes it.
+
+use alloc::vec::Vec;
+
+use core::fmt;
+
+use spin::{Mutex, Once};
+
+use x86_64::{
+    instructions::{interrupts::without_interrupts, port::Port},
+    structures::paging::PageTableFlags,
+};
+
+use crate::{acpi, memory::map_physical};
+
+/// The legacy configuration space ports.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Enables the configuration cycle in `CONFIG_ADDRESS`.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// The size of one bus's configuration space with ECAM.
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// Configuration space registers (the common part of the header).
+const REG_VENDOR: u16 = 0x00;
+const REG_DEVICE: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_REVISION: u16 = 0x08;
+const REG_PROG_IF: u16 = 0x09;
+const REG_SUBCLASS: u16 = 0x0A;
+const REG_CLASS: u16 = 0x0B;
+const REG_HEADER_TYPE: u16 = 0x0E;
+const REG_BAR0: u16 = 0x10;
+const REG_SECONDARY_BUS: u16 = 0x19;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3C;
+const REG_INTERRUPT_PIN: u16 = 0x3D;
+
+/// Command register bits
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Status register: the device has a capability list.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The most capabilities that fit in configuration space (so a broken list can't loop forever).
+const MAX_CAPABILITIES: usize = 48;
+
+/// Header type: the device has more than one function.
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+
+/// Header types
+const HEADER_NORMAL: u8 = 0x00;
+const HEADER_BRIDGE: u8 = 0x01;
+
+/// BAR bits
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b110;
+const BAR_TYPE_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// The vendor ID read from a function that doesn't exist.
+const NO_VENDOR: u16 = 0xFFFF;
+
+/// Interrupt lines above this aren't ISA IRQs (255 means there is no interrupt).
+const MAX_IRQ: u8 = 15;
+
+/// Class codes
+pub const CLASS_STORAGE: u8 = 0x01;
+pub const CLASS_BRIDGE: u8 = 0x06;
+
+/// Storage subclasses
+pub const SUBCLASS_IDE: u8 = 0x01;
+
+/// Bridge subclasses
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// Names of the class codes, for the device table.
+const CLASS_NAMES: &[(u8, &str)] = &[
+    (0x00, "unclassified"),
+    (0x01, "storage"),
+    (0x02, "network"),
+    (0x03, "display"),
+    (0x04, "multimedia"),
+    (0x05, "memory"),
+    (0x06, "bridge"),
+    (0x07, "communication"),
+    (0x08, "system"),
+    (0x09, "input"),
+    (0x0C, "serial bus"),
+];
+
+/// The location of a function.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    /// The value of `CONFIG_ADDRESS` for register `offset` (rounded down to 4 bytes).
+    fn config_address(self, offset: u16) -> u32 {
+        CONFIG_ENABLE
+            | (u32::from(self.bus) << 16)
+            | (u32::from(self.device) << 11)
+            | (u32::from(self.function) << 8)
+            | (u32::from(offset) & 0xFC)
+    }
+
+    /// The offset of register `offset` from the start of the bus's ECAM region.
+    fn ecam_offset(self, offset: u16) -> u64 {
+        (u64::from(self.device) << 15) | (u64::from(self.function) << 12) | u64::from(offset)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register: where the device's registers are.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Bar {
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        /// Physical address
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+}
+
+/// A PCI function.
+#[derive(Copy, Clone, Debug)]
+pub struct Device {
+    pub address: Address,
+
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+
+    /// The legacy (PIC) IRQ, and the interrupt pin (1 to 4 for INTA# to INTD#, or 0 for none).
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+
+    pub bars: [Option<Bar>; 6],
+
+    /// The driver that took the device, if any.
+    pub driver: Option<&'static str>,
+}
+
+/// What a driver can drive. `None` matches anything.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Id {
+    pub vendor: Option<u16>,
+    pub device: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl Id {
+    /// Match a particular device.
+    pub const fn device(vendor: u16, device: u16) -> Id {
+        Id {
+            vendor: Some(vendor),
+            device: Some(device),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Match a class of devices.
+    pub const fn class(class: u8, subclass: u8) -> Id {
+        Id {
+            vendor: None,
+            device: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    pub fn matches(&self, dev: &Device) -> bool {
+        fn ok<T: Eq>(want: Option<T>, have: T) -> bool {
+            want.map_or(true, |want| want == have)
+        }
+
+        ok(self.vendor, dev.vendor)
+            && ok(self.device, dev.device)
+            && ok(self.class, dev.class)
+            && ok(self.subclass, dev.subclass)
+            && ok(self.prog_if, dev.prog_if)
+    }
+}
+
+/// A PCI driver.
+pub struct Driver {
+    pub name: &'static str,
+
+    /// The devices it can drive.
+    pub ids: &'static [Id],
+
+    /// Set up the device. On error, the device is offered to the next driver.
+    pub probe: fn(&Device) -> Result<(), &'static str>,
+}
+
+/// The PCI drivers, in the order they are tried.
+const DRIVERS: &[Driver] = &[
+    Driver {
+        name: "virtio-blk",
+        // Transitional and modern devices
+        ids: &[Id::device(0x1AF4, 0x1001), Id::device(0x1AF4, 0x1042)],
+        probe: crate::io::virtio::blk::probe,
+    },
+    Driver {
+        name: "ata",
+        ids: &[Id::class(CLASS_STORAGE, SUBCLASS_IDE)],
+        probe: crate::io::ata::probe,
+    },
+];
+
+/// How to get at configuration space.
+struct Config {
+    /// The MCFG region for segment group 0, if there is one.
+    ecam: Option<acpi::McfgRegion>,
+
+    /// The buses whose ECAM regions have been mapped, and their virtual addresses.
+    mapped: Vec<(u8, u64)>,
+}
+
+static CONFIG: Mutex<Config> = Mutex::new(Config {
+    ecam: None,
+    mapped: Vec::new(),
+});
+
+/// The device table.
+static DEVICES: Once<Vec<Device>> = Once::new();
+
+/// The interrupt handlers of devices, with their IRQs.
+static HANDLERS: Mutex<Vec<(u8, fn())>> = Mutex::new(Vec::new());
+
+impl Config {
+    /// The virtual address of register `offset` of `addr` through ECAM, if ECAM covers it.
+    fn ecam(&mut self, addr: Address, offset: u16) -> Option<u64> {
+        let region = self.ecam?;
+        if addr.bus < region.start_bus || addr.bus > region.end_bus {
+            return None;
+        }
+
+        let base = match self.mapped.iter().find(|&&(bus, _)| bus == addr.bus) {
+            Some(&(_, base)) => base,
+            None => {
+                let base = map_physical(
+                    region.base + u64::from(addr.bus) * ECAM_BUS_SIZE,
+                    ECAM_BUS_SIZE,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE
+                        | PageTableFlags::NO_EXECUTE,
+                );
+                self.mapped.push((addr.bus, base));
+                base
+            }
+        };
+
+        Some(base + addr.ecam_offset(offset))
+    }
+
+    fn read32(&mut self, addr: Address, offset: u16) -> u32 {
+        match self.ecam(addr, offset & !3) {
+            Some(vaddr) => unsafe { (vaddr as *const u32).read_volatile() },
+            None => unsafe {
+                Port::new(CONFIG_ADDRESS).write(addr.config_address(offset));
+                Port::new(CONFIG_DATA).read()
+            },
+        }
+    }
+
+    fn write32(&mut self, addr: Address, offset: u16, value: u32) {
+        match self.ecam(addr, offset & !3) {
+            Some(vaddr) => unsafe { (vaddr as *mut u32).write_volatile(value) },
+            None => unsafe {
+                Port::new(CONFIG_ADDRESS).write(addr.config_address(offset));
+                Port::new(CONFIG_DATA).write(value);
+            },
+        }
+    }
+
+    fn write16(&mut self, addr: Address, offset: u16, value: u16) {
+        match self.ecam(addr, offset & !1) {
+            Some(vaddr) => unsafe { (vaddr as *mut u16).write_volatile(value) },
+            None => unsafe {
+                Port::new(CONFIG_ADDRESS).write(addr.config_address(offset));
+                Port::new(CONFIG_DATA + (offset & 2)).write(value);
+            },
+        }
+    }
+}
+
+/// Read the 32-bit register at `offset` (a multiple of 4) of the function at `addr`.
+pub fn read32(addr: Address, offset: u16) -> u32 {
+    without_interrupts(|| CONFIG.lock().read32(addr, offset))
+}
+
+/// Read the 16-bit register at `offset` (a multiple of 2).
+pub fn read16(addr: Address, offset: u16) -> u16 {
+    (read32(addr, offset & !3) >> ((offset & 2) * 8)) as u16
+}
+
+/// Read the 8-bit register at `offset`.
+pub fn read8(addr: Address, offset: u16) -> u8 {
+    (read32(addr, offset & !3) >> ((offset & 3) * 8)) as u8
+}
+
+/// Write the 32-bit register at `offset` (a multiple of 4).
+pub fn write32(addr: Address, offset: u16, value: u32) {
+    without_interrupts(|| CONFIG.lock().write32(addr, offset, value))
+}
+
+/// Write the 16-bit register at `offset` (a multiple of 2).
+pub fn write16(addr: Address, offset: u16, value: u16) {
+    without_interrupts(|| CONFIG.lock().write16(addr, offset, value))
+}
+
+/// Find out the size of BAR `i` of `addr` by writing all ones to it and seeing which bits stick.
+/// The caller must turn off decoding first. Returns the BAR (if it is implemented), and whether it
+/// is a 64-bit BAR (which uses BAR `i + 1` too).
+fn size_bar(addr: Address, i: u16) -> (Option<Bar>, bool) {
+    let reg = REG_BAR0 + i * 4;
+    let orig = read32(addr, reg);
+    write32(addr, reg, !0);
+    let mask = read32(addr, reg);
+    write32(addr, reg, orig);
+
+    if orig & BAR_IO != 0 {
+        let size = (!(mask & !0b11)).wrapping_add(1) & 0xFFFF;
+        let bar = if size == 0 {
+            None
+        } else {
+            Some(Bar::Io {
+                port: (orig & !0b11) as u16,
+                size,
+            })
+        };
+        return (bar, false);
+    }
+
+    let is_64 = orig & BAR_TYPE_MASK == BAR_TYPE_64;
+    let (addr_bits, mask) = if is_64 {
+        let orig_hi = read32(addr, reg + 4);
+        write32(addr, reg + 4, !0);
+        let mask_hi = read32(addr, reg + 4);
+        write32(addr, reg + 4, orig_hi);
+
+        (
+            (u64::from(orig_hi) << 32) | u64::from(orig),
+            (u64::from(mask_hi) << 32) | u64::from(mask),
+        )
+    } else {
+        // The top half can't be set, so it isn't part of the size.
+        (u64::from(orig), u64::from(mask) | 0xFFFF_FFFF_0000_0000)
+    };
+
+    let mask = mask & !0xF;
+    let bar = if mask == 0 || mask == 0xFFFF_FFFF_0000_0000 {
+        None
+    } else {
+        Some(Bar::Memory {
+            addr: addr_bits & !0xF,
+            size: (!mask).wrapping_add(1),
+            prefetchable: orig & BAR_PREFETCHABLE != 0,
+        })
+    };
+    (bar, is_64)
+}
+
+/// Size all of the BARs of the function at `addr`.
+fn size_bars(addr: Address, header_type: u8) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let count = match header_type {
+        HEADER_NORMAL => 6,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    };
+
+    // Don't let the device decode the bogus addresses we write while sizing.
+    let command = read16(addr, REG_COMMAND);
+    write16(addr, REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut i = 0;
+    while i < count {
+        let (bar, is_64) = size_bar(addr, i as u16);
+        bars[i] = bar;
+        i += if is_64 { 2 } else { 1 };
+    }
+
+    write16(addr, REG_COMMAND, command);
+
+    bars
+}
+
+/// Read the function at `addr`, if there is one. Bridges are followed to the buses behind them.
+fn scan_function(addr: Address, devices: &mut Vec<Device>) {
+    let vendor = read16(addr, REG_VENDOR);
+    if vendor == NO_VENDOR {
+        return;
+    }
+
+    let header_type = read8(addr, REG_HEADER_TYPE) & !HEADER_MULTI_FUNCTION;
+    let dev = Device {
+        address: addr,
+        vendor,
+        device: read16(addr, REG_DEVICE),
+        class: read8(addr, REG_CLASS),
+        subclass: read8(addr, REG_SUBCLASS),
+        prog_if: read8(addr, REG_PROG_IF),
+        revision: read8(addr, REG_REVISION),
+        interrupt_line: read8(addr, REG_INTERRUPT_LINE),
+        interrupt_pin: read8(addr, REG_INTERRUPT_PIN),
+        bars: size_bars(addr, header_type),
+        driver: None,
+    };
+    devices.push(dev);
+
+    if dev.class == CLASS_BRIDGE
+        && dev.subclass == SUBCLASS_PCI_BRIDGE
+        && header_type == HEADER_BRIDGE
+    {
+        let secondary = read8(addr, REG_SECONDARY_BUS);
+        // Bus 0 is never behind a bridge; this just protects against loops.
+        if secondary != 0 && secondary > addr.bus {
+            scan_bus(secondary, devices);
+        }
+    }
+}
+
+/// Read every function on `bus`.
+fn scan_bus(bus: u8, devices: &mut Vec<Device>) {
+    for device in 0..32 {
+        let addr = Address {
+            bus,
+            device,
+            function: 0,
+        };
+        if read16(addr, REG_VENDOR) == NO_VENDOR {
+            continue;
+        }
+
+        let functions = if read8(addr, REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+            8
+        } else {
+            1
+        };
+        for function in 0..functions {
+            scan_function(
+                Address {
+                    bus,
+                    device,
+                    function,
+                },
+                devices,
+            );
+        }
+    }
+}
+
+/// Find all the devices, and hand them to their drivers. This needs the ACPI tables.
+pub fn init() {
+    CONFIG.lock().ecam =
+        acpi::get().and_then(|acpi| acpi.mcfg.iter().cloned().find(|region| region.segment == 0));
+
+    let mut devices = Vec::new();
+
+    // If the host bridge has more than one function, each of them is the host controller of a
+    // different bus.
+    let host = Address {
+        bus: 0,
+        device: 0,
+        function: 0,
+    };
+    if read8(host, REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION == 0 {
+        scan_bus(0, &mut devices);
+    } else {
+        for function in 0..8 {
+            if read16(Address { function, ..host }, REG_VENDOR) != NO_VENDOR {
+                scan_bus(function, &mut devices);
+            }
+        }
+    }
+
+    info!(
+        "pci inited ({}) - {} functions",
+        if CONFIG.lock().ecam.is_some() {
+            "ECAM"
+        } else {
+            "ports"
+        },
+        devices.len()
+    );
+
+    for dev in devices.iter_mut() {
+        for driver in DRIVERS {
+            if !driver.ids.iter().any(|id| id.matches(dev)) {
+                continue;
+            }
+
+            match (driver.probe)(dev) {
+                Ok(()) => {
+                    info!("pci {}: {}", dev.address, driver.name);
+                    dev.driver = Some(driver.name);
+                    break;
+                }
+                Err(err) => warn!("pci {}: {}: {}", dev.address, driver.name, err),
+            }
+        }
+    }
+
+    DEVICES.call_once(|| devices);
+}
+
+/// The device table.
+pub fn devices() -> &'static [Device] {
+    DEVICES.r#try().map(|devices| &devices[..]).unwrap_or(&[])
+}
+
+/// The devices matching `id`.
+#[allow(dead_code)]
+pub fn find(id: Id) -> impl Iterator<Item = &'static Device> {
+    devices().iter().filter(move |dev| id.matches(dev))
+}
+
+/// Turn on I/O and memory decoding and bus mastering (DMA) for `dev`.
+pub fn enable(dev: &Device) {
+    let command = read16(dev.address, REG_COMMAND);
+    write16(
+        dev.address,
+        REG_COMMAND,
+        command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+    );
+}
+
+/// Map memory BAR `i` of `dev`, uncached. Returns its virtual address, or `None` if it isn't a
+/// memory BAR. The mapping is permanent.
+pub fn map_bar(dev: &Device, i: usize) -> Option<u64> {
+    match dev.bars[i]? {
+        Bar::Memory { addr, size, .. } => Some(map_physical(
+            addr,
+            size,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE,
+        )),
+        Bar::Io { .. } => None,
+    }
+}
+
+/// The capabilities of `dev`: their IDs and where they are in configuration space.
+pub fn capabilities(dev: &Device) -> Vec<(u8, u16)> {
+    let mut caps = Vec::new();
+    if read16(dev.address, REG_STATUS) & STATUS_CAPABILITIES == 0 {
+        return caps;
+    }
+
+    let mut offset = (read8(dev.address, REG_CAPABILITIES) & !0b11) as u16;
+    while offset != 0 && caps.len() < MAX_CAPABILITIES {
+        caps.push((read8(dev.address, offset), offset));
+        offset = (read8(dev.address, offset + 1) & !0b11) as u16;
+    }
+
+    caps
+}
+
+/// Call `handler` when `dev` interrupts, i.e. on the IRQ in its interrupt line register (as set
+/// up by the firmware). The IRQ may be shared, so the handler has to check whether its device
+/// interrupted, and if so, acknowledge the interrupt so that the device stops asserting it.
+///
+/// IRQs are routed by `interrupts::init`, so this has to be called while probing.
+pub fn set_handler(dev: &Device, handler: fn()) -> Result<(), &'static str> {
+    if dev.interrupt_pin == 0 || dev.interrupt_line > MAX_IRQ {
+        return Err("no interrupt line");
+    }
+
+    without_interrupts(|| HANDLERS.lock().push((dev.interrupt_line, handler)));
+    Ok(())
+}
+
+/// The IRQs that devices have handlers for.
+pub fn irqs() -> Vec<u8> {
+    let mut irqs: Vec<u8> = HANDLERS.lock().iter().map(|&(irq, _)| irq).collect();
+    irqs.sort();
+    irqs.dedup();
+    irqs
+}
+
+/// Call the handlers for `irq`. Returns false if there aren't any.
+pub fn handle_irq(irq: u8) -> bool {
+    let handlers = HANDLERS.lock();
+    let mut handled = false;
+    for &(_, handler) in handlers.iter().filter(|&&(line, _)| line == irq) {
+        handler();
+        handled = true;
+    }
+    handled
+}
+
+/// The name of a class code.
+fn class_name(class: u8) -> &'static str {
+    CLASS_NAMES
+        .iter()
+        .find(|&&(c, _)| c == class)
+        .map_or("other", |&(_, name)| name)
+}
+
+/// Print the device table.
+pub fn dump() {
+    for dev in devices() {
+        printk!(
+            "{} {:04x}:{:04x} rev {:02x} {:02x}.{:02x}.{:02x} {:13} {}\n",
+            dev.address,
+            dev.vendor,
+            dev.device,
+            dev.revision,
+            dev.class,
+            dev.subclass,
+            dev.prog_if,
+            class_name(dev.class),
+            dev.driver.unwrap_or("-"),
+        );
+
+        if dev.interrupt_pin != 0 {
+            printk!(
+                "        INT{}# irq {}\n",
+                (b'A' + dev.interrupt_pin - 1) as char,
+                dev.interrupt_line
+            );
+        }
+
+        for (i, bar) in dev.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Io { port, size }) => {
+                    printk!("        BAR{} I/O {:#06x} ({} bytes)\n", i, port, size)
+                }
+                Some(Bar::Memory {
+                    addr,
+                    size,
+                    prefetchable,
+                }) => printk!(
+                    "        BAR{} mem {:#x} ({} KiB{})\n",
+                    i,
+                    addr,
+                    size / 1024,
+                    if *prefetchable { ", prefetchable" } else { "" }
+                ),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{devices, find, Address, Device, Id, CLASS_BRIDGE};
+
+    kernel_test!(pci_config_address, 5, async {
+        let addr = Address {
+            bus: 1,
+            device: 2,
+            function: 3,
+        };
+        assert_eq!(addr.config_address(0x3E), 0x8001_133C);
+        assert_eq!(addr.ecam_offset(0x3E), 0x1_303E);
+        assert_eq!(alloc::format!("{}", addr), "01:02.3");
+    });
+
+    kernel_test!(pci_finds_host_bridge, 5, async {
+        // Every PC has a host bridge at 00:00.0.
+        let host: &Device = devices()
+            .iter()
+            .find(|dev| {
+                dev.address
+                    == Address {
+                        bus: 0,
+                        device: 0,
+                        function: 0,
+                    }
+            })
+            .expect("no host bridge");
+        assert_eq!(host.class, CLASS_BRIDGE);
+
+        assert!(find(Id::device(host.vendor, host.device)).any(|dev| dev.address == host.address));
+        assert!(find(Id::class(CLASS_BRIDGE, host.subclass)).count() >= 1);
+        assert_eq!(find(Id::device(0xFFFF, 0)).count(), 0);
+    });
+}
@@ -0,0 +1,159 @@
+//! Shutting down, rebooting, and exiting QEMU.
+//!
+//! Each of these tries the "proper" way first (ACPI), then falls back to whatever else might work.
+//! If all else fails, we just hang.
+
+use x86_64::{
+    instructions::{hlt, interrupts, port::Port},
+    structures::paging::PageTableFlags,
+};
+
+use crate::{acpi, memory::map_physical};
+
+/// The `SLP_EN` bit of the PM1 control registers.
+const SLP_EN: u16 = 1 << 13;
+
+/// The port of QEMU's `isa-debug-exit` device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
+#[cfg(test)]
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Ports and values that power off various emulators: QEMU, Bochs (and old QEMU), VirtualBox.
+const EMULATOR_SHUTDOWN: &[(u16, u16)] = &[(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+/// Keyboard controller command port
+const KBD_CMD: Port<u8> = Port::new(0x64);
+
+/// Keyboard controller command to pulse the reset line.
+const KBD_RESET: u8 = 0xFE;
+
+/// How many times to check whether the keyboard controller can take the reset command.
+const KBD_TRIES: usize = 100_000;
+
+/// Exit codes to report to the host through QEMU's `isa-debug-exit` device. QEMU exits with
+/// status `(code << 1) | 1`, so success is 33 and failure is 35. (We can't use 0 because that
+/// would be ambiguous with QEMU exiting normally.)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+#[cfg(test)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Wait forever.
+fn hang() -> ! {
+    loop {
+        hlt();
+    }
+}
+
+/// Power off the machine.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    info!("shutting down");
+
+    // ACPI: write SLP_TYPx | SLP_EN to the PM1 control registers.
+    if let Some(fadt) = acpi::get().and_then(|acpi| acpi.fadt.as_ref()) {
+        if let Some((slp_typa, slp_typb)) = fadt.s5_slp_typ {
+            unsafe {
+                Port::<u16>::new(fadt.pm1a_cnt_blk).write((slp_typa << 10) | SLP_EN);
+                if fadt.pm1b_cnt_blk != 0 {
+                    Port::<u16>::new(fadt.pm1b_cnt_blk).write((slp_typb << 10) | SLP_EN);
+                }
+            }
+        }
+    }
+
+    // Emulator-specific ports
+    for &(port, val) in EMULATOR_SHUTDOWN {
+        unsafe {
+            Port::<u16>::new(port).write(val);
+        }
+    }
+
+    error!("unable to shut down");
+    hang()
+}
+
+/// Reboot the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    info!("rebooting");
+
+    // ACPI reset register
+    if let Some((space, addr, val)) = acpi::get()
+        .and_then(|acpi| acpi.fadt.as_ref())
+        .and_then(|fadt| fadt.reset)
+    {
+        match space {
+            // System memory
+            0 => unsafe {
+                let vaddr = map_physical(
+                    addr,
+                    1,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE
+                        | PageTableFlags::NO_EXECUTE,
+                );
+                (vaddr as *mut u8).write_volatile(val);
+            },
+
+            // System I/O
+            1 => unsafe { Port::<u8>::new(addr as u16).write(val) },
+
+            // We don't support anything else (e.g. PCI config space)
+            _ => {}
+        }
+    }
+
+    // Pulse the reset line via the keyboard controller, once its input buffer is empty. If it
+    // never empties, there may be no controller at all, so go on to the triple fault.
+    unsafe {
+        if (0..KBD_TRIES).any(|_| KBD_CMD.read() & 0b10 == 0) {
+            KBD_CMD.write(KBD_RESET);
+        }
+    }
+
+    // Triple fault: with an empty IDT, the breakpoint can't be handled, and neither can the
+    // resulting double fault.
+    unsafe {
+        #[repr(C, packed)]
+        struct EmptyIdt {
+            limit: u16,
+            base: u64,
+        }
+
+        let idt = EmptyIdt { limit: 0, base: 0 };
+
+        asm!(
+            "
+            lidt ($0)
+            int3
+            "
+            : /* no outputs */
+            : "r"(&idt)
+            : "memory"
+            : "volatile"
+        );
+    }
+
+    error!("unable to reboot");
+    hang()
+}
+
+/// Exit QEMU with the given exit code. This only works if QEMU has an `isa-debug-exit` device;
+/// otherwise, we shut down. Only the test runner uses this.
+#[cfg(test)]
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    interrupts::disable();
+
+    unsafe {
+        Port::<u32>::new(QEMU_EXIT_PORT).write(code as u32);
+    }
+
+    // No `isa-debug-exit` device...
+    shutdown()
+}
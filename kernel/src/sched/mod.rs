@@ -1,33 +1,37 @@
 //! The scheduler
 
+mod queue;
 pub mod user;
 
-use alloc::{boxed::Box, collections::linked_list::LinkedList, vec, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 
 use core::{borrow::Borrow, mem};
 
-use spin::Mutex;
+use spin::{Mutex, Once};
 
-use crate::continuation::{Continuation, Event, EventKind};
-use crate::time::SysTime;
+use crate::continuation::{CancelToken, Continuation, Event, EventKind, Priority};
+use crate::smp::{cpu_id, MAX_CPUS};
+
+use self::queue::RunQueue;
 
 /// The size of a stack in words
 const STACK_WORDS: usize = 1 << 12; // 16KB
 
-/// The kernel task scheduler instance
-static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+/// The kernel task scheduler instances, one per core, indexed by `cpu_id`.
+static SCHEDULERS: Once<Vec<Mutex<Option<Scheduler>>>> = Once::new();
 
-/// The head of the current stack
-// TODO: maybe there is some race condition here? not really sure... I think the scheduler and the
-// syscall handler are the only ones using this, and by construction at most one of them can be
-// running at a time...
-static mut CURRENT_STACK_HEAD: u64 = 0;
+/// The head of the current stack of each core, indexed by `cpu_id`. Each slot is only touched by
+/// its own core (by the scheduler and the syscall handler), with interrupts off, so it needs no
+/// lock.
+static mut CURRENT_STACK_HEADS: [u64; MAX_CPUS] = [0; MAX_CPUS];
 
 /// The kernel task scheduler
 struct Scheduler {
-    /// The list of outstanding continuations that have yet to be scheduled, along with the event
-    /// each one is waiting on.
-    next: LinkedList<(EventKind, Continuation)>,
+    /// The continuations waiting to run on this core.
+    queue: RunQueue,
+
+    /// The ID and class of the continuation that is running on this core, if any.
+    running: Option<(u64, Priority)>,
 
     // Because every core is single-threaded, we only need one stack. After a task executes, we can
     // just clean it up and reuse it. However, to make life a bit easier, we just allocate two
@@ -37,47 +41,23 @@ struct Scheduler {
 
     /// A clean stack for the next task
     clean_stack: Stack,
+
+    /// Set if the scheduler was entered from an interrupt handler to preempt the current
+    /// continuation. In that case, interrupts need to be re-enabled once we are off the interrupt
+    /// stack.
+    preempted: bool,
 }
 
 impl Scheduler {
-    /// Get the next continuation to run along with the `Event` that it was waiting for. If no
-    /// continuation exists or no continuation is ready, return None.
-    pub fn next(&mut self) -> Option<(Event, Continuation)> {
-        // Iterate through all current outstanding tasks. Choose the first one that is ready.
-        for _ in 0..self.next.len() {
-            match self.next.pop_front()? {
-                // Not waiting? Great!
-                (EventKind::Now, cont) => return Some((Event::Now, cont)),
-
-                // Timer events? Is the requested time here?
-                (EventKind::Until(time), cont) => {
-                    if SysTime::now() >= time {
-                        return Some((Event::Timer, cont));
-                    } else {
-                        // Not ready; put it back.
-                        self.next.push_back((EventKind::Until(time), cont));
-                    }
-                }
-
-                // Waiting for kbd input?
-                (EventKind::Keyboard, cont) => {
-                    if let Some(c) = crate::io::kbd::kbd_next() {
-                        return Some((Event::Keyboard(c), cont));
-                    } else {
-                        // Not ready; put it back.
-                        self.next.push_back((EventKind::Keyboard, cont));
-                    }
-                }
-            }
+    /// Create a scheduler with no continuations.
+    fn new() -> Self {
+        Scheduler {
+            queue: RunQueue::new(),
+            running: None,
+            current_stack: Stack::new(),
+            clean_stack: Stack::new(),
+            preempted: false,
         }
-
-        // Didn't find anything (ready)...
-        None
-    }
-
-    /// Enqueue the given list of continuations.
-    pub fn enqueue(&mut self, mut cont: Vec<(EventKind, Continuation)>) {
-        self.next.extend(cont.drain(..));
     }
 }
 
@@ -109,28 +89,36 @@ impl Stack {
     }
 }
 
-/// Start the first task. This is only called by `kernel_main`!
+/// The scheduler of the current core.
+fn this_core() -> &'static Mutex<Option<Scheduler>> {
+    &SCHEDULERS.r#try().unwrap()[cpu_id()]
+}
+
+/// Start the first task. This is only called by `kernel_main` and `smp::ap_main`!
 pub fn start() -> ! {
     sched()
 }
 
-/// Initialize the process/scheduling subsystem with the initial continuation.
+/// Initialize the process/scheduling subsystem with the initial continuation. This is called on
+/// the BSP.
 pub fn init(init: Continuation) {
-    let mut s = SCHEDULER.lock();
+    SCHEDULERS.call_once(|| (0..MAX_CPUS).map(|_| Mutex::new(None)).collect());
+
+    init_ap();
 
-    let mut next = LinkedList::new();
-    next.push_back((EventKind::Now, init));
+    let _ = enqueue(vec![(EventKind::Now, init)]);
+}
+
+/// Create the scheduler for the current core. The BSP must already have called `init`.
+pub fn init_ap() {
+    let mut s = this_core().lock();
 
     // Create the scheduler
-    *s = Some(Scheduler {
-        next,
-        current_stack: Stack::new(),
-        clean_stack: Stack::new(),
-    });
+    *s = Some(Scheduler::new());
 
     // Set the current stack
     unsafe {
-        CURRENT_STACK_HEAD = s.as_ref().unwrap().current_stack.first_rsp() as u64;
+        CURRENT_STACK_HEADS[cpu_id()] = s.as_ref().unwrap().current_stack.first_rsp() as u64;
     }
 }
 
@@ -139,7 +127,7 @@ pub fn init(init: Continuation) {
 /// the idle continuation is used.
 pub fn sched() -> ! {
     // Get the scheduler
-    let mut sched = SCHEDULER.lock();
+    let mut sched = this_core().lock();
     let s = sched.as_mut().unwrap();
 
     // Make the clean stack the current stack
@@ -149,7 +137,7 @@ pub fn sched() -> ! {
     let rsp = s.current_stack.first_rsp();
 
     unsafe {
-        CURRENT_STACK_HEAD = rsp as u64;
+        CURRENT_STACK_HEADS[cpu_id()] = rsp as u64;
     }
 
     drop(sched); // unlock
@@ -159,6 +147,14 @@ pub fn sched() -> ! {
     }
 }
 
+/// Like `sched`, but called from an interrupt or system call handler (with interrupts disabled),
+/// usually after the current continuation has been re-enqueued. The handler's stack frame is
+/// discarded.
+pub fn preempt() -> ! {
+    this_core().lock().as_mut().unwrap().preempted = true;
+    sched()
+}
+
 /// Part 2 of `sched`. This actually switches to the new stack. Then, it calls `part_3`, having
 /// already switched to the new stack. This is done so that the compiler knows that no state should
 /// be carried over, so we cannot lose any important stack variables (e.g. locks).
@@ -180,28 +176,121 @@ unsafe fn sched_part_2_thunk(rsp: usize) -> ! {
 /// task and start running it.
 unsafe fn sched_part_3() -> ! {
     // Get the scheduler
-    let mut sched = SCHEDULER.lock();
+    let mut sched = this_core().lock();
     let s = sched.as_mut().unwrap();
 
     // clean old stack
     s.clean_stack.clear();
 
-    // get the next task
-    let (event, next) = if let Some(next) = s.next() {
+    // We are off the interrupt stack now, so it is safe to take interrupts again.
+    if mem::replace(&mut s.preempted, false) {
+        x86_64::instructions::interrupts::enable();
+    }
+
+    // get the next task, or steal one from another core
+    let (event, next) = if let Some(next) = s.queue.next().or_else(steal) {
         next
     } else {
         (Event::Now, make_idle_cont())
     };
 
+    s.running = Some((next.id(), next.priority()));
+
     drop(sched); // unlock
 
     // run the task
     next.run(event)
 }
 
-/// Enqueue the given list of continuations in the scheduler.
-pub fn enqueue(cont: Vec<(EventKind, Continuation)>) {
-    SCHEDULER.lock().as_mut().unwrap().enqueue(cont);
+/// Try to take a ready continuation from another core's scheduler.
+///
+/// We use `try_lock` so that two idle cores stealing from each other can't deadlock. If a core is
+/// busy with its scheduler, we just skip it this time.
+fn steal() -> Option<(Event, Continuation)> {
+    let me = cpu_id();
+
+    SCHEDULERS
+        .r#try()
+        .unwrap()
+        .iter()
+        .enumerate()
+        .filter(|&(cpu, _)| cpu != me)
+        .filter_map(|(_, sched)| sched.try_lock())
+        .filter_map(|mut sched| sched.as_mut().and_then(|s| s.queue.next()))
+        .next()
+}
+
+/// Enqueue the given list of continuations in the current core's scheduler. Returns a
+/// cancellation token for each of them, in the same order.
+pub fn enqueue(cont: Vec<(EventKind, Continuation)>) -> Vec<CancelToken> {
+    this_core().lock().as_mut().unwrap().queue.enqueue(cont)
+}
+
+/// What `for_each_continuation` knows about a continuation.
+#[derive(Copy, Clone, Debug)]
+pub struct ContinuationInfo {
+    pub id: u64,
+
+    /// The core whose scheduler has the continuation.
+    pub cpu: usize,
+
+    pub priority: Priority,
+
+    /// What the continuation is waiting for, or `None` if it is running.
+    pub waiting_for: Option<EventKind>,
+}
+
+/// The ID of the continuation running on the current core, if we can tell. This doesn't block, so
+/// it is safe to call from a trap handler.
+pub fn running() -> Option<u64> {
+    this_core().try_lock()?.as_ref()?.running.map(|(id, _)| id)
+}
+
+/// Calls `f` with every continuation that is running or enqueued on any core. Schedulers that are
+/// locked are skipped. This doesn't block or allocate, so it is safe to call from a trap handler.
+pub fn for_each_continuation<F>(mut f: F)
+where
+    F: FnMut(ContinuationInfo),
+{
+    let scheds = SCHEDULERS.r#try().into_iter().flat_map(|scheds| scheds.iter());
+
+    for (cpu, sched) in scheds.enumerate() {
+        if let Some(sched) = sched.try_lock() {
+            if let Some(s) = sched.as_ref() {
+                if let Some((id, priority)) = s.running {
+                    f(ContinuationInfo {
+                        id,
+                        cpu,
+                        priority,
+                        waiting_for: None,
+                    });
+                }
+
+                for (kind, cont) in s.queue.iter() {
+                    f(ContinuationInfo {
+                        id: cont.id(),
+                        cpu,
+                        priority: cont.priority(),
+                        waiting_for: Some(*kind),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Remove any cancelled continuations from all schedulers and drop them.
+pub fn purge_cancelled() {
+    for sched in SCHEDULERS.r#try().unwrap().iter() {
+        let cancelled = match sched.lock().as_mut() {
+            Some(s) => s.queue.remove_cancelled(),
+            None => continue,
+        }; // unlock
+
+        // Drop the continuations (and anything they captured) outside of the lock, in case some
+        // destructor wants the scheduler.
+        drop(cancelled);
+    }
 }
 
 /// Returns the idle continuation.
@@ -215,8 +304,69 @@ pub fn make_idle_cont() -> Continuation {
 }
 
 /// Enqueue the idle continuation. This continuation just calls the scheduler to schedule something
-/// else if possible.
+/// else if possible. It is in the background class so that it doesn't hold up anything else.
 pub fn idle() {
-    let cont = make_idle_cont();
-    enqueue(vec![(EventKind::Now, cont)]);
+    let cont = make_idle_cont().with_priority(Priority::Background);
+    let _ = enqueue(vec![(EventKind::Now, cont)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec};
+
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use crate::{
+        continuation::{ContError, ContResult, Continuation, EventKind},
+        task,
+        time::SysTime,
+    };
+
+    use super::enqueue;
+
+    kernel_test!(continuations_run, 5, async {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let conts = (0..8)
+            .map(|_| {
+                let count = count.clone();
+                (
+                    EventKind::Now,
+                    Continuation::new(move |_| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                        ContResult::Done
+                    }),
+                )
+            })
+            .collect();
+        let _ = enqueue(conts);
+
+        task::sleep(1).await;
+        assert_eq!(count.load(Ordering::Relaxed), 8);
+    });
+
+    kernel_test!(sleep_waits, 5, async {
+        let start = SysTime::now();
+        task::sleep(1).await;
+        assert!(SysTime::now() >= start.after(1));
+    });
+
+    kernel_test!(supervisor_handles_errors, 5, async {
+        let handled = Arc::new(AtomicBool::new(false));
+
+        let cont = {
+            let handled = handled.clone();
+            Continuation::new(|_| ContResult::Error(ContError::Timeout, None)).supervised(
+                move |error| {
+                    assert_eq!(error, ContError::Timeout);
+                    handled.store(true, Ordering::Relaxed);
+                    ContResult::Done
+                },
+            )
+        };
+        let _ = enqueue(vec![(EventKind::Now, cont)]);
+
+        task::sleep(1).await;
+        assert!(handled.load(Ordering::Relaxed));
+    });
 }
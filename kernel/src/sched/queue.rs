@@ -0,0 +1,260 @@
+//! The queues of continuations waiting to run. This is the part of the scheduler that decides
+//! what runs next; it doesn't know anything about stacks or cores, so the hosted simulator (see
+//! `sim/`) uses it as is.
+
+use alloc::{collections::linked_list::LinkedList, vec::Vec};
+
+use crate::continuation::{CancelToken, Continuation, Event, EventKind, Priority};
+use crate::time::SysTime;
+
+/// The outstanding continuations of one core.
+pub struct RunQueue {
+    /// The lists of outstanding continuations that have yet to be scheduled, along with the event
+    /// each one is waiting on. There is one list for each scheduling class, indexed by
+    /// `Priority`.
+    next: [LinkedList<(EventKind, Continuation)>; Priority::COUNT],
+}
+
+impl RunQueue {
+    /// Create a queue with no continuations.
+    pub fn new() -> Self {
+        RunQueue {
+            next: [
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+            ],
+        }
+    }
+
+    /// Get the next continuation to run along with the `Event` that it was waiting for. If no
+    /// continuation exists or no continuation is ready, return None.
+    pub fn next(&mut self) -> Option<(Event, Continuation)> {
+        // Real-time continuations first, by deadline.
+        if let Some(next) = Self::next_deadline(&mut self.next[Priority::RealTime as usize]) {
+            return Some(next);
+        }
+
+        // Then everything else, by class.
+        for queue in self.next.iter_mut() {
+            if let Some(next) = Self::next_fifo(queue) {
+                return Some(next);
+            }
+        }
+
+        // Didn't find anything (ready)...
+        None
+    }
+
+    /// Choose the ready continuation waiting for `EventKind::Until` with the earliest deadline
+    /// from `queue`, if there is one.
+    fn next_deadline(
+        queue: &mut LinkedList<(EventKind, Continuation)>,
+    ) -> Option<(Event, Continuation)> {
+        let now = SysTime::now();
+
+        let (idx, _) = queue
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (kind, cont))| match kind {
+                EventKind::Until(time) if *time <= now && !cont.is_cancelled() => Some((i, *time)),
+                _ => None,
+            })
+            .min_by_key(|&(_, time)| time)?;
+
+        // Remove it from the middle of the list.
+        let mut rest = queue.split_off(idx);
+        let (_, cont) = rest.pop_front().unwrap();
+        queue.append(&mut rest);
+
+        Some((Event::Timer, cont))
+    }
+
+    /// Choose the first ready continuation from `queue`, if there is one.
+    fn next_fifo(
+        queue: &mut LinkedList<(EventKind, Continuation)>,
+    ) -> Option<(Event, Continuation)> {
+        // Iterate through all current outstanding tasks. Choose the first one that is ready.
+        for _ in 0..queue.len() {
+            let (kind, cont) = queue.pop_front()?;
+
+            // Drop anything that was cancelled (e.g. because its parent's subtree was cancelled
+            // after it was enqueued).
+            if cont.is_cancelled() {
+                continue;
+            }
+
+            match (kind, cont) {
+                // Not waiting? Great!
+                (EventKind::Now, cont) => return Some((Event::Now, cont)),
+
+                // Errors have already happened, so they are always ready.
+                (EventKind::Error(error), cont) => return Some((Event::Error(error), cont)),
+
+                // Timer events? Is the requested time here?
+                (EventKind::Until(time), cont) => {
+                    if SysTime::now() >= time {
+                        return Some((Event::Timer, cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Until(time), cont));
+                    }
+                }
+
+                // Waiting for kbd input?
+                (EventKind::Keyboard(kbd), cont) => {
+                    if let Some(event) = crate::io::kbd::next_input(kbd) {
+                        return Some((event, cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Keyboard(kbd), cont));
+                    }
+                }
+
+                // Waiting for serial input?
+                (EventKind::Serial(port), cont) => {
+                    if let Some(b) = crate::io::serial::read_byte(port) {
+                        return Some((Event::Serial(b), cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Serial(port), cont));
+                    }
+                }
+
+                // Waiting for terminal input?
+                (EventKind::Terminal(term), cont) => {
+                    if let Some(event) = crate::io::vt::next_input(term) {
+                        return Some((event, cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Terminal(term), cont));
+                    }
+                }
+
+                // Waiting for the mouse?
+                (EventKind::Mouse(mouse), cont) => {
+                    if let Some(event) = crate::io::mouse::next_input(mouse) {
+                        return Some((event, cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Mouse(mouse), cont));
+                    }
+                }
+
+                // Waiting for a disk?
+                (EventKind::Block(id), cont) => {
+                    if let Some(event) = crate::io::block::poll(id) {
+                        return Some((event, cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Block(id), cont));
+                    }
+                }
+
+                // Waiting for a task's waker?
+                (EventKind::Woken(id), cont) => {
+                    if let Some(event) = crate::task::woken(id) {
+                        return Some((event, cont));
+                    } else {
+                        // Not ready; put it back.
+                        queue.push_back((EventKind::Woken(id), cont));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Enqueue the given list of continuations. Returns a cancellation token for each of them, in
+    /// the same order.
+    pub fn enqueue(&mut self, mut cont: Vec<(EventKind, Continuation)>) -> Vec<CancelToken> {
+        let tokens = cont.iter().map(|(_, cont)| cont.token()).collect();
+        for (kind, cont) in cont.drain(..) {
+            self.next[cont.priority() as usize].push_back((kind, cont));
+        }
+        tokens
+    }
+
+    /// All of the continuations, ready or not, with the events they are waiting for.
+    pub fn iter(&self) -> impl Iterator<Item = &(EventKind, Continuation)> {
+        self.next.iter().flat_map(|queue| queue.iter())
+    }
+
+    /// Are there no continuations at all (ready or not)?
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.next.iter().all(|queue| queue.is_empty())
+    }
+
+    /// Remove all cancelled continuations, returning them so that they can be dropped without
+    /// holding the scheduler lock.
+    pub fn remove_cancelled(&mut self) -> LinkedList<(EventKind, Continuation)> {
+        let mut cancelled = LinkedList::new();
+        for queue in self.next.iter_mut() {
+            cancelled.extend(queue.drain_filter(|(_, cont)| cont.is_cancelled()));
+        }
+        cancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use crate::{
+        continuation::{ContResult, Continuation, EventKind, Priority},
+        time::SysTime,
+    };
+
+    use super::RunQueue;
+
+    /// A continuation that does nothing.
+    fn noop() -> Continuation {
+        Continuation::new(|_| ContResult::Done)
+    }
+
+    kernel_test!(priority_order, 1, async {
+        let mut q = RunQueue::new();
+        let _ = q.enqueue(vec![
+            (EventKind::Now, noop().with_priority(Priority::Background)),
+            (EventKind::Now, noop().with_priority(Priority::Normal)),
+            (EventKind::Now, noop().with_priority(Priority::High)),
+            (EventKind::Now, noop().with_priority(Priority::RealTime)),
+        ]);
+
+        for &priority in &[
+            Priority::RealTime,
+            Priority::High,
+            Priority::Normal,
+            Priority::Background,
+        ] {
+            assert_eq!(q.next().unwrap().1.priority(), priority);
+        }
+        assert!(q.next().is_none());
+    });
+
+    kernel_test!(until_not_ready, 1, async {
+        let mut q = RunQueue::new();
+        let later = SysTime::now().after(60);
+        let _ = q.enqueue(vec![
+            (EventKind::Until(later), noop()),
+            (
+                EventKind::Until(later),
+                noop().with_priority(Priority::RealTime),
+            ),
+        ]);
+
+        assert!(q.next().is_none());
+    });
+
+    kernel_test!(cancelled_are_skipped, 1, async {
+        let mut q = RunQueue::new();
+        let tokens = q.enqueue(vec![(EventKind::Now, noop()), (EventKind::Now, noop())]);
+
+        tokens[0].cancel();
+        assert!(q.next().is_some());
+        assert!(q.next().is_none());
+    });
+}
@@ -1,5 +1,11 @@
 //! System calls and kernel <-> user mode switching...
 
+use alloc::vec;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
 use x86_64::{
     registers::{
         model_specific::{Efer, EferFlags, Msr},
@@ -10,12 +16,33 @@ use x86_64::{
 
 use crate::{
     cap::ResourceHandle,
+    continuation::{Continuation, EventKind},
     interrupts::SELECTORS,
-    memory::{map_region, VirtualMemoryRegion},
+    memory::{map_region, VirtualMemoryRegion, LAPIC_VADDR},
+    smp::{cpu_id, MAX_CPUS},
+    time::SysTime,
 };
 
 const USER_STACK_SIZE: usize = 1; // pages
 
+/// How long a user continuation can run before it is preempted (in timer ticks).
+const TIME_SLICE: usize = 10;
+
+/// When the time slice of the currently running user continuation on each core runs out, indexed
+/// by `cpu_id`.
+static SLICE_END: Mutex<[Option<SysTime>; MAX_CPUS]> = Mutex::new([None; MAX_CPUS]);
+
+/// The number of system calls handled so far, on all cores.
+static SYSCALL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// System call number: the user continuation is done.
+const SYSCALL_EXIT: u64 = 0;
+
+/// System call number: copy the kernel log (see `log::read_dmesg`) into the buffer at %rdi, which
+/// is %rsi bytes long. Returns the number of bytes copied, or `u64::MAX` if the buffer isn't
+/// writable by the user.
+const SYSCALL_DMESG: u64 = 1;
+
 // Some MSRs used for system call handling.
 
 /// Contains the stack and code segmets for syscall/sysret.
@@ -52,12 +79,70 @@ struct SavedRegs {
     pub rsp: u64,
 }
 
-/// Allocates virtual address space, adds appropriate page table mappings, loads the specified code
-/// section into the allocated memory.
+/// The registers pushed by an interrupt entry stub that may preempt user mode, followed by the
+/// frame pushed by the hardware. This is laid out in the order it is on the stack (lowest address
+/// first).
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // Pushed by the hardware.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// The registers the user was running with when it was interrupted.
+    fn saved_regs(&self) -> SavedRegs {
+        SavedRegs {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rdi: self.rdi,
+            rsi: self.rsi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rflags: self.rflags,
+            rip: self.rip,
+            rsp: self.rsp,
+        }
+    }
+}
+
+/// Allocates virtual address space, adds appropriate page table mappings, and loads the given
+/// machine code, which must fit in one page, into the allocated memory.
 ///
 /// Returns the virtual address region where the code has been loaded and the first RIP to start
 /// executing.
-pub fn load_user_code_section() -> (ResourceHandle, usize) {
+pub fn load_user_code(code: &[u8]) -> (ResourceHandle, usize) {
+    assert!(code.len() <= 4096, "user code is too large");
+
     // TODO: Allocate enough space for the code we will load
     let user_code_section = VirtualMemoryRegion::alloc_with_guard(1).register();
 
@@ -67,38 +152,23 @@ pub fn load_user_code_section() -> (ResourceHandle, usize) {
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
     );
 
-    // TODO: load the code
-
-    // TODO: this is test code that is an infinite loop followed by nops
-    let start_addr = user_code_section.with(|cap| {
-        const TEST_CODE: &[u8] = &[
-            // here:
-            0x54, // push %rsp
-            0x58, // pop %rax
-            0x0f, 0x05, // syscall
-            0xeb, 0xfa, // jmp here
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-            0x90, // nop
-        ];
-
-        unsafe {
-            let start = cap_unwrap!(VirtualMemoryRegion(cap)).start();
-            for (i, b) in TEST_CODE.iter().enumerate() {
-                start.offset(i as isize).write(*b);
-            }
-            start as usize
+    let start_addr = user_code_section.with(|cap| unsafe {
+        let start = cap_unwrap!(VirtualMemoryRegion(cap)).start();
+        for (i, b) in code.iter().enumerate() {
+            start.offset(i as isize).write(*b);
         }
+        start as usize
     });
 
     (user_code_section, start_addr)
 }
 
+/// The number of system calls handled so far, on all cores.
+#[allow(dead_code)]
+pub fn syscall_count() -> usize {
+    SYSCALL_COUNT.load(Ordering::Relaxed)
+}
+
 /// Allocates virtual address space for the user stack (fixed size). Adds appropriate page table
 /// mappings (read/write, not execute).
 ///
@@ -150,6 +220,47 @@ pub fn init() {
     }
 }
 
+/// Has the current user continuation used up its time slice?
+fn slice_expired() -> bool {
+    SLICE_END.lock()[cpu_id()].map_or(false, |slice_end| SysTime::now() >= slice_end)
+}
+
+/// Start a new time slice and switch to user mode with the given registers.
+fn resume_user(registers: &SavedRegs) -> ! {
+    SLICE_END.lock()[cpu_id()] = Some(SysTime::now().after_ticks(TIME_SLICE));
+    syscall::switch_to_user(registers)
+}
+
+/// Stop running the current user continuation and put it back on the ready queue with the given
+/// registers. Then, run the scheduler.
+///
+/// Interrupts must be disabled.
+fn preempt(registers: SavedRegs) -> ! {
+    let _ = crate::sched::enqueue(vec![(
+        EventKind::Now,
+        Continuation::new(move |_| resume_user(&registers)),
+    )]);
+
+    crate::sched::preempt()
+}
+
+/// Called by the timer interrupt handler with the interrupted registers. If the interrupt came
+/// from user mode and the user's time slice has run out, the user continuation is preempted, and
+/// this function does not return.
+///
+/// Interrupts must be disabled, and the interrupt must already be acknowledged.
+pub fn maybe_preempt(frame: &TrapFrame) {
+    // Don't preempt the kernel. Checking this first also means we never take `SLICE_END` while the
+    // kernel on this core is holding it.
+    if frame.cs & 0b11 != 3 {
+        return;
+    }
+
+    if slice_expired() {
+        preempt(frame.saved_regs());
+    }
+}
+
 pub fn start_user_task(code: (ResourceHandle, usize), stack: ResourceHandle) -> ! {
     // Compute new register values
     let rsp = stack.with(|cap| {
@@ -171,13 +282,19 @@ pub fn start_user_task(code: (ResourceHandle, usize), stack: ResourceHandle) ->
         ..SavedRegs::default()
     };
 
-    syscall::switch_to_user(&registers)
+    resume_user(&registers)
 }
 
 mod syscall {
     //! System call handling.
 
-    use super::SavedRegs;
+    use core::sync::atomic::Ordering;
+
+    use crate::{log::read_dmesg, memory::user_writable};
+
+    use super::{
+        preempt, slice_expired, SavedRegs, LAPIC_VADDR, SYSCALL_COUNT, SYSCALL_DMESG, SYSCALL_EXIT,
+    };
 
     /// Handle a `syscall` instruction from userspace.
     ///
@@ -187,7 +304,7 @@ mod syscall {
     /// Interrupts are disabled on entry.
     ///
     /// Contract with userspace (beyond what the ISA does):
-    /// - System call argument is passed in %rax
+    /// - System call number is passed in %rax, and its arguments in %rdi and %rsi
     /// - We may clobber %rdx
     /// - We will save and restore all other registers, including the stack pointer
     /// - We will return values in %rax
@@ -199,8 +316,13 @@ mod syscall {
             # save the user stack pointer to %rdx before we switch stacks.
             mov %rsp, %rdx
 
-            # switch to the tmp stack
-            mov $0, %rsp
+            # switch to this core's tmp stack. We don't have any free registers, so we use %rsp to
+            # compute the address of `CURRENT_STACK_HEADS[cpu_id]`, where `cpu_id` is the local
+            # APIC ID (bits 31:24 of the APIC ID register at offset 0x20).
+            movabs $1, %rsp
+            movl 0x20(%rsp), %esp
+            shr $$24, %rsp
+            lea ${0:c}(, %rsp, 8), %rsp
             mov (%rsp), %rsp
 
             # start saving stuff
@@ -230,7 +352,7 @@ mod syscall {
             call handle_syscall
             "
             : /* no outputs */
-            : "i"(&super::super::CURRENT_STACK_HEAD)
+            : "i"(&super::super::CURRENT_STACK_HEADS), "i"(LAPIC_VADDR)
             : "memory", "rax", "rbx", "rcx", "rdx", "rdi", "rsi", "r8", "r9", "r10", "r11", "r12",
               "r13", "r14", "r15", "rbp", "stack"
             : "volatile"
@@ -245,9 +367,40 @@ mod syscall {
     unsafe extern "C" fn handle_syscall(saved_regs: &mut SavedRegs) {
         // TODO: can probably enable interrupts here...
 
+        SYSCALL_COUNT.fetch_add(1, Ordering::Relaxed);
+
         // Handle the system call. The syscall number is passed in %rax.
         match saved_regs.rax {
-            n => printk!("syscall #{:#x?}\n", n),
+            // Drop the user continuation and run something else. Like preemption, this happens
+            // with interrupts disabled.
+            SYSCALL_EXIT => crate::sched::preempt(),
+
+            SYSCALL_DMESG => {
+                let (buf, len) = (saved_regs.rdi, saved_regs.rsi);
+                saved_regs.rax = if user_writable(buf, len) {
+                    let buf = core::slice::from_raw_parts_mut(buf as *mut u8, len as usize);
+
+                    // Fault in the buffer first, so that we don't take a page fault (which may
+                    // want to log) while holding the log lock.
+                    for b in buf.iter_mut().step_by(4096) {
+                        core::ptr::write_volatile(b, 0);
+                    }
+                    if let Some(b) = buf.last_mut() {
+                        core::ptr::write_volatile(b, 0);
+                    }
+
+                    read_dmesg(buf) as u64
+                } else {
+                    core::u64::MAX
+                };
+            }
+
+            n => debug!("unknown syscall #{:#x?}", n),
+        }
+
+        // A user that makes a lot of system calls should not get to avoid preemption.
+        if slice_expired() {
+            preempt(core::mem::replace(saved_regs, SavedRegs::default()));
         }
 
         // Return to usermode
@@ -324,3 +477,39 @@ mod syscall {
         unreachable!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use crate::{
+        continuation::{Continuation, EventKind},
+        task,
+    };
+
+    use super::{allocate_user_stack, load_user_code, start_user_task, syscall_count};
+
+    kernel_test!(syscall_and_exit, 5, async {
+        const CODE: &[u8] = &[
+            0xb8, 0x42, 0x00, 0x00, 0x00, // mov $0x42, %eax
+            0x0f, 0x05, // syscall
+            0x31, 0xc0, // xor %eax, %eax
+            0x0f, 0x05, // syscall (exit)
+            0xeb, 0xfe, // jmp . (not reached)
+        ];
+
+        let before = syscall_count();
+
+        let code = load_user_code(CODE);
+        let stack = allocate_user_stack();
+        let _ = crate::sched::enqueue(vec![(
+            EventKind::Now,
+            Continuation::new(move |_| start_user_task(code, stack)),
+        )]);
+
+        task::sleep(1).await;
+
+        // Exactly two: the user task must not still be running.
+        assert_eq!(syscall_count() - before, 2);
+    });
+}
@@ -0,0 +1,452 @@
+//! A debug shell for poking at the kernel's state at run time.
+//!
+//! The shell is a task (see `task`) that owns the console terminal (see `io::vt`) and reads lines
+//! from it. Input from the serial console goes to the same place, so it also works with
+//! `-nographic`. Type `help` for a list of commands.
+
+// Tests only run the commands, not the shell itself.
+#![cfg_attr(test, allow(dead_code))]
+
+use alloc::{string::String, vec, vec::Vec};
+
+use spin::Mutex;
+
+use crate::{
+    acpi,
+    cap::{self, ResourceHandle},
+    continuation::{CancelToken, ContResult, Continuation, EventKind},
+    debug::Debug,
+    io::{block, kbd, keymap::KEYMAPS, mouse, vt},
+    log::{self, Level},
+    memory, pci, power,
+    sched::{self, user},
+    smp, task,
+    time::SysTime,
+};
+
+/// A shell command.
+struct Command {
+    name: &'static str,
+
+    /// A description of the arguments, for `help`.
+    args: &'static str,
+
+    help: &'static str,
+
+    /// Runs the command with the given arguments. Errors are printed after the command name.
+    run: fn(&[&str]) -> Result<(), &'static str>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: "",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "ps",
+        args: "",
+        help: "list running and enqueued continuations",
+        run: ps,
+    },
+    Command {
+        name: "caps",
+        args: "",
+        help: "list registered capabilities",
+        run: caps,
+    },
+    Command {
+        name: "allowed",
+        args: "",
+        help: "list the regions that can be demand paged",
+        run: allowed,
+    },
+    Command {
+        name: "mem",
+        args: "",
+        help: "show physical and virtual memory usage",
+        run: mem,
+    },
+    Command {
+        name: "uptime",
+        args: "",
+        help: "show the time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "pci",
+        args: "",
+        help: "list PCI devices",
+        run: pci,
+    },
+    Command {
+        name: "disks",
+        args: "",
+        help: "list disks",
+        run: disks,
+    },
+    Command {
+        name: "acpi",
+        args: "",
+        help: "list ACPI tables",
+        run: acpi,
+    },
+    Command {
+        name: "dmesg",
+        args: "",
+        help: "show the kernel log",
+        run: dmesg,
+    },
+    Command {
+        name: "log",
+        args: "<module|*> <level|reset>",
+        help: "set the log level for a module, or for everything",
+        run: log_level,
+    },
+    Command {
+        name: "keymap",
+        args: "[name]",
+        help: "set the keyboard layout (no name lists them)",
+        run: keymap,
+    },
+    Command {
+        name: "mouse",
+        args: "",
+        help: "start or stop printing mouse events",
+        run: mouse,
+    },
+    Command {
+        name: "user",
+        args: "<program>",
+        help: "run a test user program (no program lists them)",
+        run: user_program,
+    },
+    Command {
+        name: "reboot",
+        args: "",
+        help: "reboot the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        args: "",
+        help: "power off the machine",
+        run: shutdown,
+    },
+];
+
+/// Test programs for `user`: a name, a description, and machine code.
+const USER_PROGRAMS: &[(&str, &str, &[u8])] = &[
+    (
+        "exit",
+        "make an unknown system call, then exit",
+        &[
+            0xb8, 0x42, 0x00, 0x00, 0x00, // mov $0x42, %eax
+            0x0f, 0x05, // syscall
+            0x31, 0xc0, // xor %eax, %eax
+            0x0f, 0x05, // syscall (exit)
+            0xeb, 0xfe, // jmp . (not reached)
+        ],
+    ),
+    (
+        "dmesg",
+        "read the kernel log into a buffer on the stack, then exit",
+        &[
+            0x48, 0x81, 0xec, 0x00, 0x02, 0x00, 0x00, // sub $0x200, %rsp
+            0x48, 0x89, 0xe7, // mov %rsp, %rdi
+            0xbe, 0x00, 0x02, 0x00, 0x00, // mov $0x200, %esi
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov $1, %eax
+            0x0f, 0x05, // syscall (dmesg)
+            0x31, 0xc0, // xor %eax, %eax
+            0x0f, 0x05, // syscall (exit)
+            0xeb, 0xfe, // jmp . (not reached)
+        ],
+    ),
+    (
+        "spin",
+        "loop forever (it is preempted when its time slice runs out)",
+        &[
+            0xeb, 0xfe, // jmp .
+        ],
+    ),
+];
+
+/// Returns a continuation that runs the shell.
+pub fn continuation() -> Continuation {
+    task::continuation(run())
+}
+
+async fn run() -> ContResult {
+    let term = match vt::open(vt::CONSOLE) {
+        Ok(term) => term,
+        Err(err) => {
+            error!("shell: {}", err);
+            return ContResult::Done;
+        }
+    };
+
+    printk!("\nos2 debug shell. Type `help` for a list of commands.\n");
+
+    loop {
+        printk!("os2> ");
+        if let Some(line) = read_line(term).await {
+            execute(&line);
+        }
+    }
+}
+
+/// Read a line of input from the terminal `term` (which echoes it). Returns `None` if Ctrl-C was
+/// typed.
+async fn read_line(term: ResourceHandle) -> Option<String> {
+    let mut line = Vec::new();
+
+    loop {
+        match task::terminal(term).await? {
+            b'\n' => return Some(String::from_utf8_lossy(&line).into_owned()),
+            b => line.push(b),
+        }
+    }
+}
+
+/// Run the command on `line`.
+fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    match COMMANDS.iter().find(|cmd| cmd.name == *name) {
+        Some(cmd) => {
+            if let Err(error) = (cmd.run)(args) {
+                printk!("{}: {}\n", name, error);
+            }
+        }
+        None => printk!("unknown command `{}`; try `help`\n", name),
+    }
+}
+
+fn help(_: &[&str]) -> Result<(), &'static str> {
+    for cmd in COMMANDS {
+        printk!("  {:8} {:26} {}\n", cmd.name, cmd.args, cmd.help);
+    }
+
+    Ok(())
+}
+
+fn ps(_: &[&str]) -> Result<(), &'static str> {
+    // Don't print while holding the scheduler locks.
+    let mut conts = Vec::new();
+    sched::for_each_continuation(|cont| conts.push(cont));
+
+    printk!("  {:>6} {:>3} {:10} {}\n", "ID", "CPU", "CLASS", "STATE");
+    for cont in conts {
+        printk!(
+            "  {:>6} {:>3} {:10} ",
+            cont.id,
+            cont.cpu,
+            alloc::format!("{:?}", cont.priority)
+        );
+
+        match cont.waiting_for {
+            None => printk!("running\n"),
+            Some(EventKind::Now) => printk!("ready\n"),
+            Some(EventKind::Keyboard(_)) => printk!("waiting for keyboard\n"),
+            Some(EventKind::Serial(port)) => printk!("waiting for COM{}\n", port),
+            Some(EventKind::Terminal(_)) => printk!("waiting for terminal\n"),
+            Some(EventKind::Mouse(_)) => printk!("waiting for mouse\n"),
+            Some(EventKind::Block(_)) => printk!("waiting for disk\n"),
+            Some(EventKind::Woken(_)) => printk!("waiting for waker\n"),
+            Some(EventKind::Until(time)) => printk!("waiting until {}\n", time),
+            Some(EventKind::Error(error)) => printk!("handling {:?}\n", error),
+        }
+    }
+
+    Ok(())
+}
+
+fn caps(_: &[&str]) -> Result<(), &'static str> {
+    cap::for_each_capability(|key, cap| printk!("  {:032x} {:?}\n", key, cap));
+    Ok(())
+}
+
+fn allowed(_: &[&str]) -> Result<(), &'static str> {
+    // Don't print while holding the lock the page fault handler needs.
+    let mut regions = Vec::new();
+    memory::for_each_allowed(|start, len, flags| regions.push((start, len, flags)));
+
+    for (start, len, flags) in regions {
+        printk!("  {:#014x}-{:#014x} {:?}\n", start, start + len, flags);
+    }
+
+    Ok(())
+}
+
+fn mem(_: &[&str]) -> Result<(), &'static str> {
+    let stats = memory::stats();
+
+    printk!(
+        "  physical: {} of {} frames allocated ({} MiB free)\n",
+        stats.phys_allocated,
+        stats.phys_frames,
+        (stats.phys_frames - stats.phys_allocated) >> 8
+    );
+    printk!("  virtual: {} pages allocated\n", stats.virt_allocated);
+    printk!(
+        "  allowed: {} regions, {} KiB\n",
+        stats.allowed_regions,
+        stats.allowed_bytes >> 10
+    );
+
+    Ok(())
+}
+
+fn uptime(_: &[&str]) -> Result<(), &'static str> {
+    printk!("  up {} s, {} cpus\n", SysTime::now(), smp::ncpus());
+    Ok(())
+}
+
+fn pci(_: &[&str]) -> Result<(), &'static str> {
+    pci::dump();
+    Ok(())
+}
+
+fn disks(_: &[&str]) -> Result<(), &'static str> {
+    block::dump();
+    Ok(())
+}
+
+fn acpi(_: &[&str]) -> Result<(), &'static str> {
+    acpi::dump();
+    Ok(())
+}
+
+fn dmesg(_: &[&str]) -> Result<(), &'static str> {
+    let mut buf = vec![0; 64 * 1024];
+    let len = log::read_dmesg(&mut buf);
+    Debug.write_bytes(&buf[..len]);
+    Ok(())
+}
+
+fn log_level(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: log <module|*> <level|reset>";
+
+    let (module, level) = match args {
+        [module, level] => (*module, *level),
+        _ => return Err(USAGE),
+    };
+
+    match (module, level) {
+        ("*", "reset") => return Err("can only reset a module"),
+        (module, "reset") => log::set_module_level(module, None),
+        ("*", level) => log::set_level(Level::from_name(level).ok_or(USAGE)?),
+        (module, level) => {
+            log::set_module_level(module, Some(Level::from_name(level).ok_or(USAGE)?))
+        }
+    }
+
+    Ok(())
+}
+
+fn keymap(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [name] => kbd::set_keymap(name),
+
+        _ => {
+            let current = kbd::keymap_name();
+            for keymap in KEYMAPS {
+                let mark = if keymap.name == current { "*" } else { " " };
+                printk!(" {}{}\n", mark, keymap.name);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// While the `mouse` command is on: its mouse capability, and the task printing the events.
+static MOUSE: Mutex<Option<(ResourceHandle, CancelToken)>> = Mutex::new(None);
+
+fn mouse(_: &[&str]) -> Result<(), &'static str> {
+    let mut printing = MOUSE.lock();
+
+    if let Some((handle, token)) = printing.take() {
+        token.cancel_tree();
+        mouse::close(handle);
+        printk!("stopped printing mouse events\n");
+        return Ok(());
+    }
+
+    let handle = mouse::open();
+    let printer = task::continuation(print_mouse_events(handle));
+    let tokens = sched::enqueue(vec![(EventKind::Now, printer)]);
+    *printing = Some((handle, tokens[0].clone()));
+    printk!("printing mouse events; run `mouse` again to stop\n");
+
+    Ok(())
+}
+
+async fn print_mouse_events(handle: ResourceHandle) -> ContResult {
+    loop {
+        printk!("{:?}\n", task::mouse(handle).await);
+    }
+}
+
+fn user_program(args: &[&str]) -> Result<(), &'static str> {
+    let code = match args {
+        [name] => match USER_PROGRAMS.iter().find(|(other, _, _)| other == name) {
+            Some(&(_, _, code)) => code,
+            None => return Err("no such program"),
+        },
+
+        _ => {
+            for (name, help, _) in USER_PROGRAMS {
+                printk!("  {:8} {}\n", name, help);
+            }
+            return Ok(());
+        }
+    };
+
+    let code = user::load_user_code(code);
+    let stack = user::allocate_user_stack();
+    let _ = sched::enqueue(vec![(
+        EventKind::Now,
+        Continuation::new(move |_| user::start_user_task(code, stack)),
+    )]);
+
+    printk!("  started\n");
+
+    Ok(())
+}
+
+fn reboot(_: &[&str]) -> Result<(), &'static str> {
+    power::reboot()
+}
+
+fn shutdown(_: &[&str]) -> Result<(), &'static str> {
+    power::shutdown()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::execute;
+
+    kernel_test!(commands_run, 5, async {
+        for line in &[
+            "", "help", "ps", "caps", "allowed", "mem", "uptime", "pci", "disks", "acpi", "dmesg",
+            "keymap", "mouse", "mouse", "nope",
+        ] {
+            execute(line);
+        }
+    });
+
+    kernel_test!(log_command, 5, async {
+        use crate::log::{enabled, Level};
+
+        execute("log kernel::shell trace");
+        assert!(enabled(Level::Trace, "kernel::shell::tests"));
+
+        execute("log kernel::shell reset");
+        assert!(!enabled(Level::Trace, "kernel::shell::tests"));
+    });
+}
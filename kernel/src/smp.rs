@@ -0,0 +1,274 @@
+//! Booting the application processors (APs).
+//!
+//! Only the bootstrap processor (BSP) is running when we get to `kernel_main`. The others are
+//! woken with the INIT-SIPI-SIPI sequence via the local APIC. A STARTUP IPI makes the core start
+//! executing in real mode at a page-aligned address below 1MiB, so we copy a small trampoline
+//! there that switches to long mode using the kernel's page tables and then jumps to `ap_main`.
+//!
+//! We start the enabled cores listed in the ACPI MADT, one at a time. If there is no MADT, we just
+//! try to start every APIC ID below `MAX_CPUS` and see which ones come up.
+//!
+//! Each core gets its own GDT, TSS, IST stacks, and scheduler (with its own continuation stacks).
+//! Continuations are enqueued on the core that created them. Idle cores steal ready continuations
+//! from other cores.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::{registers::control::Cr3, structures::paging::PageTableFlags};
+
+use crate::{acpi, interrupts::lapic, memory::map_fixed, sched, time::SysTime};
+
+/// The maximum number of cores we support. Cores are identified by their local APIC ID, which
+/// must be less than this.
+pub const MAX_CPUS: usize = 16;
+
+/// The physical (and virtual) address the trampoline is copied to. The frame is kept out of the
+/// frame allocator (see `memory::paging`).
+///
+/// NOTE: this is hard-coded in the trampoline assembly below, too.
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// The size of each AP's initial kernel stack (bytes). This is only used until the AP enters the
+/// scheduler.
+const AP_STACK_SIZE: usize = 4096 * 4;
+
+/// The number of cores that are up and running, including the BSP.
+static NCPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by an AP when it has finished booting and no longer needs the trampoline.
+static AP_READY: AtomicBool = AtomicBool::new(false);
+
+/// The APIC ID of the BSP.
+static BSP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The ID of the current core.
+pub fn cpu_id() -> usize {
+    lapic::id() as usize
+}
+
+/// Is the current core the BSP?
+pub fn is_bsp() -> bool {
+    cpu_id() == BSP_ID.load(Ordering::Relaxed)
+}
+
+/// The number of cores that are up and running.
+pub fn ncpus() -> usize {
+    NCPUS.load(Ordering::Relaxed)
+}
+
+// The trampoline. It is assembled into the kernel text and copied to `TRAMPOLINE_ADDR` before
+// use, so all addresses are computed relative to `ap_trampoline_start`.
+//
+// The BSP fills in `ap_cr3`, `ap_stack`, and `ap_entry` before starting each AP.
+global_asm!(
+    "
+    .section .text
+    .code16
+    .global ap_trampoline_start
+    ap_trampoline_start:
+        cli
+        cld
+        xor %ax, %ax
+        mov %ax, %ds
+
+        # Load a temporary GDT and go to protected mode.
+        lgdtl (ap_gdt_ptr - ap_trampoline_start + 0x8000)
+        mov %cr0, %eax
+        or $1, %eax
+        mov %eax, %cr0
+        ljmpl $0x8, $(ap_trampoline_32 - ap_trampoline_start + 0x8000)
+
+    .code32
+    ap_trampoline_32:
+        mov $0x10, %ax
+        mov %ax, %ds
+        mov %ax, %es
+        mov %ax, %ss
+
+        # Enable PAE and global pages.
+        mov %cr4, %eax
+        or $((1 << 5) | (1 << 7)), %eax
+        mov %eax, %cr4
+
+        # Use the kernel's page tables.
+        mov (ap_cr3 - ap_trampoline_start + 0x8000), %eax
+        mov %eax, %cr3
+
+        # Enable long mode and no-execute.
+        mov $0xC0000080, %ecx
+        rdmsr
+        or $((1 << 8) | (1 << 11)), %eax
+        wrmsr
+
+        # Enable paging and write protection.
+        mov %cr0, %eax
+        or $((1 << 31) | (1 << 16)), %eax
+        mov %eax, %cr0
+
+        ljmpl $0x18, $(ap_trampoline_64 - ap_trampoline_start + 0x8000)
+
+    .code64
+    ap_trampoline_64:
+        mov (ap_stack - ap_trampoline_start + 0x8000), %rsp
+        xor %rbp, %rbp
+        mov (ap_entry - ap_trampoline_start + 0x8000), %rax
+        call *%rax
+
+    1:
+        hlt
+        jmp 1b
+
+    .align 8
+    ap_gdt:
+        .quad 0
+        .quad 0x00cf9a000000ffff # 32-bit code
+        .quad 0x00cf92000000ffff # 32-bit data
+        .quad 0x00af9a000000ffff # 64-bit code
+    ap_gdt_ptr:
+        .word ap_gdt_ptr - ap_gdt - 1
+        .long ap_gdt - ap_trampoline_start + 0x8000
+
+    .align 8
+    .global ap_cr3
+    ap_cr3:
+        .quad 0
+    .global ap_stack
+    ap_stack:
+        .quad 0
+    .global ap_entry
+    ap_entry:
+        .quad 0
+
+    .global ap_trampoline_end
+    ap_trampoline_end:
+    "
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_cr3: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+}
+
+/// Returns a pointer to the copy of the trampoline variable `var` at `TRAMPOLINE_ADDR`.
+unsafe fn trampoline_var(var: &u8) -> *mut u64 {
+    let offset = var as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+    (TRAMPOLINE_ADDR + offset) as *mut u64
+}
+
+/// Busy-wait for the given number of timer ticks. Interrupts must be enabled.
+fn wait_ticks(ticks: usize) {
+    let end = SysTime::now().after_ticks(ticks);
+    while SysTime::now() < end {
+        spin_loop_hint();
+    }
+}
+
+/// Boot all of the APs. This should be called on the BSP after interrupts are enabled (we need
+/// the timer for delays).
+pub fn init() {
+    // The trampoline runs with the kernel page tables right after enabling paging, so it needs to
+    // be identity mapped.
+    map_fixed(
+        TRAMPOLINE_ADDR,
+        TRAMPOLINE_ADDR,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    );
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, TRAMPOLINE_ADDR as *mut u8, len);
+
+        // The trampoline loads CR3 before it is in long mode, so it only gets 32 bits.
+        let cr3 = Cr3::read().0.start_address().as_u64();
+        assert!(cr3 < 1 << 32, "PML4 at {:#x} is above 4GiB", cr3);
+        trampoline_var(&ap_cr3).write_volatile(cr3);
+        trampoline_var(&ap_entry).write_volatile(ap_main as u64);
+    }
+
+    let bsp = lapic::id();
+    BSP_ID.store(bsp as usize, Ordering::Relaxed);
+    let mut stack = None;
+
+    let apic_ids: Vec<u8> = match acpi::get().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) => madt
+            .cpus
+            .iter()
+            .filter(|cpu| cpu.enabled)
+            .map(|cpu| cpu.apic_id)
+            .collect(),
+        None => (0..MAX_CPUS as u8).collect(),
+    };
+
+    for apic_id in apic_ids {
+        if apic_id == bsp {
+            continue;
+        }
+
+        if apic_id as usize >= MAX_CPUS {
+            warn!("skipping cpu {}: too many cpus", apic_id);
+            continue;
+        }
+
+        // Only allocate a new stack if the last one was used.
+        let stack_top = *stack.get_or_insert_with(|| {
+            #[repr(align(16))]
+            struct Stack {
+                _data: [u8; AP_STACK_SIZE],
+            }
+
+            let stack = Box::leak(box Stack {
+                _data: [0; AP_STACK_SIZE],
+            });
+            stack as *mut Stack as u64 + AP_STACK_SIZE as u64
+        });
+
+        AP_READY.store(false, Ordering::SeqCst);
+        unsafe {
+            trampoline_var(&ap_stack).write_volatile(stack_top);
+        }
+
+        // INIT-SIPI-SIPI. The second SIPI is only needed if the first one was missed.
+        lapic::send_init(apic_id);
+        wait_ticks(10);
+        lapic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        wait_ticks(1);
+        if !AP_READY.load(Ordering::SeqCst) {
+            lapic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        }
+
+        // Give it a bit to come up. If it doesn't, assume there is no such core.
+        let give_up = SysTime::now().after_ticks(100);
+        while !AP_READY.load(Ordering::SeqCst) && SysTime::now() < give_up {
+            spin_loop_hint();
+        }
+
+        if AP_READY.load(Ordering::SeqCst) {
+            NCPUS.fetch_add(1, Ordering::Relaxed);
+            stack = None;
+        }
+    }
+
+    info!("smp inited - {} cpus", ncpus());
+}
+
+/// The first Rust code an AP runs, on the stack given to it by the BSP.
+extern "C" fn ap_main() -> ! {
+    info!("cpu {} up", cpu_id());
+
+    crate::interrupts::init_ap();
+    sched::user::init();
+    sched::init_ap();
+
+    // Done with the trampoline; the BSP can start the next AP.
+    AP_READY.store(true, Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::enable();
+
+    sched::start()
+}
@@ -0,0 +1,417 @@
+//! An adapter that lets kernel tasks be written as `async` code rather than as nested
+//! `Continuation::new` closures.
+//!
+//! A task is a future that is polled from a continuation. Each time the future awaits an event
+//! (e.g. `sleep_until` or `keyboard`), the poll returns `Pending` and the continuation returns
+//! `ContResult::Success` with the matching `EventKind` and a continuation that polls the future
+//! again. So there is still only one stack, and no state lives on it between polls: everything the
+//! task needs is in the (boxed) future.
+//!
+//! Futures that are not built on `EventKind`s can use the `Waker` instead. A task that is pending
+//! without waiting for an event waits for `EventKind::Woken` with its ID, which is ready once its
+//! waker has been woken (possibly while the task was still being polled). Since the continuation
+//! waiting for it stays in the scheduler like any other, it keeps its supervisor, scheduling class
+//! and place in the continuation DAG, so it can be cancelled. Wakers must not be woken from
+//! interrupt handlers, since waking takes a lock that the scheduler also takes.
+//!
+//! A task can only wait for one event at a time (i.e. no `select!`).
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+
+use core::{
+    future::Future,
+    mem::ManuallyDrop,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use spin::Mutex;
+
+use crate::{
+    cap::ResourceHandle,
+    continuation::{ContResult, Continuation, Event, EventKind},
+    io::{block::BlockError, kbd::KeyEvent, mouse::MouseEvent},
+    smp::{cpu_id, MAX_CPUS},
+    time::SysTime,
+};
+
+/// The event the task currently being polled on each core is waiting for, if any. Set by
+/// `EventFuture`. Indexed by `cpu_id`.
+static WAITING_FOR: Mutex<[Option<EventKind>; MAX_CPUS]> = Mutex::new([None; MAX_CPUS]);
+
+/// The event that woke up the task currently being polled on each core, if any. Taken by
+/// `EventFuture`. Indexed by `cpu_id`.
+static DELIVERED: Mutex<[Option<Event>; MAX_CPUS]> = Mutex::new([None; MAX_CPUS]);
+
+/// The IDs of the tasks whose wakers have been woken since they were last polled (see `woken`).
+static WOKEN: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// The ID of the next task.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The state of a task that is shared with its wakers.
+struct TaskCell {
+    /// Identifies the task in `EventKind::Woken`.
+    id: u64,
+
+    /// Set when the task is gone, so that its wakers don't do anything anymore. This is only
+    /// accessed with `WOKEN` locked.
+    finished: AtomicBool,
+}
+
+impl TaskCell {
+    /// Called by the waker.
+    fn wake(&self) {
+        let mut woken = WOKEN.lock();
+        if !self.finished.load(Ordering::Relaxed) && !woken.contains(&self.id) {
+            woken.push(self.id);
+        }
+    }
+}
+
+/// A task: its future, and the state it shares with its wakers. It is owned by the continuation
+/// that polls it next, so it goes away if that continuation is cancelled.
+struct Task {
+    future: Pin<Box<dyn Future<Output = ContResult> + Send>>,
+    cell: Arc<TaskCell>,
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let mut woken = WOKEN.lock();
+        self.cell.finished.store(true, Ordering::Relaxed);
+        woken.retain(|&id| id != self.cell.id);
+    }
+}
+
+/// Returns a continuation that runs `future` as a task. The continuation should be enqueued to
+/// wait for `EventKind::Now`. When the future completes, the continuation's result is the
+/// future's output.
+pub fn continuation<F>(future: F) -> Continuation
+where
+    F: 'static + Send + Future<Output = ContResult>,
+{
+    let task = Task {
+        future: Box::pin(future),
+        cell: Arc::new(TaskCell {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            finished: AtomicBool::new(false),
+        }),
+    };
+
+    resume(task, false)
+}
+
+/// Returns a continuation that polls `task`. If `deliver` is true, the event the continuation is
+/// run with is passed to the future the task is waiting on.
+fn resume(task: Task, deliver: bool) -> Continuation {
+    let mut task = Some(task);
+    Continuation::new(move |event| {
+        let task = task.take().expect("task is already being polled");
+        poll(task, if deliver { Some(event) } else { None })
+    })
+}
+
+/// Poll `task` once and turn the result into a `ContResult`.
+fn poll(mut task: Task, event: Option<Event>) -> ContResult {
+    DELIVERED.lock()[cpu_id()] = event;
+
+    let waker = waker(task.cell.clone());
+    let result = task.future.as_mut().poll(&mut Context::from_waker(&waker));
+
+    // Don't let anything leak into the next task that is polled.
+    let waiting_for = WAITING_FOR.lock()[cpu_id()].take();
+    DELIVERED.lock()[cpu_id()].take();
+
+    match result {
+        Poll::Ready(result) => result,
+
+        Poll::Pending => match waiting_for {
+            // Wait for the event, then deliver it.
+            Some(kind) => ContResult::Success(vec![(kind, resume(task, true))]),
+
+            // Wait for the waker. If it was woken while we were polling, this is ready right away.
+            None => {
+                let kind = EventKind::Woken(task.cell.id);
+                ContResult::Success(vec![(kind, resume(task, false))])
+            }
+        },
+    }
+}
+
+/// The event for a continuation waiting for the waker of task `id`, if it has been woken.
+pub fn woken(id: u64) -> Option<Event> {
+    let mut woken = WOKEN.lock();
+    let i = woken.iter().position(|&woken| woken == id)?;
+    woken.swap_remove(i);
+    Some(Event::Now)
+}
+
+/// Returns a `Waker` for the task in `cell`.
+fn waker(cell: Arc<TaskCell>) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(Arc::into_raw(cell) as *const (), &WAKER_VTABLE)) }
+}
+
+/// A `Waker` is just an `Arc<TaskCell>`.
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+
+unsafe fn waker_clone(ptr: *const ()) -> RawWaker {
+    let cell = ManuallyDrop::new(Arc::from_raw(ptr as *const TaskCell));
+    let clone: Arc<TaskCell> = (*cell).clone();
+    RawWaker::new(Arc::into_raw(clone) as *const (), &WAKER_VTABLE)
+}
+
+unsafe fn waker_wake(ptr: *const ()) {
+    let cell = Arc::from_raw(ptr as *const TaskCell);
+    cell.wake();
+}
+
+unsafe fn waker_wake_by_ref(ptr: *const ()) {
+    let cell = ManuallyDrop::new(Arc::from_raw(ptr as *const TaskCell));
+    cell.wake();
+}
+
+unsafe fn waker_drop(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const TaskCell));
+}
+
+/// A future that completes when the given kind of event happens.
+pub struct EventFuture {
+    kind: EventKind,
+
+    /// Have we told the task adapter what we are waiting for yet?
+    registered: bool,
+}
+
+impl Future for EventFuture {
+    type Output = Event;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Event> {
+        if self.registered {
+            if let Some(event) = DELIVERED.lock()[cpu_id()].take() {
+                return Poll::Ready(event);
+            }
+        }
+
+        let mut waiting_for = WAITING_FOR.lock();
+        let waiting_for = &mut waiting_for[cpu_id()];
+        assert!(
+            waiting_for.is_none(),
+            "a task can only wait for one event at a time"
+        );
+        *waiting_for = Some(self.kind);
+        self.registered = true;
+
+        Poll::Pending
+    }
+}
+
+/// Wait for the given kind of event, returning the event.
+pub fn wait(kind: EventKind) -> EventFuture {
+    EventFuture {
+        kind,
+        registered: false,
+    }
+}
+
+/// Wait until the system clock reads `time`.
+pub async fn sleep_until(time: SysTime) {
+    wait(EventKind::Until(time)).await;
+}
+
+/// Wait for `secs` seconds.
+pub async fn sleep(secs: usize) {
+    sleep_until(SysTime::now().after(secs)).await
+}
+
+/// Wait for the next byte of text typed on the keyboard `kbd` (see `io::kbd::Mode::Text`).
+#[allow(dead_code)]
+pub async fn keyboard(kbd: ResourceHandle) -> u8 {
+    loop {
+        // Raw key events can still arrive if the mode was switched while we were waiting.
+        if let Event::Keyboard(c) = wait(EventKind::Keyboard(kbd)).await {
+            return c;
+        }
+    }
+}
+
+/// Wait for the next byte received on the serial port COM`port`.
+pub async fn serial(port: u8) -> u8 {
+    match wait(EventKind::Serial(port)).await {
+        Event::Serial(b) => b,
+        _ => unreachable!(),
+    }
+}
+
+/// Wait for the next byte of input on the virtual terminal owned through `term` (see `io::vt`).
+/// Returns `None` if Ctrl-C was typed.
+pub async fn terminal(term: ResourceHandle) -> Option<u8> {
+    match wait(EventKind::Terminal(term)).await {
+        Event::Terminal(b) => Some(b),
+        Event::Interrupt => None,
+        _ => unreachable!(),
+    }
+}
+
+/// Wait for the next event for the mouse capability `mouse` (see `io::mouse`).
+pub async fn mouse(mouse: ResourceHandle) -> MouseEvent {
+    match wait(EventKind::Mouse(mouse)).await {
+        Event::Mouse(event) => event,
+        _ => unreachable!(),
+    }
+}
+
+/// Wait for the block device request `id` to finish (see `io::block`).
+pub async fn block(id: u64) -> Result<(), BlockError> {
+    match wait(EventKind::Block(id)).await {
+        Event::Block(result) => result,
+        _ => unreachable!(),
+    }
+}
+
+/// Wait for the next key press or release on the keyboard `kbd` (see `io::kbd::Mode::Raw`).
+#[allow(dead_code)]
+pub async fn key(kbd: ResourceHandle) -> KeyEvent {
+    loop {
+        // Text can still arrive if the mode was switched while we were waiting.
+        if let Event::Key(event) = wait(EventKind::Keyboard(kbd)).await {
+            return event;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec};
+
+    use core::{
+        future::Future,
+        pin::Pin,
+        sync::atomic::{AtomicBool, Ordering},
+        task::{Context, Poll, Waker},
+    };
+
+    use spin::Mutex;
+
+    use crate::{
+        continuation::{ContResult, EventKind},
+        sched,
+    };
+
+    /// A future that wakes itself up and is pending once.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// A flag that is set by someone else, who then wakes up whoever is waiting for it.
+    struct Flag {
+        set: AtomicBool,
+        waker: Mutex<Option<Waker>>,
+    }
+
+    impl Flag {
+        fn new() -> Arc<Self> {
+            Arc::new(Flag {
+                set: AtomicBool::new(false),
+                waker: Mutex::new(None),
+            })
+        }
+
+        fn set(&self) {
+            self.set.store(true, Ordering::SeqCst);
+            if let Some(waker) = self.waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// A future that completes once the flag is set.
+    struct WaitForFlag(Arc<Flag>);
+
+    impl Future for WaitForFlag {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            *self.0.waker.lock() = Some(cx.waker().clone());
+            if self.0.set.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Sets the flag when dropped.
+    struct SetOnDrop(Arc<AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    kernel_test!(wake_while_polling, 1, async {
+        YieldOnce(false).await;
+    });
+
+    // The waking continuation may be stolen by another core and run while we are being polled.
+    kernel_test!(wake_from_another_continuation, 5, async {
+        for _ in 0..100 {
+            let flag = Flag::new();
+            let setter = {
+                let flag = flag.clone();
+                super::continuation(async move {
+                    flag.set();
+                    ContResult::Done
+                })
+            };
+            let _ = sched::enqueue(vec![(EventKind::Now, setter)]);
+
+            WaitForFlag(flag).await;
+        }
+    });
+
+    kernel_test!(cancel_task_waiting_for_waker, 5, async {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = Flag::new();
+        let waiter = {
+            let guard = SetOnDrop(dropped.clone());
+            let flag = flag.clone();
+            super::continuation(async move {
+                let _guard = guard;
+                WaitForFlag(flag).await;
+                ContResult::Done
+            })
+        };
+        let tokens = sched::enqueue(vec![(EventKind::Now, waiter)]);
+
+        // Once it has a waker, it's waiting for it (or about to be).
+        while flag.waker.lock().is_none() {
+            YieldOnce(false).await;
+        }
+
+        tokens[0].cancel_tree();
+        while !dropped.load(Ordering::SeqCst) {
+            YieldOnce(false).await;
+        }
+    });
+
+    kernel_test!(sleep_then_continue, 5, async {
+        super::sleep(1).await;
+        super::sleep(1).await;
+    });
+}
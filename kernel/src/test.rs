@@ -0,0 +1,206 @@
+//! The in-kernel test framework. This is only built for `cargo xtest`.
+//!
+//! Test cases are declared anywhere in the kernel with `kernel_test!`. A test case is an `async`
+//! block that runs as a task (see `task`), so it can wait for events and spawn continuations like
+//! any other kernel code. A test passes if its future completes, and fails if it panics or if an
+//! error reaches its supervisor. Each test also has a timeout (in seconds), after which it is
+//! cancelled and counted as a failure, so a test that waits forever doesn't hang the whole run.
+//!
+//! The kernel boots as usual, then runs the tests one at a time, reporting results over the serial
+//! port. At the end, we exit QEMU with `QemuExitCode::Success` if all tests passed, and
+//! `QemuExitCode::Failed` otherwise. A panic also exits with `QemuExitCode::Failed` right away.
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+use crate::{
+    continuation::{ContError, ContResult, Continuation, EventKind},
+    power::{exit_qemu, QemuExitCode},
+    sched, task,
+    time::SysTime,
+};
+
+/// The future of a test case.
+pub type TestFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A test case. Declare these with `kernel_test!`.
+pub struct TestCase {
+    /// The name of the test, including the module path.
+    pub name: &'static str,
+
+    /// How long the test may run before it is considered hung (seconds).
+    pub timeout: usize,
+
+    /// Creates the future that runs the test.
+    pub body: fn() -> TestFuture,
+}
+
+/// Declare a test case with the given name, timeout (seconds), and `async` body.
+#[macro_export]
+macro_rules! kernel_test {
+    ($name:ident, $timeout:expr, $body:expr) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::test::TestCase = $crate::test::TestCase {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            timeout: $timeout,
+            body: || alloc::boxed::Box::pin($body),
+        };
+    };
+}
+
+/// How a test ended.
+#[derive(Copy, Clone, Debug)]
+enum Outcome {
+    Passed,
+    Failed(ContError),
+    TimedOut,
+}
+
+/// The state of the currently running test, shared by the test, its supervisor, its watchdog,
+/// and the runner. Whoever finishes first decides the outcome.
+struct TestState {
+    outcome: Option<Outcome>,
+
+    /// The runner, if it is waiting for the outcome.
+    waker: Option<Waker>,
+}
+
+/// Record the outcome of a test, unless it already has one, and wake up the runner.
+fn report(state: &Mutex<TestState>, outcome: Outcome) {
+    let waker = {
+        let mut state = state.lock();
+        if state.outcome.is_some() {
+            return;
+        }
+        state.outcome = Some(outcome);
+        state.waker.take()
+    }; // unlock
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// A future that completes with the outcome of a test.
+struct OutcomeFuture(Arc<Mutex<TestState>>);
+
+impl Future for OutcomeFuture {
+    type Output = Outcome;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Outcome> {
+        let mut state = self.0.lock();
+        match state.outcome {
+            Some(outcome) => Poll::Ready(outcome),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Run one test and wait for its outcome.
+async fn run_one(test: &'static TestCase) -> Outcome {
+    let state = Arc::new(Mutex::new(TestState {
+        outcome: None,
+        waker: None,
+    }));
+
+    let body = {
+        let state = state.clone();
+        task::continuation(async move {
+            (test.body)().await;
+            report(&state, Outcome::Passed);
+            ContResult::Done
+        })
+    };
+
+    let body = {
+        let state = state.clone();
+        body.supervised(move |error| {
+            report(&state, Outcome::Failed(error));
+            ContResult::Done
+        })
+    };
+
+    let watchdog = {
+        let state = state.clone();
+        Continuation::new(move |_| {
+            report(&state, Outcome::TimedOut);
+            ContResult::Done
+        })
+    };
+
+    let deadline = SysTime::now().after(test.timeout);
+    let tokens = sched::enqueue(vec![
+        (EventKind::Now, body),
+        (EventKind::Until(deadline), watchdog),
+    ]);
+
+    let outcome = OutcomeFuture(state).await;
+
+    // Whichever of the test and the watchdog is left should not run anymore.
+    tokens[0].cancel_tree();
+    tokens[1].cancel();
+
+    outcome
+}
+
+/// Run all of the tests, then exit QEMU.
+async fn run_all(tests: Vec<&'static TestCase>) -> ContResult {
+    let mut failed = 0;
+
+    for test in tests.iter() {
+        printk!("test {} ... ", test.name);
+
+        match run_one(test).await {
+            Outcome::Passed => printk!("ok\n"),
+            Outcome::Failed(error) => {
+                failed += 1;
+                printk!("FAILED ({:?})\n", error);
+            }
+            Outcome::TimedOut => {
+                failed += 1;
+                printk!("TIMED OUT ({} s)\n", test.timeout);
+            }
+        }
+    }
+
+    printk!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        if failed == 0 { "ok" } else { "FAILED" },
+        tests.len() - failed,
+        failed
+    );
+
+    exit_qemu(if failed == 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    })
+}
+
+/// The test runner, called by `test_main` once the kernel is initialized. It enqueues a task that
+/// runs the tests.
+pub fn runner(tests: &[&'static TestCase]) {
+    printk!("\nrunning {} tests\n", tests.len());
+
+    let _ = sched::enqueue(vec![(
+        EventKind::Now,
+        task::continuation(run_all(tests.to_vec())),
+    )]);
+}
+
+/// Called by the panic handler. The current test has failed, and we can't keep going.
+pub fn panicked() -> ! {
+    printk!("\ntest result: FAILED (panicked)\n");
+    exit_qemu(QemuExitCode::Failed)
+}
@@ -1,14 +1,17 @@
 //! A module for dealing with system time and the passage of time.
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::interrupts::PIT_HZ;
+use crate::interrupts::TIMER_HZ;
 
 /// Counts interrupts. This can be used as a source of time.
 static TICKS: AtomicUsize = AtomicUsize::new(0);
 
 /// Opaquely represents a system time
-#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct SysTime(usize);
 
 impl SysTime {
@@ -35,7 +38,24 @@ impl SysTime {
 
     /// Get the time `secs` seconds after `self`.
     pub fn after(self, secs: usize) -> Self {
-        SysTime(self.0 + secs * PIT_HZ)
+        SysTime(self.0 + secs * TIMER_HZ)
+    }
+
+    /// Get the time `ticks` timer ticks after `self`.
+    pub fn after_ticks(self, ticks: usize) -> Self {
+        SysTime(self.0 + ticks)
+    }
+}
+
+/// Formats the time since boot in seconds, e.g. `12.345`.
+impl fmt::Display for SysTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0 / TIMER_HZ;
+        let millis = (self.0 % TIMER_HZ) * 1000 / TIMER_HZ;
+
+        // A width (e.g. `{:9}`) right-aligns the whole thing, like a number.
+        let width = f.width().unwrap_or(0).saturating_sub(4);
+        write!(f, "{0:>1$}.{2:03}", secs, width, millis)
     }
 }
 
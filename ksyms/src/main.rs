@@ -0,0 +1,207 @@
+//! Fill in the symbol table embedded in the kernel, so that backtraces can be symbolized.
+//!
+//! The kernel reserves a fixed-size `.ksyms` section (see `kernel/src/backtrace.rs` for the
+//! format). This reads the function symbols from the kernel's ELF symbol table, demangles them, and
+//! writes them into that section, in place. Since the section doesn't change size, nothing else in
+//! the image moves, so the addresses in the table stay correct.
+//!
+//! ```txt
+//! ksyms <path to kernel ELF>
+//! ```
+//!
+//! Running it again on the same kernel just writes the same table again.
+
+use std::{env, fs, process};
+
+use rustc_demangle::demangle;
+
+/// The magic number at the start of the `.ksyms` section.
+const MAGIC: &[u8; 8] = b"KSYMS\0\0\0";
+
+/// The size of the header of the `.ksyms` section: the magic and the count.
+const HEADER_SIZE: usize = 16;
+
+/// The size of a table entry: address, name offset, name length.
+const ENTRY_SIZE: usize = 16;
+
+/// Section types
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+
+/// Symbol types
+const STT_FUNC: u8 = 2;
+
+/// A section header, with just the fields we need.
+struct Section {
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+}
+
+fn u16_at(elf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([elf[off], elf[off + 1]])
+}
+
+fn u32_at(elf: &[u8], off: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&elf[off..off + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(elf: &[u8], off: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&elf[off..off + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// The NUL-terminated string at `off` in `strtab`.
+fn str_at(strtab: &[u8], off: usize) -> &str {
+    let s = &strtab[off..];
+    let end = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+    std::str::from_utf8(&s[..end]).unwrap_or("")
+}
+
+/// Read the section headers of a little-endian ELF64 file.
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.len() < 64 || &elf[..4] != b"\x7fELF" || elf[4] != 2 || elf[5] != 1 {
+        return Err("not a little-endian ELF64 file".into());
+    }
+
+    let shoff = u64_at(elf, 0x28) as usize;
+    let shentsize = u16_at(elf, 0x3A) as usize;
+    let shnum = u16_at(elf, 0x3C) as usize;
+
+    if shentsize < 64 || shoff + shnum * shentsize > elf.len() {
+        return Err("bad section header table".into());
+    }
+
+    Ok((0..shnum)
+        .map(|i| {
+            let sh = shoff + i * shentsize;
+            Section {
+                name: u32_at(elf, sh),
+                kind: u32_at(elf, sh + 4),
+                offset: u64_at(elf, sh + 24) as usize,
+                size: u64_at(elf, sh + 32) as usize,
+                link: u32_at(elf, sh + 40),
+            }
+        })
+        .collect())
+}
+
+/// The contents of a section.
+fn contents<'a>(elf: &'a [u8], section: &Section) -> Result<&'a [u8], String> {
+    elf.get(section.offset..section.offset + section.size)
+        .ok_or_else(|| "section is out of bounds".to_string())
+}
+
+/// The function symbols of the kernel, demangled, sorted by address, one name per address.
+fn functions(elf: &[u8], sections: &[Section]) -> Result<Vec<(u64, String)>, String> {
+    let symtab = sections
+        .iter()
+        .find(|s| s.kind == SHT_SYMTAB)
+        .ok_or("no symbol table (was the kernel stripped?)")?;
+    let strtab = sections
+        .get(symtab.link as usize)
+        .ok_or("bad symbol string table")?;
+
+    let symbols = contents(elf, symtab)?;
+    let strtab = contents(elf, strtab)?;
+
+    let mut functions: Vec<_> = symbols
+        .chunks_exact(24)
+        .filter(|sym| sym[4] & 0xF == STT_FUNC)
+        .map(|sym| (u64_at(sym, 8), u32_at(sym, 0) as usize))
+        .filter(|&(addr, name)| addr != 0 && name < strtab.len())
+        .map(|(addr, name)| (addr, format!("{:#}", demangle(str_at(strtab, name)))))
+        .collect();
+
+    functions.sort();
+    functions.dedup_by_key(|(addr, _)| *addr);
+
+    Ok(functions)
+}
+
+/// Encode the table (everything after the magic).
+fn encode(functions: &[(u64, String)]) -> Vec<u8> {
+    let mut entries = Vec::with_capacity(functions.len() * ENTRY_SIZE);
+    let mut names = Vec::new();
+    let names_start = functions.len() * ENTRY_SIZE;
+
+    for (addr, name) in functions {
+        entries.extend_from_slice(&addr.to_le_bytes());
+        entries.extend_from_slice(&((names_start + names.len()) as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+
+    let mut table = (functions.len() as u64).to_le_bytes().to_vec();
+    table.extend(entries);
+    table.extend(names);
+    table
+}
+
+fn run(path: &str) -> Result<(), String> {
+    let mut elf = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let sections = sections(&elf)?;
+    let shstrndx = u16_at(&elf, 0x3E) as usize;
+    let shstrtab = contents(&elf, sections.get(shstrndx).ok_or("bad section name table")?)?;
+
+    let ksyms = sections
+        .iter()
+        .find(|s| str_at(shstrtab, s.name as usize) == ".ksyms")
+        .ok_or("no .ksyms section (is this the kernel?)")?;
+
+    if ksyms.kind == SHT_NOBITS || ksyms.size < HEADER_SIZE {
+        return Err(".ksyms section has no space in the file".into());
+    }
+    if &contents(&elf, ksyms)?[..8] != MAGIC {
+        return Err(".ksyms section has the wrong magic".into());
+    }
+
+    let functions = functions(&elf, &sections)?;
+    let table = encode(&functions);
+
+    if 8 + table.len() > ksyms.size {
+        return Err(format!(
+            "the symbol table needs {} bytes, but .ksyms only has {}; increase KSYMS_SIZE in \
+             kernel/src/backtrace.rs",
+            8 + table.len(),
+            ksyms.size
+        ));
+    }
+
+    // Clear out anything from a previous run, then write the table after the magic.
+    let section = &mut elf[ksyms.offset + 8..ksyms.offset + ksyms.size];
+    section.iter_mut().for_each(|b| *b = 0);
+    section[..table.len()].copy_from_slice(&table);
+
+    fs::write(path, &elf).map_err(|e| format!("{}: {}", path, e))?;
+
+    println!(
+        "ksyms: wrote {} symbols ({} of {} bytes)",
+        functions.len(),
+        8 + table.len(),
+        ksyms.size
+    );
+
+    Ok(())
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: ksyms <kernel ELF>");
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&path) {
+        eprintln!("ksyms: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,58 @@
+//! Benchmarks of the scheduler, run in the simulator.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use test::Bencher;
+
+use sim::{
+    continuation::{ContResult, Continuation, EventKind},
+    sched, simulate,
+};
+
+/// Enqueue and run a batch of continuations that do nothing.
+#[bench]
+fn run_1000_continuations(b: &mut Bencher) {
+    simulate(|| {
+        b.iter(|| {
+            let conts = (0..1000)
+                .map(|_| (EventKind::Now, Continuation::new(|_| ContResult::Done)))
+                .collect();
+            let _ = sched::enqueue(conts);
+
+            while sched::step() {}
+        })
+    });
+}
+
+/// A continuation that creates the next one in the chain until `left` reaches 0.
+fn chain(left: Arc<AtomicUsize>) -> Continuation {
+    Continuation::new(move |_| {
+        if left.fetch_sub(1, Ordering::Relaxed) == 1 {
+            ContResult::Done
+        } else {
+            ContResult::Success(vec![(EventKind::Now, chain(left.clone()))])
+        }
+    })
+}
+
+/// Run a chain of 1000 continuations, each created by the previous one.
+#[bench]
+fn run_chain_of_1000(b: &mut Bencher) {
+    simulate(|| {
+        b.iter(|| {
+            let _ = sched::enqueue(vec![(
+                EventKind::Now,
+                chain(Arc::new(AtomicUsize::new(1000))),
+            )]);
+
+            while sched::step() {}
+        })
+    });
+}
@@ -0,0 +1,100 @@
+//! Running the simulator.
+
+use alloc::{sync::Arc, vec};
+
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    cap,
+    continuation::{ContResult, EventKind},
+    io, memory, sched, task,
+    time::{self, SysTime},
+};
+
+/// The kernel code keeps its state in globals, so only one simulation can run at a time (e.g.
+/// `cargo test` runs tests on several threads).
+static SIMULATOR: Mutex<()> = Mutex::new(());
+
+/// Run `f` with a fresh simulator: nothing is enqueued, no keys have been typed, and the
+/// capability registry is empty. The clock is not reset, though.
+pub fn simulate<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = SIMULATOR.lock();
+
+    sched::init();
+    io::kbd::init();
+    io::serial::init();
+    memory::init();
+    cap::init();
+
+    f()
+}
+
+/// Run continuations until `done` returns true, and return true. Whenever nothing is ready to run,
+/// the clock ticks. Gives up and returns false once nothing is left to run, or when the clock
+/// reads `limit`.
+pub fn run_until<F>(limit: SysTime, mut done: F) -> bool
+where
+    F: FnMut() -> bool,
+{
+    loop {
+        if done() {
+            return true;
+        }
+
+        if !sched::step() {
+            if sched::is_empty() || SysTime::now() >= limit {
+                return false;
+            }
+
+            time::tick();
+        }
+    }
+}
+
+/// Run `body` as a task in a fresh simulator, and panic if it doesn't finish within `timeout`
+/// simulated seconds. This is what `kernel_test!` does on the host.
+pub fn run_test<F>(timeout: usize, body: F)
+where
+    F: 'static + Send + Future<Output = ()>,
+{
+    simulate(|| {
+        let done = Arc::new(AtomicBool::new(false));
+
+        let cont = {
+            let done = done.clone();
+            task::continuation(async move {
+                body.await;
+                done.store(true, Ordering::Relaxed);
+                ContResult::Done
+            })
+        };
+        let _ = sched::enqueue(vec![(EventKind::Now, cont)]);
+
+        let limit = SysTime::now().after(timeout);
+        assert!(
+            run_until(limit, || done.load(Ordering::Relaxed)),
+            "test did not finish within {} simulated seconds",
+            timeout
+        );
+    })
+}
+
+/// Declare a test case with the given name, timeout (seconds), and `async` body. On the host,
+/// this is a normal `#[test]` that runs in the simulator.
+#[macro_export]
+macro_rules! kernel_test {
+    ($name:ident, $timeout:expr, $body:expr) => {
+        #[test]
+        fn $name() {
+            $crate::harness::run_test($timeout, $body);
+        }
+    };
+}
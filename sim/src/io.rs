@@ -0,0 +1,138 @@
+//! Simulated I/O.
+
+pub mod kbd {
+    //! A simulated keyboard. "Typed" keys are buffered until a continuation waiting for
+    //! `EventKind::Keyboard` with the keyboard capability that has the focus takes them.
+    //!
+    //! Unlike the kernel's keyboard, this only types text: there are no raw key events.
+
+    use alloc::collections::linked_list::LinkedList;
+
+    use spin::Mutex;
+
+    use crate::{
+        cap::{Capability, ResourceHandle, UnregisteredResourceHandle},
+        continuation::Event,
+    };
+
+    /// Stands in for the kernel's key events, which the simulated keyboard never produces.
+    #[derive(Copy, Clone, Debug)]
+    pub struct KeyEvent;
+
+    /// Buffered keyboard input.
+    static KBD_BUFFER: Mutex<Option<LinkedList<u8>>> = Mutex::new(None);
+
+    /// The keyboard capability with the input focus.
+    static FOCUS: Mutex<Option<ResourceHandle>> = Mutex::new(None);
+
+    /// Clear the buffer and the focus.
+    pub fn init() {
+        *KBD_BUFFER.lock() = Some(LinkedList::new());
+        *FOCUS.lock() = None;
+    }
+
+    /// Get a keyboard capability. It gets the focus if nobody has it.
+    pub fn open() -> ResourceHandle {
+        let kbd = UnregisteredResourceHandle::new(Capability::Keyboard).register();
+        FOCUS.lock().get_or_insert(kbd);
+        kbd
+    }
+
+    /// Give the focus to `kbd`.
+    pub fn focus(kbd: ResourceHandle) {
+        *FOCUS.lock() = Some(kbd);
+    }
+
+    /// Type the given keys.
+    pub fn type_keys(keys: &[u8]) {
+        KBD_BUFFER
+            .lock()
+            .as_mut()
+            .unwrap()
+            .extend(keys.iter().cloned());
+    }
+
+    /// Return the first buffered character, if `kbd` has the focus.
+    pub fn next_input(kbd: ResourceHandle) -> Option<Event> {
+        if *FOCUS.lock() != Some(kbd) {
+            return None;
+        }
+
+        KBD_BUFFER
+            .lock()
+            .as_mut()
+            .unwrap()
+            .pop_front()
+            .map(Event::Keyboard)
+    }
+}
+
+pub mod serial {
+    //! Simulated serial ports. Bytes "received" with `receive` are buffered until a continuation
+    //! waiting for `EventKind::Serial` on that port takes them.
+
+    use alloc::collections::vec_deque::VecDeque;
+
+    use spin::Mutex;
+
+    /// Buffered input of COM1 to COM4.
+    static RX: Mutex<Option<[VecDeque<u8>; 4]>> = Mutex::new(None);
+
+    /// Clear the buffers.
+    pub fn init() {
+        *RX.lock() = Some(Default::default());
+    }
+
+    /// Receive the given bytes on COM`port`.
+    pub fn receive(port: u8, bytes: &[u8]) {
+        RX.lock().as_mut().unwrap()[port as usize - 1].extend(bytes.iter().cloned());
+    }
+
+    /// Take the next received byte from COM`port`, if there is one.
+    pub fn read_byte(port: u8) -> Option<u8> {
+        RX.lock().as_mut().unwrap()[port as usize - 1].pop_front()
+    }
+}
+
+pub mod mouse {
+    //! The simulator has no mouse, so nobody waiting for `EventKind::Mouse` ever gets an event.
+
+    use crate::{cap::ResourceHandle, continuation::Event};
+
+    /// Stands in for the kernel's mouse events, which never happen here.
+    #[derive(Copy, Clone, Debug)]
+    pub struct MouseEvent;
+
+    /// There are never any events.
+    pub fn next_input(_mouse: ResourceHandle) -> Option<Event> {
+        None
+    }
+}
+
+pub mod block {
+    //! The simulator has no disks, so requests never finish and nobody waiting for
+    //! `EventKind::Block` ever gets an event.
+
+    use crate::continuation::Event;
+
+    /// Stands in for the kernel's block device errors, which never happen here.
+    #[derive(Copy, Clone, Debug)]
+    pub struct BlockError;
+
+    /// No request ever finishes.
+    pub fn poll(_id: u64) -> Option<Event> {
+        None
+    }
+}
+
+pub mod vt {
+    //! The simulator has no virtual terminals, so nobody waiting for `EventKind::Terminal` ever
+    //! gets any input.
+
+    use crate::{cap::ResourceHandle, continuation::Event};
+
+    /// There is never any input.
+    pub fn next_input(_term: ResourceHandle) -> Option<Event> {
+        None
+    }
+}
@@ -0,0 +1,56 @@
+//! A hosted simulation of the kernel's continuation scheduler and capability system.
+//!
+//! The kernel modules that are pure logic (`continuation`, `cap`, `task`, `time`, and the run
+//! queues of `sched`) are compiled as they are from `kernel/src`. This crate provides hosted
+//! versions of the modules they depend on:
+//! - `sched`: a single simulated core. Continuations are run with `Continuation::step` rather than
+//!   on their own stacks.
+//! - `time`: the kernel's clock, but it is only ticked by the simulator, so time only passes when
+//!   nothing is ready to run.
+//! - `io::kbd`: keys are "typed" with `io::kbd::type_keys`, and go to the keyboard capability with
+//!   the focus (see `io::kbd::focus`).
+//! - `io::serial`: bytes are "received" with `io::serial::receive`.
+//! - `memory`: virtual memory regions are allocated with the same buddy allocator as the kernel,
+//!   but they are never mapped.
+//!
+//! Use `simulate` to run some code with a fresh simulator, and `run_until` to run continuations
+//! until some condition holds. The kernel's own `kernel_test!`s in the included modules also run
+//! here with `cargo test`.
+
+#![feature(box_syntax, drain_filter)]
+// Some of the kernel code is not used by the simulator.
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[macro_use]
+pub mod harness;
+#[macro_use]
+#[path = "../../kernel/src/cap.rs"]
+pub mod cap;
+#[path = "../../kernel/src/continuation.rs"]
+pub mod continuation;
+pub mod io;
+pub mod memory;
+pub mod sched;
+#[path = "../../kernel/src/task.rs"]
+pub mod task;
+#[path = "../../kernel/src/time.rs"]
+pub mod time;
+
+pub use harness::{run_until, simulate};
+
+/// Stands in for the kernel's `interrupts` module.
+pub mod interrupts {
+    /// The frequency of the simulated timer. This is the same as the kernel's.
+    pub const TIMER_HZ: usize = 1000;
+}
+
+/// Stands in for the kernel's `smp` module. There is only one simulated core.
+pub mod smp {
+    pub const MAX_CPUS: usize = 1;
+
+    pub fn cpu_id() -> usize {
+        0
+    }
+}
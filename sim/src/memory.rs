@@ -0,0 +1,65 @@
+//! Simulated virtual memory. Regions are allocated like in the kernel, but there are no page
+//! tables, so they must not be accessed.
+
+use buddy::BuddyAllocator;
+
+use spin::Mutex;
+
+use crate::cap::{Capability, UnregisteredResourceHandle};
+
+/// The size of a page (bytes).
+const PAGE_SIZE: u64 = 1 << 12;
+
+/// The width of the simulated virtual address space (bits).
+const ADDRESS_SPACE_WIDTH: u8 = 48;
+
+/// The virtual address space allocator.
+static VIRT_MEM_ALLOC: Mutex<Option<BuddyAllocator<usize>>> = Mutex::new(None);
+
+/// Start over with an empty address space.
+pub fn init() {
+    let mut alloc = BuddyAllocator::new(ADDRESS_SPACE_WIDTH);
+    alloc.extend(1, (1 << (ADDRESS_SPACE_WIDTH - 1)) - 1);
+    *VIRT_MEM_ALLOC.lock() = Some(alloc);
+}
+
+/// Capability on a memory region.
+#[derive(Debug)]
+pub struct VirtualMemoryRegion {
+    /// The first virtual address of the memory region (bytes).
+    addr: u64,
+
+    /// The length of the memory region (bytes).
+    len: u64,
+}
+
+impl VirtualMemoryRegion {
+    /// Allocate a region of virtual memory with the given number of pages.
+    ///
+    /// # Panics
+    ///
+    /// If we exhaust the virtual address space.
+    pub fn alloc(npages: usize) -> UnregisteredResourceHandle {
+        let mem = VIRT_MEM_ALLOC
+            .lock()
+            .as_mut()
+            .unwrap()
+            .alloc(npages)
+            .expect("Out of virtual memory.");
+
+        UnregisteredResourceHandle::new(Capability::VirtualMemoryRegion(VirtualMemoryRegion {
+            addr: mem as u64 * PAGE_SIZE,
+            len: npages as u64 * PAGE_SIZE,
+        }))
+    }
+
+    /// The first virtual address of the memory region.
+    pub fn start(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+
+    /// The length of the region (in bytes).
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
@@ -0,0 +1,63 @@
+//! The simulated scheduler. There is one core, and continuations don't get their own stacks:
+//! `step` runs the next ready continuation on the caller's stack and returns.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::continuation::{CancelToken, Continuation, EventKind};
+
+#[path = "../../kernel/src/sched/queue.rs"]
+mod queue;
+
+pub use self::queue::RunQueue;
+
+/// The continuations of the simulated core.
+static QUEUE: Mutex<Option<RunQueue>> = Mutex::new(None);
+
+/// Drop everything that is enqueued and start over.
+pub fn init() {
+    let old = QUEUE.lock().replace(RunQueue::new()); // unlock
+
+    // Drop the continuations outside of the lock, in case some destructor wants the scheduler.
+    drop(old);
+}
+
+/// Run the next ready continuation, if there is one. Returns false if nothing was ready.
+pub fn step() -> bool {
+    let next = QUEUE.lock().as_mut().unwrap().next(); // unlock
+
+    match next {
+        Some((event, cont)) => {
+            cont.step(event);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Is nothing enqueued at all (ready or not)?
+pub fn is_empty() -> bool {
+    QUEUE.lock().as_ref().unwrap().is_empty()
+}
+
+/// Enqueue the given list of continuations. Returns a cancellation token for each of them, in the
+/// same order.
+pub fn enqueue(cont: Vec<(EventKind, Continuation)>) -> Vec<CancelToken> {
+    QUEUE.lock().as_mut().unwrap().enqueue(cont)
+}
+
+/// Remove any cancelled continuations and drop them.
+pub fn purge_cancelled() {
+    let cancelled = QUEUE.lock().as_mut().map(|queue| queue.remove_cancelled()); // unlock
+    drop(cancelled);
+}
+
+/// The kernel enqueues the idle continuation when a continuation is done. The simulator just
+/// notices when nothing is ready, so there is nothing to do.
+pub fn idle() {}
+
+/// The simulator never switches stacks, so nothing should call `Continuation::run`.
+pub fn sched() -> ! {
+    panic!("`sched` called in the simulator; use `Continuation::step`");
+}
@@ -0,0 +1,238 @@
+//! Property tests of the scheduler, run in the simulator. Each property is checked on a number of
+//! random workloads.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use sim::{
+    continuation::{ContError, ContResult, Continuation, EventKind, Priority},
+    io::{kbd, serial},
+    run_until, sched, simulate, task,
+    time::SysTime,
+};
+
+/// The number of random workloads to try for each property.
+const RUNS: u64 = 50;
+
+/// A continuation that appends `val` to `log` when it runs.
+fn record<T: 'static + Send>(log: &Arc<Mutex<Vec<T>>>, val: T) -> Continuation {
+    let log = log.clone();
+    let mut val = Some(val);
+    Continuation::new(move |_| {
+        log.lock().unwrap().push(val.take().unwrap());
+        ContResult::Done
+    })
+}
+
+/// Run until `log` has `n` entries, or give up after a simulated second.
+fn run_until_logged<T>(log: &Arc<Mutex<Vec<T>>>, n: usize) -> bool {
+    run_until(SysTime::now().after(1), || log.lock().unwrap().len() == n)
+}
+
+#[test]
+fn classes_run_in_priority_order() {
+    const PRIORITIES: [Priority; Priority::COUNT] = [
+        Priority::RealTime,
+        Priority::High,
+        Priority::Normal,
+        Priority::Background,
+    ];
+
+    for seed in 0..RUNS {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        simulate(|| {
+            let log = Arc::new(Mutex::new(vec![]));
+            let n = rng.gen_range(1, 50);
+
+            let conts = (0..n)
+                .map(|_| {
+                    let priority = PRIORITIES[rng.gen_range(0, Priority::COUNT)];
+                    (
+                        EventKind::Now,
+                        record(&log, priority).with_priority(priority),
+                    )
+                })
+                .collect();
+            let _ = sched::enqueue(conts);
+
+            assert!(run_until_logged(&log, n));
+
+            let log = log.lock().unwrap();
+            assert!(log.windows(2).all(|w| w[0] <= w[1]), "seed {}", seed);
+        });
+    }
+}
+
+#[test]
+fn deadlines_run_in_order() {
+    for seed in 0..RUNS {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        simulate(|| {
+            let log = Arc::new(Mutex::new(vec![]));
+            let n = rng.gen_range(1, 50);
+            let now = SysTime::now();
+
+            let conts = (0..n)
+                .map(|_| {
+                    let ticks = rng.gen_range(0, 100);
+                    (
+                        EventKind::Until(now.after_ticks(ticks)),
+                        record(&log, ticks).with_priority(Priority::RealTime),
+                    )
+                })
+                .collect();
+            let _ = sched::enqueue(conts);
+
+            assert!(run_until_logged(&log, n));
+
+            let log = log.lock().unwrap();
+            assert!(log.windows(2).all(|w| w[0] <= w[1]), "seed {}", seed);
+        });
+    }
+}
+
+/// A continuation that counts forever, once per tick.
+fn counter(count: Arc<AtomicUsize>) -> Continuation {
+    Continuation::new(move |_| {
+        count.fetch_add(1, Ordering::Relaxed);
+        ContResult::Success(vec![(
+            EventKind::Until(SysTime::now().after_ticks(1)),
+            counter(count.clone()),
+        )])
+    })
+}
+
+#[test]
+fn cancel_tree_stops_descendants() {
+    for seed in 0..RUNS {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        simulate(|| {
+            let count = Arc::new(AtomicUsize::new(0));
+            let n = rng.gen_range(1, 100);
+
+            let tokens = sched::enqueue(vec![(EventKind::Now, counter(count.clone()))]);
+            assert!(run_until(SysTime::now().after(1), || count
+                .load(Ordering::Relaxed)
+                == n));
+
+            tokens[0].cancel_tree();
+
+            assert!(sched::is_empty(), "seed {}", seed);
+            assert!(!run_until(SysTime::now().after(1), || false));
+            assert_eq!(count.load(Ordering::Relaxed), n, "seed {}", seed);
+        });
+    }
+}
+
+#[test]
+fn errors_escalate_to_outer_supervisor() {
+    simulate(|| {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let inner = Continuation::new(|_| ContResult::Error(ContError::Timeout, None))
+            .supervised(|error| ContResult::Error(error, None));
+
+        let outer = {
+            let log = log.clone();
+            let mut inner = Some(inner);
+            Continuation::new(move |_| {
+                ContResult::Success(vec![(EventKind::Now, inner.take().unwrap())])
+            })
+            .supervised(move |error| {
+                log.lock().unwrap().push(error);
+                ContResult::Done
+            })
+        };
+        let _ = sched::enqueue(vec![(EventKind::Now, outer)]);
+
+        assert!(run_until_logged(&log, 1));
+        assert_eq!(log.lock().unwrap()[0], ContError::Timeout);
+    });
+}
+
+#[test]
+fn tasks_read_typed_keys() {
+    simulate(|| {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let kbd = kbd::open();
+        let cont = {
+            let log = log.clone();
+            task::continuation(async move {
+                for _ in 0..2 {
+                    let c = task::keyboard(kbd).await;
+                    log.lock().unwrap().push(c);
+                }
+                ContResult::Done
+            })
+        };
+        let _ = sched::enqueue(vec![(EventKind::Now, cont)]);
+
+        kbd::type_keys(b"hi");
+
+        assert!(run_until_logged(&log, 2));
+        assert_eq!(&*log.lock().unwrap(), b"hi");
+    });
+}
+
+#[test]
+fn tasks_read_serial_input() {
+    simulate(|| {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let cont = {
+            let log = log.clone();
+            task::continuation(async move {
+                for _ in 0..2 {
+                    let b = task::serial(3).await;
+                    log.lock().unwrap().push(b);
+                }
+                ContResult::Done
+            })
+        };
+        let _ = sched::enqueue(vec![(EventKind::Now, cont)]);
+
+        // Input on other ports doesn't count.
+        serial::receive(1, b"x");
+        serial::receive(3, b"ok");
+
+        assert!(run_until_logged(&log, 2));
+        assert_eq!(&*log.lock().unwrap(), b"ok");
+    });
+}
+
+#[test]
+fn only_the_focus_reads_typed_keys() {
+    simulate(|| {
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let first = kbd::open();
+        let second = kbd::open();
+        for (name, kbd) in vec![("first", first), ("second", second)] {
+            let log = log.clone();
+            let cont = task::continuation(async move {
+                loop {
+                    let c = task::keyboard(kbd).await;
+                    log.lock().unwrap().push((name, c));
+                }
+            });
+            let _ = sched::enqueue(vec![(EventKind::Now, cont)]);
+        }
+
+        kbd::type_keys(b"a");
+        assert!(run_until_logged(&log, 1));
+
+        kbd::focus(second);
+        kbd::type_keys(b"b");
+        assert!(run_until_logged(&log, 2));
+
+        assert_eq!(&*log.lock().unwrap(), &[("first", b'a'), ("second", b'b')]);
+    });
+}